@@ -0,0 +1,8 @@
+-- Initial schema, as created before migrations were tracked
+CREATE TABLE IF NOT EXISTS coffees (
+    id TEXT PRIMARY KEY UNIQUE,
+    name TEXT NOT NULL,
+    farmer TEXT NOT NULL,
+    price INTEGER NOT NULL,
+    origin TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Soft delete: a coffee with a deleted_at timestamp (Unix seconds) is in the trash
+ALTER TABLE coffees ADD COLUMN deleted_at INTEGER;
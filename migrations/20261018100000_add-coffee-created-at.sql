@@ -0,0 +1,8 @@
+-- Creation time in Unix seconds, coffees added before it was tracked get the
+-- time of the migration. Each sortable column gets an index with id as tie breaker,
+-- matching the keyset pagination of ListCoffees.
+ALTER TABLE coffees ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+UPDATE coffees SET created_at = CAST(strftime('%s', 'now') AS INTEGER);
+CREATE INDEX coffees_price ON coffees (price, id);
+CREATE INDEX coffees_roastery ON coffees (roastery, id);
+CREATE INDEX coffees_created_at ON coffees (created_at, id);
@@ -0,0 +1,29 @@
+-- Full-text index over the searchable coffee columns. Diacritics are folded so
+-- "cafe" matches "Café", and 2 and 3 character prefixes are indexed for
+-- type-ahead queries. The triggers keep it in sync with coffees.
+CREATE VIRTUAL TABLE coffees_fts USING fts5(
+    id UNINDEXED,
+    roastery,
+    farmer,
+    origin,
+    tokenize = 'unicode61 remove_diacritics 2',
+    prefix = '2 3'
+);
+
+INSERT INTO coffees_fts (id, roastery, farmer, origin)
+SELECT id, roastery, farmer, origin FROM coffees;
+
+CREATE TRIGGER coffees_fts_insert AFTER INSERT ON coffees BEGIN
+    INSERT INTO coffees_fts (id, roastery, farmer, origin)
+    VALUES (new.id, new.roastery, new.farmer, new.origin);
+END;
+
+CREATE TRIGGER coffees_fts_update AFTER UPDATE OF id, roastery, farmer, origin ON coffees BEGIN
+    DELETE FROM coffees_fts WHERE id = old.id;
+    INSERT INTO coffees_fts (id, roastery, farmer, origin)
+    VALUES (new.id, new.roastery, new.farmer, new.origin);
+END;
+
+CREATE TRIGGER coffees_fts_delete AFTER DELETE ON coffees BEGIN
+    DELETE FROM coffees_fts WHERE id = old.id;
+END;
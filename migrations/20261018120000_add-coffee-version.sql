@@ -0,0 +1,2 @@
+-- Row version for optimistic concurrency, bumped by every write
+ALTER TABLE coffees ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
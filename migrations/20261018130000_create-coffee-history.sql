@@ -0,0 +1,74 @@
+-- Audit trail of every write to coffees, with the row as it was before and after
+-- the change as JSON. The triggers take the actor and time from audit_context,
+-- which the server fills in within the writing transaction. Writes made without
+-- it, e.g. from the sqlite3 shell, are recorded as the "system" actor.
+CREATE TABLE coffee_history (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    coffee_id TEXT NOT NULL,
+    -- The version the change left the coffee at, or deleted it at.
+    version INTEGER NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('insert', 'update', 'delete')),
+    before TEXT,
+    after TEXT,
+    actor TEXT NOT NULL,
+    changed_at INTEGER NOT NULL
+);
+
+CREATE INDEX coffee_history_coffee_id ON coffee_history (coffee_id, seq);
+
+CREATE TABLE audit_context (
+    actor TEXT NOT NULL,
+    changed_at INTEGER NOT NULL
+);
+
+-- Existing coffees start their history as they are now.
+INSERT INTO coffee_history (coffee_id, version, action, after, actor, changed_at)
+SELECT id, version, 'insert',
+    json_object('id', id, 'roastery', roastery, 'icon', icon, 'farmer', farmer,
+        'price', price, 'origin', origin, 'created_at', created_at,
+        'version', version, 'deleted_at', deleted_at),
+    'migration', CAST(strftime('%s', 'now') AS INTEGER)
+FROM coffees;
+
+CREATE TRIGGER coffees_history_insert AFTER INSERT ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, after, actor, changed_at)
+    VALUES (
+        new.id, new.version, 'insert',
+        json_object('id', new.id, 'roastery', new.roastery, 'icon', new.icon,
+            'farmer', new.farmer, 'price', new.price, 'origin', new.origin,
+            'created_at', new.created_at, 'version', new.version,
+            'deleted_at', new.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
+
+CREATE TRIGGER coffees_history_update AFTER UPDATE ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, before, after, actor, changed_at)
+    VALUES (
+        new.id, new.version, 'update',
+        json_object('id', old.id, 'roastery', old.roastery, 'icon', old.icon,
+            'farmer', old.farmer, 'price', old.price, 'origin', old.origin,
+            'created_at', old.created_at, 'version', old.version,
+            'deleted_at', old.deleted_at),
+        json_object('id', new.id, 'roastery', new.roastery, 'icon', new.icon,
+            'farmer', new.farmer, 'price', new.price, 'origin', new.origin,
+            'created_at', new.created_at, 'version', new.version,
+            'deleted_at', new.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
+
+CREATE TRIGGER coffees_history_delete AFTER DELETE ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, before, actor, changed_at)
+    VALUES (
+        old.id, old.version, 'delete',
+        json_object('id', old.id, 'roastery', old.roastery, 'icon', old.icon,
+            'farmer', old.farmer, 'price', old.price, 'origin', old.origin,
+            'created_at', old.created_at, 'version', old.version,
+            'deleted_at', old.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
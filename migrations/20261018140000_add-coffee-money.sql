@@ -0,0 +1,81 @@
+-- Prices become an amount in minor units plus an ISO 4217 currency. The old bare
+-- prices were whole euros (nobody sells a bag for 20 cents), so they are
+-- converted to euro cents, history snapshots included.
+
+-- The history triggers reference price, and the conversion itself is not a change
+-- worth recording.
+DROP TRIGGER coffees_history_insert;
+DROP TRIGGER coffees_history_update;
+DROP TRIGGER coffees_history_delete;
+
+ALTER TABLE coffees ADD COLUMN price_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE coffees ADD COLUMN price_currency TEXT NOT NULL DEFAULT 'EUR';
+UPDATE coffees SET price_minor = price * 100, price_currency = 'EUR';
+
+UPDATE coffee_history SET before = json_set(before, '$.price', json_object(
+    'amount_minor', json_extract(before, '$.price') * 100, 'currency', 'EUR'))
+WHERE before IS NOT NULL;
+UPDATE coffee_history SET after = json_set(after, '$.price', json_object(
+    'amount_minor', json_extract(after, '$.price') * 100, 'currency', 'EUR'))
+WHERE after IS NOT NULL;
+
+DROP INDEX coffees_price;
+ALTER TABLE coffees DROP COLUMN price;
+CREATE INDEX coffees_price ON coffees (price_minor, id);
+
+-- How much one unit of from_currency is worth in to_currency. A rate also
+-- converts the other way round unless that direction has a rate of its own.
+CREATE TABLE exchange_rates (
+    from_currency TEXT NOT NULL,
+    to_currency TEXT NOT NULL,
+    rate REAL NOT NULL CHECK (rate > 0),
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (from_currency, to_currency)
+);
+
+CREATE TRIGGER coffees_history_insert AFTER INSERT ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, after, actor, changed_at)
+    VALUES (
+        new.id, new.version, 'insert',
+        json_object('id', new.id, 'roastery', new.roastery, 'icon', new.icon,
+            'farmer', new.farmer,
+            'price', json_object('amount_minor', new.price_minor, 'currency', new.price_currency),
+            'origin', new.origin, 'created_at', new.created_at, 'version', new.version,
+            'deleted_at', new.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
+
+CREATE TRIGGER coffees_history_update AFTER UPDATE ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, before, after, actor, changed_at)
+    VALUES (
+        new.id, new.version, 'update',
+        json_object('id', old.id, 'roastery', old.roastery, 'icon', old.icon,
+            'farmer', old.farmer,
+            'price', json_object('amount_minor', old.price_minor, 'currency', old.price_currency),
+            'origin', old.origin, 'created_at', old.created_at, 'version', old.version,
+            'deleted_at', old.deleted_at),
+        json_object('id', new.id, 'roastery', new.roastery, 'icon', new.icon,
+            'farmer', new.farmer,
+            'price', json_object('amount_minor', new.price_minor, 'currency', new.price_currency),
+            'origin', new.origin, 'created_at', new.created_at, 'version', new.version,
+            'deleted_at', new.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
+
+CREATE TRIGGER coffees_history_delete AFTER DELETE ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, before, actor, changed_at)
+    VALUES (
+        old.id, old.version, 'delete',
+        json_object('id', old.id, 'roastery', old.roastery, 'icon', old.icon,
+            'farmer', old.farmer,
+            'price', json_object('amount_minor', old.price_minor, 'currency', old.price_currency),
+            'origin', old.origin, 'created_at', old.created_at, 'version', old.version,
+            'deleted_at', old.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
@@ -0,0 +1,212 @@
+-- Roasteries, farmers and origins become tables of their own that coffees point
+-- at. The existing free text is merged into them: names that differ only in case
+-- or surrounding whitespace become one entity, spelled as the first of them in
+-- sort order, which prefers capitalised spellings. Names that only appear in the
+-- history of purged coffees get an entity too, so every snapshot can point at one.
+-- Ids made here are random hex rather than nanoids, both are opaque strings.
+
+CREATE TABLE roasteries (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    website TEXT,
+    contact TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE farmers (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    region TEXT,
+    contact TEXT,
+    created_at INTEGER NOT NULL
+);
+
+-- name is the full origin, e.g. "El Mirador, Huila, Colombia".
+CREATE TABLE origins (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    region TEXT,
+    country TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TEMP VIEW legacy_names AS
+SELECT trim(roastery) AS roastery, trim(farmer) AS farmer, trim(origin) AS origin
+FROM coffees
+UNION ALL
+SELECT trim(json_extract(snapshot, '$.roastery')), trim(json_extract(snapshot, '$.farmer')),
+    trim(json_extract(snapshot, '$.origin'))
+FROM (
+    SELECT before AS snapshot FROM coffee_history WHERE before IS NOT NULL
+    UNION ALL
+    SELECT after FROM coffee_history WHERE after IS NOT NULL
+);
+
+INSERT INTO roasteries (id, name, created_at)
+SELECT lower(hex(randomblob(10))), min(roastery), CAST(strftime('%s', 'now') AS INTEGER)
+FROM legacy_names GROUP BY lower(roastery);
+
+INSERT INTO farmers (id, name, created_at)
+SELECT lower(hex(randomblob(10))), min(farmer), CAST(strftime('%s', 'now') AS INTEGER)
+FROM legacy_names GROUP BY lower(farmer);
+
+-- The country is whatever follows the last comma.
+INSERT INTO origins (id, name, country, created_at)
+SELECT lower(hex(randomblob(10))), name,
+    trim(replace(name, rtrim(name, replace(name, ',', '')), '')),
+    CAST(strftime('%s', 'now') AS INTEGER)
+FROM (SELECT min(origin) AS name FROM legacy_names GROUP BY lower(origin));
+
+DROP VIEW legacy_names;
+
+UPDATE coffee_history SET before = json_set(before,
+    '$.roastery_id', (SELECT id FROM roasteries WHERE name = trim(json_extract(before, '$.roastery'))),
+    '$.farmer_id', (SELECT id FROM farmers WHERE name = trim(json_extract(before, '$.farmer'))),
+    '$.origin_id', (SELECT id FROM origins WHERE name = trim(json_extract(before, '$.origin'))))
+WHERE before IS NOT NULL;
+UPDATE coffee_history SET after = json_set(after,
+    '$.roastery_id', (SELECT id FROM roasteries WHERE name = trim(json_extract(after, '$.roastery'))),
+    '$.farmer_id', (SELECT id FROM farmers WHERE name = trim(json_extract(after, '$.farmer'))),
+    '$.origin_id', (SELECT id FROM origins WHERE name = trim(json_extract(after, '$.origin'))))
+WHERE after IS NOT NULL;
+
+-- SQLite can't add NOT NULL foreign keys to a table, so coffees is rebuilt. Its
+-- triggers and indexes go with the old table and are recreated below.
+CREATE TABLE coffees_new (
+    id TEXT PRIMARY KEY NOT NULL,
+    roastery_id TEXT NOT NULL REFERENCES roasteries (id),
+    farmer_id TEXT NOT NULL REFERENCES farmers (id),
+    origin_id TEXT NOT NULL REFERENCES origins (id),
+    icon TEXT NOT NULL,
+    price_minor INTEGER NOT NULL,
+    price_currency TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1,
+    deleted_at INTEGER
+);
+
+INSERT INTO coffees_new
+SELECT coffees.id, roasteries.id, farmers.id, origins.id, icon, price_minor, price_currency,
+    coffees.created_at, version, deleted_at
+FROM coffees
+JOIN roasteries ON roasteries.name = trim(coffees.roastery)
+JOIN farmers ON farmers.name = trim(coffees.farmer)
+JOIN origins ON origins.name = trim(coffees.origin);
+
+DROP TABLE coffees;
+ALTER TABLE coffees_new RENAME TO coffees;
+
+CREATE INDEX coffees_price ON coffees (price_minor, id);
+CREATE INDEX coffees_created_at ON coffees (created_at, id);
+CREATE INDEX coffees_roastery_id ON coffees (roastery_id);
+CREATE INDEX coffees_farmer_id ON coffees (farmer_id);
+CREATE INDEX coffees_origin_id ON coffees (origin_id);
+
+-- Coffees along with the names of what they point at, as the API shows them.
+CREATE VIEW coffee_view AS
+SELECT
+    coffees.id,
+    coffees.roastery_id,
+    roasteries.name AS roastery,
+    coffees.icon,
+    coffees.farmer_id,
+    farmers.name AS farmer,
+    coffees.origin_id,
+    origins.name AS origin,
+    coffees.price_minor,
+    coffees.price_currency,
+    coffees.created_at,
+    coffees.version,
+    coffees.deleted_at
+FROM coffees
+JOIN roasteries ON roasteries.id = coffees.roastery_id
+JOIN farmers ON farmers.id = coffees.farmer_id
+JOIN origins ON origins.id = coffees.origin_id;
+
+-- The search index keeps the names, following both coffees and renames.
+DELETE FROM coffees_fts;
+INSERT INTO coffees_fts (id, roastery, farmer, origin)
+SELECT id, roastery, farmer, origin FROM coffee_view;
+
+CREATE TRIGGER coffees_fts_insert AFTER INSERT ON coffees BEGIN
+    INSERT INTO coffees_fts (id, roastery, farmer, origin)
+    SELECT id, roastery, farmer, origin FROM coffee_view WHERE id = new.id;
+END;
+
+CREATE TRIGGER coffees_fts_update AFTER UPDATE OF id, roastery_id, farmer_id, origin_id ON coffees
+BEGIN
+    DELETE FROM coffees_fts WHERE id = old.id;
+    INSERT INTO coffees_fts (id, roastery, farmer, origin)
+    SELECT id, roastery, farmer, origin FROM coffee_view WHERE id = new.id;
+END;
+
+CREATE TRIGGER coffees_fts_delete AFTER DELETE ON coffees BEGIN
+    DELETE FROM coffees_fts WHERE id = old.id;
+END;
+
+CREATE TRIGGER roasteries_fts_rename AFTER UPDATE OF name ON roasteries BEGIN
+    UPDATE coffees_fts SET roastery = new.name
+    WHERE id IN (SELECT id FROM coffees WHERE roastery_id = new.id);
+END;
+
+CREATE TRIGGER farmers_fts_rename AFTER UPDATE OF name ON farmers BEGIN
+    UPDATE coffees_fts SET farmer = new.name
+    WHERE id IN (SELECT id FROM coffees WHERE farmer_id = new.id);
+END;
+
+CREATE TRIGGER origins_fts_rename AFTER UPDATE OF name ON origins BEGIN
+    UPDATE coffees_fts SET origin = new.name
+    WHERE id IN (SELECT id FROM coffees WHERE origin_id = new.id);
+END;
+
+-- History snapshots record the names as they were at the time of the change.
+CREATE TRIGGER coffees_history_insert AFTER INSERT ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, after, actor, changed_at)
+    SELECT
+        new.id, new.version, 'insert',
+        json_object('id', id, 'roastery_id', roastery_id, 'roastery', roastery, 'icon', icon,
+            'farmer_id', farmer_id, 'farmer', farmer, 'origin_id', origin_id, 'origin', origin,
+            'price', json_object('amount_minor', price_minor, 'currency', price_currency),
+            'created_at', created_at, 'version', version, 'deleted_at', deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    FROM coffee_view WHERE id = new.id;
+END;
+
+CREATE TRIGGER coffees_history_update AFTER UPDATE ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, before, after, actor, changed_at)
+    SELECT
+        new.id, new.version, 'update',
+        json_object('id', old.id, 'roastery_id', old.roastery_id,
+            'roastery', (SELECT name FROM roasteries WHERE id = old.roastery_id),
+            'icon', old.icon, 'farmer_id', old.farmer_id,
+            'farmer', (SELECT name FROM farmers WHERE id = old.farmer_id),
+            'origin_id', old.origin_id,
+            'origin', (SELECT name FROM origins WHERE id = old.origin_id),
+            'price', json_object('amount_minor', old.price_minor, 'currency', old.price_currency),
+            'created_at', old.created_at, 'version', old.version, 'deleted_at', old.deleted_at),
+        json_object('id', id, 'roastery_id', roastery_id, 'roastery', roastery, 'icon', icon,
+            'farmer_id', farmer_id, 'farmer', farmer, 'origin_id', origin_id, 'origin', origin,
+            'price', json_object('amount_minor', price_minor, 'currency', price_currency),
+            'created_at', created_at, 'version', version, 'deleted_at', deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    FROM coffee_view WHERE id = new.id;
+END;
+
+CREATE TRIGGER coffees_history_delete AFTER DELETE ON coffees BEGIN
+    INSERT INTO coffee_history (coffee_id, version, action, before, actor, changed_at)
+    VALUES (
+        old.id, old.version, 'delete',
+        json_object('id', old.id, 'roastery_id', old.roastery_id,
+            'roastery', (SELECT name FROM roasteries WHERE id = old.roastery_id),
+            'icon', old.icon, 'farmer_id', old.farmer_id,
+            'farmer', (SELECT name FROM farmers WHERE id = old.farmer_id),
+            'origin_id', old.origin_id,
+            'origin', (SELECT name FROM origins WHERE id = old.origin_id),
+            'price', json_object('amount_minor', old.price_minor, 'currency', old.price_currency),
+            'created_at', old.created_at, 'version', old.version, 'deleted_at', old.deleted_at),
+        COALESCE((SELECT actor FROM audit_context), 'system'),
+        COALESCE((SELECT changed_at FROM audit_context), CAST(strftime('%s', 'now') AS INTEGER))
+    );
+END;
@@ -0,0 +1,16 @@
+-- API keys for callers of the mutating methods. Only the SHA-256 of a key is kept,
+-- along with its first characters so people can tell their keys apart. Revoked
+-- keys stay around, named, for the audit trail.
+CREATE TABLE api_keys (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    prefix TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    rotated_at INTEGER,
+    revoked_at INTEGER
+);
+
+-- Key names end up as the actor in the coffee history, so no two live keys may
+-- share one.
+CREATE UNIQUE INDEX api_keys_name ON api_keys (name) WHERE revoked_at IS NULL;
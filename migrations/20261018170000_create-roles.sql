@@ -0,0 +1,27 @@
+-- Roles grant the permissions RPC methods declare with #[permission(..)]. Every
+-- API key has one role. Grants can change at runtime, a key's permissions are
+-- looked up on every call.
+CREATE TABLE roles (
+    name TEXT PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE role_permissions (
+    role TEXT NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO roles (name) VALUES ('viewer'), ('editor'), ('admin');
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('viewer', 'read'),
+    ('editor', 'read'),
+    ('editor', 'write'),
+    ('admin', 'read'),
+    ('admin', 'write'),
+    ('admin', 'delete'),
+    ('admin', 'manage_keys');
+
+-- Keys issued so far could do anything, they keep doing so. SQLite can't add a
+-- column with both a foreign key and a default, the server checks roles exist.
+ALTER TABLE api_keys ADD COLUMN role TEXT NOT NULL DEFAULT 'admin';
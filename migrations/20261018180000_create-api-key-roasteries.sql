@@ -0,0 +1,21 @@
+-- The roasteries an API key acts for. Keys whose role doesn't grant
+-- all_roasteries can only write coffees of these, and the roasteries themselves.
+CREATE TABLE api_key_roasteries (
+    key_id TEXT NOT NULL REFERENCES api_keys (id) ON DELETE CASCADE,
+    roastery_id TEXT NOT NULL REFERENCES roasteries (id) ON DELETE CASCADE,
+    PRIMARY KEY (key_id, roastery_id)
+);
+
+CREATE INDEX api_key_roasteries_roastery_id ON api_key_roasteries (roastery_id);
+
+-- Editors and admins keep writing every coffee, roasteries only their own.
+INSERT INTO role_permissions (role, permission) VALUES
+    ('editor', 'all_roasteries'),
+    ('admin', 'all_roasteries');
+
+INSERT INTO roles (name) VALUES ('roastery');
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('roastery', 'read'),
+    ('roastery', 'write'),
+    ('roastery', 'delete');
@@ -0,0 +1,9 @@
+-- Calls each client made to each method on a UTC day, counted against the
+-- method's daily quota. Day first, so past days are cheap to clear out.
+CREATE TABLE rate_limit_usage (
+    day INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    method TEXT NOT NULL,
+    calls INTEGER NOT NULL,
+    PRIMARY KEY (day, client, method)
+);
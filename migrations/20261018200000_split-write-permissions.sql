@@ -0,0 +1,27 @@
+-- write and delete covered the whole catalog, so a roastery's key could edit
+-- other people's farmers and origins or set exchange rates. Split them into
+-- coffees and roasteries, which keys without all_roasteries only write for the
+-- roasteries they act for, and the shared catalog of farmers, origins, exchange
+-- rates and new roasteries, which only roles writing every roastery keep.
+INSERT INTO role_permissions (role, permission)
+SELECT granted.role, split.column2
+FROM role_permissions AS granted
+JOIN (VALUES
+    ('write', 'write_coffees'),
+    ('write', 'write_roasteries'),
+    ('delete', 'delete_coffees'),
+    ('delete', 'delete_roasteries')
+) AS split ON split.column1 = granted.permission;
+
+INSERT INTO role_permissions (role, permission)
+SELECT granted.role, split.column2
+FROM role_permissions AS granted
+JOIN (VALUES
+    ('write', 'write_catalog'),
+    ('delete', 'delete_catalog')
+) AS split ON split.column1 = granted.permission
+WHERE granted.role IN (
+    SELECT role FROM role_permissions WHERE permission = 'all_roasteries'
+);
+
+DELETE FROM role_permissions WHERE permission IN ('write', 'delete');
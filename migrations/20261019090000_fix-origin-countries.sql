@@ -0,0 +1,27 @@
+-- Origins were given whatever followed the last comma of their free text as
+-- their country, all of it when there was no comma, so some hold countries the
+-- API doesn't know. Countries known up to case or surrounding spaces are spelled
+-- as known. Otherwise the longest known country named anywhere in the origin is
+-- taken, so "Papua New Guinea" isn't read as something shorter. Origins naming
+-- none get an empty country, marking them for someone to set one.
+
+-- Mirrors validation::KNOWN_COUNTRIES.
+CREATE TEMP TABLE known_countries (name TEXT NOT NULL);
+INSERT INTO known_countries (name) VALUES
+    ('Bolivia'), ('Brazil'), ('Burundi'), ('Cameroon'), ('China'), ('Colombia'),
+    ('Costa Rica'), ('Cuba'), ('DR Congo'), ('Dominican Republic'), ('Ecuador'),
+    ('El Salvador'), ('Ethiopia'), ('Guatemala'), ('Haiti'), ('Honduras'), ('India'),
+    ('Indonesia'), ('Jamaica'), ('Kenya'), ('Laos'), ('Malawi'), ('Mexico'),
+    ('Myanmar'), ('Nepal'), ('Nicaragua'), ('Panama'), ('Papua New Guinea'), ('Peru'),
+    ('Philippines'), ('Rwanda'), ('Tanzania'), ('Thailand'), ('Timor-Leste'),
+    ('Uganda'), ('United States'), ('Venezuela'), ('Vietnam'), ('Yemen'), ('Zambia'),
+    ('Zimbabwe');
+
+UPDATE origins SET country = COALESCE(
+    (SELECT name FROM known_countries WHERE lower(name) = lower(trim(origins.country))),
+    (SELECT name FROM known_countries WHERE instr(lower(origins.name), lower(name)) > 0
+        ORDER BY length(name) DESC LIMIT 1),
+    ''
+);
+
+DROP TABLE known_countries;
@@ -0,0 +1,911 @@
+//! Procedural macros that turn a plain async trait into an RPC service.
+//!
+//! ```ignore
+//! #[rpissc::service(methods = Methods)]
+//! pub trait CoffeeRpc {
+//!     async fn get_random_coffee(&self) -> anyhow::Result<Coffee>;
+//!     async fn grab_id(&self, roastery: String, origin: String) -> anyhow::Result<Nanoid>;
+//! }
+//! ```
+//!
+//! generates a `Methods` enum with one variant per trait method, speaking the shape
+//!
+//! ```json
+//! {
+//!     "method": "GrabId",
+//!     "data": { "roastery": "...", "origin": "..." }
+//! }
+//! ```
+//!
+//! Methods without arguments become unit variants, methods with a single argument
+//! newtype variants and anything else a struct variant keyed by argument name.
+//!
+//! The same calls are accepted as JSON-RPC 2.0 through the generated
+//! `call_json_rpc`, with `params` given by position or by name.
+//!
+//! Alongside the enum it generates a typed client with one async method per trait
+//! method, so the server and its callers are always built from the same definition.
+//!
+//! Methods marked `#[validate]` check every argument with `Validate` before they
+//! run, see `#[derive(Validate)]`.
+//!
+//! Every call goes through the trait's `authorize` hook before it is dispatched,
+//! which lets everything through unless the implementation overrides it. Methods
+//! marked `#[public]` report so through the generated `Methods::is_public`, for the
+//! hook to tell which calls need credentials, and those marked
+//! `#[permission(name)]` name the permission they need through
+//! `Methods::permission`.
+//!
+//! The generated code expects the runtime items from the calling crate's `service`
+//! module (`Request`, `Response`, `IntoResponse`, `Router`, `server_loop`), `client`
+//! module (`RpcClient`, `ClientError`), `error` module (`AppError`), `jsonrpc`
+//! module, `validation` module (`Validate`) and `config` module (`ServerConfig`) as
+//! well as `serde`, `serde_json` and `tracing` to be available.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, punctuated::Punctuated, spanned::Spanned, Data, DeriveInput,
+    Expr, Fields, FnArg, GenericArgument, Ident, ItemTrait, Pat, PathArguments, ReturnType, Token,
+    TraitItem, TraitItemFn, Type,
+};
+
+/// Generates the request enum, dispatch and serve loop for an RPC trait.
+///
+/// Accepts `methods = Ident` to name the generated request enum, which otherwise
+/// defaults to `{Trait}Methods`, and `client = Ident` to name the generated typed
+/// client, which otherwise defaults to `{Trait}Client`.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = ServiceArgs::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with parser);
+    let item = parse_macro_input!(item as ItemTrait);
+    expand_service(args, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives the calling crate's `validation::Validate` from `#[validate(..)]` field
+/// attributes.
+///
+/// Each attribute lists rules, `rule` or `rule(args..)`, naming functions of the
+/// `validation` module that take a reference to the field followed by `args` and
+/// return `Result<(), String>`. A field's rules run in order until one fails, and
+/// every failing field is reported. `Option` fields are only checked when `Some`.
+#[proc_macro_derive(Validate, attributes(validate))]
+pub fn derive_validate(item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as DeriveInput);
+    expand_validate(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_validate(item: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &item.data else {
+        return Err(syn::Error::new(
+            item.ident.span(),
+            "Validate can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            item.ident.span(),
+            "Validate needs named fields",
+        ));
+    };
+
+    let mut checks = Vec::new();
+    for field in &fields.named {
+        let ident = field.ident.as_ref().unwrap();
+        let name = ident.to_string();
+        let mut rules = Vec::new();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("validate"))
+        {
+            let exprs = attr.parse_args_with(Punctuated::<Expr, Token![,]>::parse_terminated)?;
+            for expr in exprs {
+                rules.push(match expr {
+                    Expr::Path(rule) => quote!(crate::validation::#rule(value)),
+                    Expr::Call(call) => {
+                        let rule = &call.func;
+                        let args = &call.args;
+                        quote!(crate::validation::#rule(value, #args))
+                    }
+                    expr => {
+                        return Err(syn::Error::new(
+                            expr.span(),
+                            "expected `rule` or `rule(args..)`",
+                        ))
+                    }
+                });
+            }
+        }
+        if rules.is_empty() {
+            continue;
+        }
+        let check = quote! {
+            let result = ::core::result::Result::<(), ::std::string::String>::Ok(())
+                #(.and_then(|()| #rules))*;
+            if let ::core::result::Result::Err(message) = result {
+                errors.add(#name, message);
+            }
+        };
+        checks.push(if is_option(&field.ty) {
+            quote! {
+                if let ::core::option::Option::Some(value) = &self.#ident {
+                    #check
+                }
+            }
+        } else {
+            quote! {
+                {
+                    let value = &self.#ident;
+                    #check
+                }
+            }
+        });
+    }
+
+    let ident = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::validation::Validate for #ident #ty_generics #where_clause {
+            fn validate(
+                &self,
+            ) -> ::core::result::Result<(), crate::validation::ValidationErrors> {
+                let mut errors = crate::validation::ValidationErrors::default();
+                #(#checks)*
+                errors.into_result()
+            }
+        }
+    })
+}
+
+fn is_option(ty: &Type) -> bool {
+    matches!(
+        ty,
+        Type::Path(path) if path.path.segments.last().is_some_and(|last| last.ident == "Option")
+    )
+}
+
+#[derive(Default)]
+struct ServiceArgs {
+    methods: Option<Ident>,
+    client: Option<Ident>,
+}
+
+impl ServiceArgs {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("methods") {
+            self.methods = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("client") {
+            self.client = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported rpissc::service argument"))
+        }
+    }
+}
+
+struct RpcMethod {
+    ident: Ident,
+    variant: Ident,
+    args: Vec<(Ident, Type)>,
+    output: Type,
+    /// Whether the arguments are validated before the method runs.
+    validate: bool,
+    /// Whether the method may be called without credentials.
+    public: bool,
+    /// The permission a caller needs, if any.
+    permission: Option<String>,
+}
+
+impl RpcMethod {
+    fn parse(method: &TraitItemFn) -> syn::Result<Self> {
+        let sig = &method.sig;
+        if sig.asyncness.is_none() {
+            return Err(syn::Error::new(
+                sig.fn_token.span(),
+                "rpc methods must be `async fn`",
+            ));
+        }
+        if !sig.generics.params.is_empty() {
+            return Err(syn::Error::new(
+                sig.generics.span(),
+                "rpc methods can't be generic",
+            ));
+        }
+        let mut inputs = sig.inputs.iter();
+        match inputs.next() {
+            Some(FnArg::Receiver(receiver))
+                if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+            _ => {
+                return Err(syn::Error::new(
+                    sig.inputs.span(),
+                    "rpc methods must take `&self`",
+                ))
+            }
+        }
+        let args = inputs
+            .map(|arg| match arg {
+                FnArg::Typed(arg) => match &*arg.pat {
+                    Pat::Ident(pat) => Ok((pat.ident.clone(), (*arg.ty).clone())),
+                    pat => Err(syn::Error::new(
+                        pat.span(),
+                        "rpc arguments must be plain identifiers",
+                    )),
+                },
+                FnArg::Receiver(receiver) => {
+                    Err(syn::Error::new(receiver.span(), "unexpected receiver"))
+                }
+            })
+            .collect::<syn::Result<_>>()?;
+        let output = match &sig.output {
+            ReturnType::Default => parse_quote!(()),
+            ReturnType::Type(_, ty) => (**ty).clone(),
+        };
+        Ok(Self {
+            ident: sig.ident.clone(),
+            variant: Ident::new(&pascal_case(&sig.ident.to_string()), sig.ident.span()),
+            args,
+            output,
+            validate: method
+                .attrs
+                .iter()
+                .any(|attr| attr.path().is_ident("validate")),
+            public: method
+                .attrs
+                .iter()
+                .any(|attr| attr.path().is_ident("public")),
+            permission: parse_permission(method)?,
+        })
+    }
+
+    fn variant_def(&self) -> TokenStream2 {
+        let variant = &self.variant;
+        match self.args.as_slice() {
+            [] => quote!(#variant),
+            [(_, ty)] => quote!(#variant(#ty)),
+            args => {
+                let fields = args.iter().map(|(name, ty)| quote!(#name: #ty));
+                quote!(#variant { #(#fields),* })
+            }
+        }
+    }
+
+    /// Matches the variant without binding its arguments.
+    fn variant_pat_ignored(&self, methods: &Ident) -> TokenStream2 {
+        let variant = &self.variant;
+        match self.args.as_slice() {
+            [] => quote!(#methods::#variant),
+            [_] => quote!(#methods::#variant(_)),
+            _ => quote!(#methods::#variant { .. }),
+        }
+    }
+
+    fn variant_pat(&self, methods: &Ident) -> TokenStream2 {
+        let variant = &self.variant;
+        let names = self.args.iter().map(|(name, _)| name);
+        match self.args.as_slice() {
+            [] => quote!(#methods::#variant),
+            [_] => quote!(#methods::#variant(#(#names),*)),
+            _ => quote!(#methods::#variant { #(#names),* }),
+        }
+    }
+}
+
+/// Reads `#[permission(name)]` off a method, which can't also be `#[public]`.
+fn parse_permission(method: &TraitItemFn) -> syn::Result<Option<String>> {
+    let mut permissions = method
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("permission"));
+    let Some(attr) = permissions.next() else {
+        return Ok(None);
+    };
+    if let Some(extra) = permissions.next() {
+        return Err(syn::Error::new(
+            extra.span(),
+            "a method needs at most one permission",
+        ));
+    }
+    if method
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("public"))
+    {
+        return Err(syn::Error::new(
+            attr.span(),
+            "public methods can't require a permission",
+        ));
+    }
+    let name: Ident = attr.parse_args()?;
+    Ok(Some(name.to_string()))
+}
+
+fn expand_service(args: ServiceArgs, mut item: ItemTrait) -> syn::Result<TokenStream2> {
+    let vis = &item.vis;
+    let methods_ident = args
+        .methods
+        .unwrap_or_else(|| format_ident!("{}Methods", item.ident));
+    let client_ident = args
+        .client
+        .unwrap_or_else(|| format_ident!("{}Client", item.ident));
+
+    let mut methods = Vec::new();
+    for trait_item in &mut item.items {
+        let TraitItem::Fn(method) = trait_item else {
+            continue;
+        };
+        let rpc = RpcMethod::parse(method)?;
+        method.attrs.retain(|attr| {
+            !["validate", "public", "permission"]
+                .iter()
+                .any(|name| attr.path().is_ident(name))
+        });
+        desugar_async(method, &rpc.output);
+        methods.push(rpc);
+    }
+    if methods.is_empty() {
+        return Err(syn::Error::new(
+            item.ident.span(),
+            "an rpc service needs at least one method",
+        ));
+    }
+
+    let variants = methods.iter().map(RpcMethod::variant_def);
+    let arms = methods.iter().map(|method| {
+        let pat = method.variant_pat(&methods_ident);
+        let ident = &method.ident;
+        let names = method.args.iter().map(|(name, _)| name);
+        let validate = method
+            .args
+            .iter()
+            .filter(|_| method.validate)
+            .map(|(name, _)| {
+                quote! {
+                    if let ::core::result::Result::Err(errors) =
+                        crate::validation::Validate::validate(&#name)
+                    {
+                        return crate::service::IntoResponse::into_response(
+                            crate::error::AppError::from(errors),
+                        );
+                    }
+                }
+            });
+        quote! {
+            #pat => {
+                #(#validate)*
+                crate::service::IntoResponse::into_response(self.#ident(#(#names),*).await)
+            }
+        }
+    });
+
+    item.items.push(parse_quote! {
+        /// Checks that the current request may make `method`, before it is
+        /// dispatched. Lets every call through unless overridden.
+        fn authorize(
+            &self,
+            method: &#methods_ident,
+        ) -> impl ::core::future::Future<
+            Output = ::core::result::Result<(), crate::error::AppError>,
+        > + Send {
+            let _ = method;
+            async { ::core::result::Result::Ok(()) }
+        }
+    });
+    item.items.push(parse_quote! {
+        /// Authorizes `method` and runs the trait method matching it.
+        fn dispatch(
+            &self,
+            method: #methods_ident,
+        ) -> impl ::core::future::Future<Output = crate::service::Response> + Send
+        where
+            Self: Sync,
+        {
+            async move {
+                if let ::core::result::Result::Err(err) = self.authorize(&method).await {
+                    return crate::service::IntoResponse::into_response(err);
+                }
+                match method {
+                    #(#arms,)*
+                }
+            }
+        }
+    });
+    item.items.push(parse_quote! {
+        /// Decodes a raw `{ "method", "data" }` request and dispatches it.
+        fn call(
+            &self,
+            req: crate::service::Request,
+        ) -> impl ::core::future::Future<Output = crate::service::Response> + Send
+        where
+            Self: Sync,
+        {
+            async move {
+                let method = match ::serde_json::to_value(req)
+                    .and_then(::serde_json::from_value::<#methods_ident>)
+                {
+                    Ok(method) => method,
+                    Err(err) => {
+                        return crate::service::IntoResponse::into_response(
+                            crate::error::AppError::BadRequest(err.to_string()),
+                        )
+                    }
+                };
+                ::tracing::info!("🌸 Received request: {:?}", method);
+                self.dispatch(method).await
+            }
+        }
+    });
+    item.items.push(parse_quote! {
+        /// Decodes a JSON-RPC call and dispatches it.
+        fn call_json_rpc(
+            &self,
+            call: crate::jsonrpc::Call,
+        ) -> impl ::core::future::Future<Output = crate::jsonrpc::Outcome> + Send
+        where
+            Self: Sync,
+        {
+            async move {
+                let method = #methods_ident::from_params(&call.method, call.params)?;
+                ::tracing::info!("🌸 Received JSON-RPC request: {:?}", method);
+                crate::jsonrpc::outcome(self.dispatch(method).await)
+            }
+        }
+    });
+    item.items.push(parse_quote! {
+        /// Serves `router` with `self` as the state every handler receives.
+        fn serve(
+            self,
+            router: crate::service::Router<Self>,
+            config: crate::config::ServerConfig,
+        ) -> impl ::core::future::Future<Output = ::anyhow::Result<()>> + Send
+        where
+            Self: Sized + Send + Sync + 'static,
+        {
+            crate::service::server_loop(router, self, config)
+        }
+    });
+
+    let param_arms = methods.iter().map(|method| {
+        let name = method.variant.to_string();
+        let variant = &method.variant;
+        let decode = match method.args.as_slice() {
+            [] => quote! {
+                crate::jsonrpc::no_params(params)?;
+                #methods_ident::#variant
+            },
+            [(arg, _)] => {
+                let arg = arg.to_string();
+                quote! {
+                    let param = crate::jsonrpc::single_param(params, #arg)?;
+                    #methods_ident::#variant(
+                        ::serde_json::from_value(param)
+                            .map_err(crate::jsonrpc::Error::invalid_params)?,
+                    )
+                }
+            }
+            args => {
+                let names = args.iter().map(|(name, _)| name.to_string());
+                let fields = args.iter().map(|(name, _)| {
+                    let key = name.to_string();
+                    quote!(#name: crate::jsonrpc::take_param(&mut params, #key)?)
+                });
+                quote! {
+                    let mut params = crate::jsonrpc::named_params(params, &[#(#names),*])?;
+                    #methods_ident::#variant { #(#fields),* }
+                }
+            }
+        };
+        quote! {
+            #name => { #decode }
+        }
+    });
+
+    let client_methods = methods.iter().map(|method| {
+        let ident = &method.ident;
+        let args = method.args.iter().map(|(name, ty)| quote!(#name: #ty));
+        let request = method.variant_pat(&methods_ident);
+        let ok = ok_type(&method.output);
+        quote! {
+            pub async fn #ident(
+                &self,
+                #(#args),*
+            ) -> ::core::result::Result<#ok, crate::client::ClientError> {
+                self.inner.call(&#request).await
+            }
+        }
+    });
+
+    let name_arms = methods.iter().map(|method| {
+        let pat = method.variant_pat_ignored(&methods_ident);
+        let name = method.variant.to_string();
+        quote!(#pat => #name)
+    });
+    let public_arms = methods.iter().map(|method| {
+        let pat = method.variant_pat_ignored(&methods_ident);
+        let public = method.public;
+        quote!(#pat => #public)
+    });
+
+    let permission_arms = methods.iter().map(|method| {
+        let pat = method.variant_pat_ignored(&methods_ident);
+        match &method.permission {
+            Some(permission) => quote!(#pat => ::core::option::Option::Some(#permission)),
+            None => quote!(#pat => ::core::option::Option::None),
+        }
+    });
+    let mut permissions = methods
+        .iter()
+        .filter_map(|method| method.permission.clone())
+        .collect::<Vec<_>>();
+    permissions.sort();
+    permissions.dedup();
+    let names = methods.iter().map(|method| method.variant.to_string());
+
+    let doc = format!("Requests accepted by [`{}`].", item.ident);
+    let client_doc = format!("Typed client for [`{}`].", item.ident);
+    Ok(quote! {
+        #item
+
+        #[doc = #doc]
+        #[derive(Debug, Clone, ::serde::Serialize, ::serde::Deserialize)]
+        #[serde(tag = "method", content = "data")]
+        #vis enum #methods_ident {
+            #(#variants,)*
+        }
+
+        impl #methods_ident {
+            /// Every method's name on the wire, in declaration order.
+            pub const NAMES: &'static [&'static str] = &[#(#names),*];
+
+            /// Every permission some method needs, sorted.
+            pub const PERMISSIONS: &'static [&'static str] = &[#(#permissions),*];
+
+            /// The method's name on the wire, e.g. `GrabCoffee`.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    #(#name_arms,)*
+                }
+            }
+
+            /// Whether the method was marked `#[public]`, callable without credentials.
+            pub fn is_public(&self) -> bool {
+                match self {
+                    #(#public_arms,)*
+                }
+            }
+
+            /// The permission named by the method's `#[permission(..)]`, if any.
+            pub fn permission(&self) -> ::core::option::Option<&'static str> {
+                match self {
+                    #(#permission_arms,)*
+                }
+            }
+
+            /// Decodes JSON-RPC `params`, given by position or by name, for `method`.
+            pub fn from_params(
+                method: &str,
+                params: ::core::option::Option<::serde_json::Value>,
+            ) -> ::core::result::Result<Self, crate::jsonrpc::Error> {
+                ::core::result::Result::Ok(match method {
+                    #(#param_arms)*
+                    _ => return ::core::result::Result::Err(
+                        crate::jsonrpc::Error::method_not_found(method),
+                    ),
+                })
+            }
+        }
+
+        #[doc = #client_doc]
+        #[derive(Debug, Clone)]
+        #vis struct #client_ident {
+            inner: crate::client::RpcClient,
+        }
+
+        impl #client_ident {
+            /// Creates a client for the server listening on `addr`, e.g. `127.0.0.1:8080`.
+            pub fn new(addr: impl Into<String>) -> Self {
+                Self::from(crate::client::RpcClient::new(addr))
+            }
+
+            #(#client_methods)*
+        }
+
+        impl ::core::convert::From<crate::client::RpcClient> for #client_ident {
+            fn from(inner: crate::client::RpcClient) -> Self {
+                Self { inner }
+            }
+        }
+    })
+}
+
+/// Picks `T` out of a `Result<T, E>` style return type, aliases such as
+/// `AppResult<T>` included, which is what the client decodes on success. Any other
+/// type is decoded as is.
+fn ok_type(output: &Type) -> Type {
+    if let Type::Path(path) = output {
+        if let Some(segment) = path.path.segments.last() {
+            if segment.ident.to_string().ends_with("Result") {
+                if let PathArguments::AngleBracketed(args) = &segment.arguments {
+                    if let Some(GenericArgument::Type(ok)) = args.args.first() {
+                        return ok.clone();
+                    }
+                }
+            }
+        }
+    }
+    output.clone()
+}
+
+/// Rewrites `async fn f(..) -> T` into `fn f(..) -> impl Future<Output = T> + Send`, so
+/// generic callers can spawn the futures.
+fn desugar_async(method: &mut TraitItemFn, output: &Type) {
+    let sig = &mut method.sig;
+    sig.asyncness = None;
+    sig.output = parse_quote! {
+        -> impl ::core::future::Future<Output = #output> + Send
+    };
+    if let Some(body) = method.default.take() {
+        method.default = Some(parse_quote!({ async move #body }));
+    }
+}
+
+fn pascal_case(snake: &str) -> String {
+    snake
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(item: TokenStream2) -> syn::Result<syn::File> {
+        let item = syn::parse2(item).unwrap();
+        let args = ServiceArgs {
+            methods: Some(format_ident!("Methods")),
+            client: None,
+        };
+        expand_service(args, item).map(|tokens| syn::parse2(tokens).unwrap())
+    }
+
+    fn expand_err(item: TokenStream2) -> String {
+        match expand(item) {
+            Ok(_) => panic!("expansion should fail"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn methods_enum(file: &syn::File) -> &syn::ItemEnum {
+        file.items
+            .iter()
+            .find_map(|item| match item {
+                syn::Item::Enum(item) if item.ident == "Methods" => Some(item),
+                _ => None,
+            })
+            .expect("no Methods enum")
+    }
+
+    #[test]
+    fn variants_follow_the_arguments() {
+        let file = expand(quote! {
+            pub trait Shop {
+                async fn list_all(&self) -> Result<Vec<u32>, Error>;
+                async fn get_one(&self, id: u32) -> Result<u32, Error>;
+                async fn set_price(&self, id: u32, price: i64) -> Result<(), Error>;
+            }
+        })
+        .unwrap();
+        let methods = methods_enum(&file);
+        let variants = methods
+            .variants
+            .iter()
+            .map(|variant| (variant.ident.to_string(), &variant.fields))
+            .collect::<Vec<_>>();
+        assert_eq!(variants[0].0, "ListAll");
+        assert!(matches!(variants[0].1, Fields::Unit));
+        assert_eq!(variants[1].0, "GetOne");
+        assert!(matches!(variants[1].1, Fields::Unnamed(fields) if fields.unnamed.len() == 1));
+        assert_eq!(variants[2].0, "SetPrice");
+        let Fields::Named(fields) = variants[2].1 else {
+            panic!("SetPrice should be a struct variant");
+        };
+        let names = fields
+            .named
+            .iter()
+            .map(|field| field.ident.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["id", "price"]);
+    }
+
+    #[test]
+    fn enum_is_tagged_by_method_with_data_content() {
+        let file = expand(quote! {
+            pub trait Shop {
+                async fn list_all(&self) -> Result<(), Error>;
+            }
+        })
+        .unwrap();
+        let serde = methods_enum(&file)
+            .attrs
+            .iter()
+            .find(|attr| attr.path().is_ident("serde"))
+            .expect("no serde attribute");
+        let serde = quote!(#serde).to_string();
+        assert!(serde.contains("tag = \"method\""), "{serde}");
+        assert!(serde.contains("content = \"data\""), "{serde}");
+    }
+
+    #[test]
+    fn names_and_permissions_are_listed() {
+        let file = expand(quote! {
+            pub trait Shop {
+                #[permission(write)]
+                async fn set_price(&self, id: u32, price: i64) -> Result<(), Error>;
+                #[public]
+                async fn list_all(&self) -> Result<(), Error>;
+                #[permission(delete)]
+                async fn remove(&self, id: u32) -> Result<(), Error>;
+                #[permission(write)]
+                async fn add(&self, price: i64) -> Result<(), Error>;
+            }
+        })
+        .unwrap();
+        let tokens = quote!(#file).to_string();
+        assert!(
+            tokens.contains(r#"& ["SetPrice" , "ListAll" , "Remove" , "Add"]"#),
+            "{tokens}"
+        );
+        assert!(tokens.contains(r#"& ["delete" , "write"]"#), "{tokens}");
+        // The marker attributes are consumed, they aren't real attributes.
+        let Some(syn::Item::Trait(item)) = file.items.first() else {
+            panic!("the trait should come first");
+        };
+        for item in &item.items {
+            if let TraitItem::Fn(method) = item {
+                let markers = method.attrs.iter().filter(|attr| {
+                    ["validate", "public", "permission"]
+                        .iter()
+                        .any(|name| attr.path().is_ident(name))
+                });
+                assert_eq!(markers.count(), 0, "{}", method.sig.ident);
+            }
+        }
+    }
+
+    #[test]
+    fn public_methods_cant_require_a_permission() {
+        let message = expand_err(quote! {
+            pub trait Shop {
+                #[public]
+                #[permission(write)]
+                async fn list_all(&self) -> Result<(), Error>;
+            }
+        });
+        assert_eq!(message, "public methods can't require a permission");
+        // The order of the attributes doesn't matter.
+        let message = expand_err(quote! {
+            pub trait Shop {
+                #[permission(write)]
+                #[public]
+                async fn list_all(&self) -> Result<(), Error>;
+            }
+        });
+        assert_eq!(message, "public methods can't require a permission");
+    }
+
+    #[test]
+    fn rejects_a_second_permission() {
+        let message = expand_err(quote! {
+            pub trait Shop {
+                #[permission(read)]
+                #[permission(write)]
+                async fn list_all(&self) -> Result<(), Error>;
+            }
+        });
+        assert_eq!(message, "a method needs at most one permission");
+    }
+
+    #[test]
+    fn rejects_unsupported_signatures() {
+        let cases = [
+            (
+                quote!(
+                    fn list_all(&self) -> Result<(), Error>;
+                ),
+                "rpc methods must be `async fn`",
+            ),
+            (
+                quote!(
+                    async fn list_all<T>(&self) -> Result<(), Error>;
+                ),
+                "rpc methods can't be generic",
+            ),
+            (
+                quote!(
+                    async fn list_all(&mut self) -> Result<(), Error>;
+                ),
+                "rpc methods must take `&self`",
+            ),
+            (
+                quote!(
+                    async fn list_all(&self, (a, b): (u32, u32)) -> Result<(), Error>;
+                ),
+                "rpc arguments must be plain identifiers",
+            ),
+        ];
+        for (method, expected) in cases {
+            let message = expand_err(quote!(pub trait Shop { #method }));
+            assert_eq!(message, expected, "{method}");
+        }
+        let message = expand_err(quote!(
+            pub trait Shop {}
+        ));
+        assert_eq!(message, "an rpc service needs at least one method");
+    }
+
+    #[test]
+    fn validate_checks_every_field_with_its_rules() {
+        let item = syn::parse2(quote! {
+            struct Params {
+                #[validate(text, length(1, 10))]
+                name: String,
+                #[validate(positive)]
+                price: Option<i64>,
+                note: String,
+            }
+        })
+        .unwrap();
+        let tokens = expand_validate(item).unwrap().to_string();
+        assert!(
+            tokens.contains("crate :: validation :: text (value)"),
+            "{tokens}"
+        );
+        assert!(
+            tokens.contains("crate :: validation :: length (value , 1 , 10)"),
+            "{tokens}"
+        );
+        assert!(tokens.contains("\"price\""), "{tokens}");
+        assert!(!tokens.contains("\"note\""), "{tokens}");
+    }
+
+    #[test]
+    fn validate_needs_a_struct_with_named_fields() {
+        let item = syn::parse2(quote!(
+            enum Params {
+                A,
+            }
+        ))
+        .unwrap();
+        let message = expand_validate(item).unwrap_err().to_string();
+        assert_eq!(message, "Validate can only be derived for structs");
+        let item = syn::parse2(quote!(
+            struct Params(String);
+        ))
+        .unwrap();
+        let message = expand_validate(item).unwrap_err().to_string();
+        assert_eq!(message, "Validate needs named fields");
+    }
+
+    #[test]
+    fn pascal_cases_method_names() {
+        assert_eq!(pascal_case("get_random_coffee"), "GetRandomCoffee");
+        assert_eq!(pascal_case("grab_id"), "GrabId");
+        assert_eq!(pascal_case("x"), "X");
+    }
+}
@@ -0,0 +1,484 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{ApiKey, Coffee, CoffeeChange, Farmer, IssuedApiKey, Nanoid, Origin, Roastery, Role},
+    error::AppResult,
+    money::{Currency, ExchangeRate, Money},
+    validation::Validate,
+};
+
+/// Methods marked `#[public]` can be called by anyone, the others need an API key
+/// whose role grants the method's `#[permission(..)]`.
+///
+/// Writes to coffees and roasteries are further limited to the roasteries a key
+/// acts for unless its role grants `all_roasteries`. Farmers, origins, exchange
+/// rates and new roasteries are shared by everyone, `write_catalog` and
+/// `delete_catalog` are meant for roles that look after the whole catalog.
+#[rpissc::service(methods = Methods, client = CoffeeClient)]
+pub trait CoffeeRpc {
+    #[public]
+    async fn get_random_coffee(&self) -> AppResult<Coffee>;
+    #[validate]
+    #[permission(write_coffees)]
+    async fn add_coffee(&self, coffee: AddCoffeeParams) -> AppResult<Coffee>;
+    /// The coffee a roastery sells from an origin.
+    #[public]
+    async fn grab_id(&self, roastery_id: Nanoid, origin_id: Nanoid) -> AppResult<Nanoid>;
+    #[public]
+    async fn grab_coffee(&self, id: Nanoid) -> AppResult<Coffee>;
+    #[validate]
+    #[permission(write_coffees)]
+    async fn edit_coffee(&self, coffee: EditCoffee) -> AppResult<Coffee>;
+    /// Pages through the catalog, see [`ListCoffees`].
+    #[validate]
+    #[public]
+    async fn list_coffees(&self, query: ListCoffees) -> AppResult<CoffeePage>;
+    /// Full-text search over roastery, farmer and origin, best matches first.
+    #[validate]
+    #[public]
+    async fn search_coffees(&self, search: SearchParams) -> AppResult<Vec<SearchHit>>;
+    /// Moves a coffee to the trash, hiding it from every other method.
+    #[permission(delete_coffees)]
+    async fn delete_coffee(&self, id: Nanoid) -> AppResult<Coffee>;
+    #[permission(read)]
+    async fn list_deleted_coffees(&self) -> AppResult<Vec<Coffee>>;
+    /// Takes a coffee back out of the trash.
+    #[permission(delete_coffees)]
+    async fn restore_coffee(&self, id: Nanoid) -> AppResult<Coffee>;
+    /// Permanently removes a coffee, which has to be in the trash already.
+    #[permission(delete_coffees)]
+    async fn purge_coffee(&self, id: Nanoid) -> AppResult<()>;
+    /// Every recorded change to a coffee, oldest first, trashed and purged ones
+    /// included.
+    #[permission(read)]
+    async fn get_coffee_history(&self, id: Nanoid) -> AppResult<Vec<CoffeeChange>>;
+    /// Edits a coffee back to how it was at an earlier version. The revert is a new
+    /// version of its own.
+    #[permission(write_coffees)]
+    async fn revert_coffee(&self, revert: RevertCoffee) -> AppResult<Coffee>;
+    /// Sets the rate `convert_to` uses from one currency to another.
+    #[validate]
+    #[permission(write_catalog)]
+    async fn set_exchange_rate(&self, rate: SetExchangeRate) -> AppResult<ExchangeRate>;
+    #[public]
+    async fn list_exchange_rates(&self) -> AppResult<Vec<ExchangeRate>>;
+
+    #[validate]
+    #[permission(write_catalog)]
+    async fn add_roastery(&self, roastery: AddRoastery) -> AppResult<Roastery>;
+    #[public]
+    async fn grab_roastery(&self, id: Nanoid) -> AppResult<Roastery>;
+    /// Every roastery, by name.
+    #[public]
+    async fn list_roasteries(&self) -> AppResult<Vec<Roastery>>;
+    #[validate]
+    #[permission(write_roasteries)]
+    async fn edit_roastery(&self, roastery: EditRoastery) -> AppResult<Roastery>;
+    /// Removes a roastery no coffee points at, trashed ones included.
+    #[permission(delete_roasteries)]
+    async fn delete_roastery(&self, id: Nanoid) -> AppResult<Roastery>;
+    /// Merges a duplicate roastery, e.g. "Onyx Coffee Lab" into "Onyx": its
+    /// coffees, trashed ones included, and the keys acting for it move to the
+    /// roastery kept, which is returned.
+    #[permission(delete_catalog)]
+    async fn merge_roasteries(&self, id: Nanoid, into: Nanoid) -> AppResult<Roastery>;
+
+    #[validate]
+    #[permission(write_catalog)]
+    async fn add_farmer(&self, farmer: AddFarmer) -> AppResult<Farmer>;
+    #[public]
+    async fn grab_farmer(&self, id: Nanoid) -> AppResult<Farmer>;
+    /// Every farmer, by name.
+    #[public]
+    async fn list_farmers(&self) -> AppResult<Vec<Farmer>>;
+    #[validate]
+    #[permission(write_catalog)]
+    async fn edit_farmer(&self, farmer: EditFarmer) -> AppResult<Farmer>;
+    /// Removes a farmer no coffee points at, trashed ones included.
+    #[permission(delete_catalog)]
+    async fn delete_farmer(&self, id: Nanoid) -> AppResult<Farmer>;
+    /// Merges a duplicate farmer, its coffees move to the farmer kept.
+    #[permission(delete_catalog)]
+    async fn merge_farmers(&self, id: Nanoid, into: Nanoid) -> AppResult<Farmer>;
+
+    #[validate]
+    #[permission(write_catalog)]
+    async fn add_origin(&self, origin: AddOrigin) -> AppResult<Origin>;
+    #[public]
+    async fn grab_origin(&self, id: Nanoid) -> AppResult<Origin>;
+    /// Every origin, by name.
+    #[public]
+    async fn list_origins(&self) -> AppResult<Vec<Origin>>;
+    #[validate]
+    #[permission(write_catalog)]
+    async fn edit_origin(&self, origin: EditOrigin) -> AppResult<Origin>;
+    /// Removes an origin no coffee points at, trashed ones included.
+    #[permission(delete_catalog)]
+    async fn delete_origin(&self, id: Nanoid) -> AppResult<Origin>;
+    /// Merges a duplicate origin, its coffees move to the origin kept.
+    #[permission(delete_catalog)]
+    async fn merge_origins(&self, id: Nanoid, into: Nanoid) -> AppResult<Origin>;
+
+    /// Issues a key, returned this once.
+    #[validate]
+    #[permission(manage_keys)]
+    async fn create_api_key(&self, key: CreateApiKey) -> AppResult<IssuedApiKey>;
+    /// Every key, revoked ones included.
+    #[permission(manage_keys)]
+    async fn list_api_keys(&self) -> AppResult<Vec<ApiKey>>;
+    /// Replaces a key's secret, the old one stops working right away.
+    #[permission(manage_keys)]
+    async fn rotate_api_key(&self, id: Nanoid) -> AppResult<IssuedApiKey>;
+    #[permission(manage_keys)]
+    async fn revoke_api_key(&self, id: Nanoid) -> AppResult<ApiKey>;
+    /// Every role with its permissions, by name.
+    #[permission(manage_keys)]
+    async fn list_roles(&self) -> AppResult<Vec<Role>>;
+    /// Creates a role or replaces the permissions of an existing one.
+    #[validate]
+    #[permission(manage_keys)]
+    async fn set_role(&self, role: SetRole) -> AppResult<Role>;
+    /// Gives a key another role, effective from its next call.
+    #[permission(manage_keys)]
+    async fn set_api_key_role(&self, id: Nanoid, role: String) -> AppResult<ApiKey>;
+    /// Lets a key act for a roastery, returning all the roasteries it acts for.
+    #[permission(manage_keys)]
+    async fn assign_roastery(
+        &self,
+        key_id: Nanoid,
+        roastery_id: Nanoid,
+    ) -> AppResult<Vec<Roastery>>;
+    /// Stops a key acting for a roastery, returning the roasteries it still acts for.
+    #[permission(manage_keys)]
+    async fn unassign_roastery(
+        &self,
+        key_id: Nanoid,
+        roastery_id: Nanoid,
+    ) -> AppResult<Vec<Roastery>>;
+    /// The roasteries a key acts for, by name.
+    #[permission(manage_keys)]
+    async fn list_key_roasteries(&self, key_id: Nanoid) -> AppResult<Vec<Roastery>>;
+}
+
+/// Longest accepted roastery or farmer name, in characters.
+pub const MAX_NAME_LENGTH: usize = 100;
+/// Longest accepted origin, in characters.
+pub const MAX_ORIGIN_LENGTH: usize = 200;
+/// Longest accepted region or contact, in characters.
+pub const MAX_DETAIL_LENGTH: usize = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct AddCoffeeParams {
+    pub roastery_id: Nanoid,
+    #[validate(icon)]
+    pub icon: String,
+    pub farmer_id: Nanoid,
+    #[validate(currency, non_negative)]
+    pub price: Money,
+    pub origin_id: Nanoid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct EditCoffee {
+    pub id: Nanoid,
+    pub roastery_id: Option<Nanoid>,
+    #[validate(icon)]
+    pub icon: Option<String>,
+    pub farmer_id: Option<Nanoid>,
+    #[validate(currency, non_negative)]
+    pub price: Option<Money>,
+    pub origin_id: Option<Nanoid>,
+    /// Only apply the edit if the coffee is still at this version, failing with a
+    /// conflict carrying the current coffee otherwise.
+    #[serde(default)]
+    pub expected_version: Option<i64>,
+}
+
+/// Largest page [`CoffeeRpc::list_coffees`] returns, whatever the requested limit.
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Filters, order and position of a catalog listing. Every field is optional, an
+/// empty query lists every coffee oldest first.
+///
+/// To fetch the next page, repeat the query with `cursor` set to the previous
+/// page's [`CoffeePage::next_cursor`]. A cursor is only valid for the sort it was
+/// issued for.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+#[serde(default)]
+pub struct ListCoffees {
+    pub roastery_id: Option<Nanoid>,
+    pub origin_id: Option<Nanoid>,
+    pub farmer_id: Option<Nanoid>,
+    /// Only coffees of the roasteries the caller's API key acts for.
+    pub mine: bool,
+    /// Only coffees priced in this currency.
+    #[validate(currency)]
+    pub currency: Option<Currency>,
+    /// Lowest price to include in minor units of the coffee's currency, inclusive.
+    pub min_price: Option<i64>,
+    /// Highest price to include in minor units of the coffee's currency, inclusive.
+    pub max_price: Option<i64>,
+    /// Also show every price in this currency, as [`Coffee::converted_price`].
+    #[validate(currency)]
+    pub convert_to: Option<Currency>,
+    pub sort: CoffeeSort,
+    pub order: SortOrder,
+    /// Page size, 50 by default and capped at [`MAX_PAGE_SIZE`].
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoffeeSort {
+    /// By amount, regardless of currency, so best combined with a currency filter.
+    Price,
+    /// By roastery name.
+    Roastery,
+    #[default]
+    CreatedAt,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoffeePage {
+    pub coffees: Vec<Coffee>,
+    /// Cursor for the following page, `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct SearchParams {
+    /// Words to look for. Every word has to match, as a prefix and ignoring
+    /// diacritics, so `caf imp` finds "Café Imports".
+    pub query: String,
+    /// Number of hits, 20 by default and capped at [`MAX_PAGE_SIZE`].
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// Also show every price in this currency, as [`Coffee::converted_price`].
+    #[serde(default)]
+    #[validate(currency)]
+    pub convert_to: Option<Currency>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SearchHit {
+    #[sqlx(flatten)]
+    pub coffee: Coffee,
+    /// Relevance, higher is better. Only comparable within one search.
+    pub score: f64,
+    /// The best matching field with the matched words wrapped in `<mark>` tags.
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevertCoffee {
+    pub id: Nanoid,
+    pub to_version: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct SetExchangeRate {
+    #[validate(currency)]
+    pub from: Currency,
+    #[validate(currency)]
+    pub to: Currency,
+    /// Units of `to` one unit of `from` is worth.
+    #[validate(positive)]
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct AddRoastery {
+    #[validate(text(MAX_NAME_LENGTH))]
+    pub name: String,
+    #[serde(default)]
+    #[validate(url)]
+    pub website: Option<String>,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub contact: Option<String>,
+}
+
+/// Changes the fields that are set, leaving the others as they are.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct EditRoastery {
+    pub id: Nanoid,
+    #[serde(default)]
+    #[validate(text(MAX_NAME_LENGTH))]
+    pub name: Option<String>,
+    #[serde(default)]
+    #[validate(url)]
+    pub website: Option<String>,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub contact: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct AddFarmer {
+    #[validate(text(MAX_NAME_LENGTH))]
+    pub name: String,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub region: Option<String>,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub contact: Option<String>,
+}
+
+/// Changes the fields that are set, leaving the others as they are.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct EditFarmer {
+    pub id: Nanoid,
+    #[serde(default)]
+    #[validate(text(MAX_NAME_LENGTH))]
+    pub name: Option<String>,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub region: Option<String>,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub contact: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct AddOrigin {
+    #[validate(text(MAX_ORIGIN_LENGTH))]
+    pub name: String,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub region: Option<String>,
+    #[validate(known_country)]
+    pub country: String,
+}
+
+/// Changes the fields that are set, leaving the others as they are.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct EditOrigin {
+    pub id: Nanoid,
+    #[serde(default)]
+    #[validate(text(MAX_ORIGIN_LENGTH))]
+    pub name: Option<String>,
+    #[serde(default)]
+    #[validate(text(MAX_DETAIL_LENGTH))]
+    pub region: Option<String>,
+    #[serde(default)]
+    #[validate(known_country)]
+    pub country: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct CreateApiKey {
+    /// Who the key is for, recorded as the actor of their changes.
+    #[validate(text(MAX_NAME_LENGTH))]
+    pub name: String,
+    /// One of the roles of `ListRoles`.
+    pub role: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct SetRole {
+    #[validate(text(MAX_NAME_LENGTH))]
+    pub name: String,
+    #[validate(permissions)]
+    pub permissions: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::jsonrpc;
+
+    fn wire(method: &Methods) -> serde_json::Value {
+        serde_json::to_value(method).unwrap()
+    }
+
+    #[test]
+    fn unit_variants_carry_no_data() {
+        assert_eq!(
+            wire(&Methods::GetRandomCoffee),
+            json!({ "method": "GetRandomCoffee" })
+        );
+        let method: Methods = serde_json::from_value(json!({ "method": "ListRoles" })).unwrap();
+        assert!(matches!(method, Methods::ListRoles));
+    }
+
+    #[test]
+    fn newtype_variants_carry_their_argument_as_data() {
+        assert_eq!(
+            wire(&Methods::GrabCoffee("abc".into())),
+            json!({ "method": "GrabCoffee", "data": "abc" })
+        );
+        let method: Methods =
+            serde_json::from_value(json!({ "method": "DeleteCoffee", "data": "abc" })).unwrap();
+        assert!(matches!(method, Methods::DeleteCoffee(id) if &*id == "abc"));
+    }
+
+    #[test]
+    fn struct_variants_carry_their_arguments_by_name() {
+        let method = Methods::GrabId {
+            roastery_id: "r".into(),
+            origin_id: "o".into(),
+        };
+        assert_eq!(
+            wire(&method),
+            json!({ "method": "GrabId", "data": { "roastery_id": "r", "origin_id": "o" } })
+        );
+        let missing = serde_json::from_value::<Methods>(
+            json!({ "method": "GrabId", "data": { "roastery_id": "r" } }),
+        );
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn json_rpc_params_by_position_or_name() {
+        let by_position = Methods::from_params("GrabId", Some(json!(["r", "o"]))).unwrap();
+        let by_name = Methods::from_params(
+            "GrabId",
+            Some(json!({ "origin_id": "o", "roastery_id": "r" })),
+        )
+        .unwrap();
+        for method in [by_position, by_name] {
+            assert!(matches!(
+                method,
+                Methods::GrabId { roastery_id, origin_id } if &*roastery_id == "r" && &*origin_id == "o"
+            ));
+        }
+        let single = Methods::from_params("GrabCoffee", Some(json!(["abc"]))).unwrap();
+        assert!(matches!(single, Methods::GrabCoffee(id) if &*id == "abc"));
+        assert!(Methods::from_params("ListRoles", None).is_ok());
+    }
+
+    #[test]
+    fn json_rpc_rejects_unknown_methods_and_bad_params() {
+        let err = Methods::from_params("BrewCoffee", None).unwrap_err();
+        assert_eq!(err.code, jsonrpc::METHOD_NOT_FOUND);
+        let err = Methods::from_params("GrabId", Some(json!(["r"]))).unwrap_err();
+        assert_eq!(err.code, jsonrpc::INVALID_PARAMS);
+        let err = Methods::from_params("ListRoles", Some(json!(["extra"]))).unwrap_err();
+        assert_eq!(err.code, jsonrpc::INVALID_PARAMS);
+    }
+
+    #[test]
+    fn access_markers() {
+        assert_eq!(Methods::GetRandomCoffee.name(), "GetRandomCoffee");
+        assert!(Methods::GetRandomCoffee.is_public());
+        assert_eq!(Methods::GetRandomCoffee.permission(), None);
+        assert!(!Methods::ListRoles.is_public());
+        assert_eq!(Methods::ListRoles.permission(), Some("manage_keys"));
+        assert!(Methods::NAMES.contains(&"GrabId"));
+        assert!(Methods::PERMISSIONS
+            .windows(2)
+            .all(|pair| pair[0] < pair[1]));
+    }
+}
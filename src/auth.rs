@@ -0,0 +1,73 @@
+//! API keys, sent as `Authorization: Bearer <key>`.
+//!
+//! Keys are random tokens shown once when issued. Only their SHA-256 is stored,
+//! which is enough for secrets this long, along with a short prefix so people can
+//! tell their keys apart.
+
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+/// Permission to write the coffees of every roastery. Keys whose role lacks it
+/// only write those of the roasteries they are assigned.
+pub const ALL_ROASTERIES: &str = "all_roasteries";
+
+/// What every key starts with, so leaked ones are easy to scan for.
+pub const KEY_PREFIX: &str = "ck_";
+
+/// Random characters after [`KEY_PREFIX`].
+const KEY_LENGTH: usize = 40;
+
+/// Characters of a key kept in the clear to identify it.
+const SHOWN_LENGTH: usize = 10;
+
+/// The API key a request authenticated with.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub key_id: String,
+    pub name: String,
+    pub role: String,
+    /// What the role granted when the key was checked.
+    pub permissions: Vec<String>,
+}
+
+impl Principal {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|granted| granted == permission)
+    }
+}
+
+/// A freshly generated key, only ever held in memory until it is handed out.
+#[derive(Debug, Clone)]
+pub struct GeneratedKey {
+    pub key: String,
+    pub hash: String,
+    /// The start of the key, stored in the clear.
+    pub prefix: String,
+}
+
+impl GeneratedKey {
+    pub fn new() -> Self {
+        let secret = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(KEY_LENGTH)
+            .map(char::from)
+            .collect::<String>();
+        let key = format!("{KEY_PREFIX}{secret}");
+        Self {
+            hash: hash_key(&key),
+            prefix: key[..SHOWN_LENGTH].to_string(),
+            key,
+        }
+    }
+}
+
+impl Default for GeneratedKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The hex SHA-256 a key is stored and looked up by.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
@@ -0,0 +1,313 @@
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::error::{ErrorBody, ErrorCode, ErrorEnvelope};
+
+/// Transport underneath the clients generated by `#[rpissc::service(client = ...)]`.
+///
+/// Every call posts a `{ "method", "data" }` request to the server's RPC endpoint over
+/// a fresh HTTP/1.1 connection.
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    addr: String,
+    path: String,
+    api_key: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    Encode(serde_json::Error),
+    Decode(serde_json::Error),
+    InvalidResponse(&'static str),
+    /// The server rejected the call with a structured error.
+    Api {
+        status: u16,
+        error: ErrorBody,
+    },
+    /// The server answered with a non-2xx status and no error object.
+    Server {
+        status: u16,
+        body: String,
+    },
+}
+
+impl ClientError {
+    /// The server's error code, if the call failed with a structured error.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Api { error, .. } => Some(error.code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "io error: {err}"),
+            ClientError::Encode(err) => write!(f, "failed to encode request: {err}"),
+            ClientError::Decode(err) => write!(f, "failed to decode response: {err}"),
+            ClientError::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+            ClientError::Api { status, error } => {
+                write!(
+                    f,
+                    "server error {status} ({:?}): {}",
+                    error.code, error.message
+                )
+            }
+            ClientError::Server { status, body } => write!(f, "server error {status}: {body}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(err) => Some(err),
+            ClientError::Encode(err) | ClientError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+impl RpcClient {
+    /// Creates a client for the server listening on `addr`, e.g. `127.0.0.1:8080`.
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            path: "/rpc".into(),
+            api_key: None,
+        }
+    }
+
+    /// Overrides the path of the RPC endpoint, `/rpc` by default.
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Sends `key` as `Authorization: Bearer` with every call.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    pub async fn call<M, T>(&self, method: &M) -> Result<T, ClientError>
+    where
+        M: Serialize,
+        T: DeserializeOwned,
+    {
+        let body = serde_json::to_vec(method).map_err(ClientError::Encode)?;
+        let (status, body) = self.post(&body).await?;
+        if !(200..300).contains(&status) {
+            return Err(match serde_json::from_slice::<ErrorEnvelope>(&body) {
+                Ok(envelope) => ClientError::Api {
+                    status,
+                    error: envelope.error,
+                },
+                Err(_) => ClientError::Server {
+                    status,
+                    body: String::from_utf8_lossy(&body).into_owned(),
+                },
+            });
+        }
+        serde_json::from_slice(&body).map_err(ClientError::Decode)
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(u16, Vec<u8>), ClientError> {
+        let mut socket = TcpStream::connect(&self.addr).await?;
+        let authorization = self
+            .api_key
+            .as_ref()
+            .map(|key| format!("Authorization: Bearer {key}\r\n"))
+            .unwrap_or_default();
+        let head = format!(
+            "POST {} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: {}\r\n\
+            {authorization}\
+            Connection: close\r\n\r\n",
+            self.path,
+            self.addr,
+            body.len()
+        );
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(body).await?;
+        socket.flush().await?;
+
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).await?;
+        parse_response(data)
+    }
+}
+
+fn parse_response(mut data: Vec<u8>) -> Result<(u16, Vec<u8>), ClientError> {
+    let end = data
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(ClientError::InvalidResponse("incomplete response head"))?;
+    let head = std::str::from_utf8(&data[..end])
+        .map_err(|_| ClientError::InvalidResponse("response head is not valid UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(ClientError::InvalidResponse("invalid status line"))?;
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>())
+        .transpose()
+        .map_err(|_| ClientError::InvalidResponse("invalid Content-Length"))?;
+
+    let mut body = data.split_off(end + 4);
+    if let Some(len) = content_length {
+        if body.len() < len {
+            return Err(ClientError::InvalidResponse("truncated response body"));
+        }
+        body.truncate(len);
+    }
+    Ok((status, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::api::{CoffeeClient, Methods};
+
+    /// Answers a single connection with `response`, returning the request it read.
+    async fn serve_once(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map_or(0, |len| len.parse().unwrap());
+                    if body.len() >= len {
+                        break;
+                    }
+                }
+            }
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn response_body_is_cut_at_content_length() {
+        let raw = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}trailing".to_vec();
+        let (status, body) = parse_response(raw).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"{}");
+
+        let raw = b"HTTP/1.1 204 No Content\r\n\r\nrest".to_vec();
+        assert_eq!(parse_response(raw).unwrap(), (204, b"rest".to_vec()));
+    }
+
+    #[test]
+    fn malformed_responses_are_rejected() {
+        let cases: [(&[u8], &str); 4] = [
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n",
+                "incomplete response head",
+            ),
+            (b"HTTP/1.1 OK\r\n\r\n", "invalid status line"),
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n",
+                "invalid Content-Length",
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n{}",
+                "truncated response body",
+            ),
+        ];
+        for (raw, reason) in cases {
+            match parse_response(raw.to_vec()) {
+                Err(ClientError::InvalidResponse(got)) => assert_eq!(got, reason),
+                other => panic!("expected {reason:?}, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_calls_post_the_method_and_decode_the_result() {
+        let (addr, request) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\n\"abc123def4\"",
+        )
+        .await;
+        let client = CoffeeClient::from(
+            RpcClient::new(addr.clone())
+                .with_path("/api/rpc")
+                .with_api_key("secret"),
+        );
+        let id = client
+            .grab_id("roastery".into(), "origin".into())
+            .await
+            .unwrap();
+        assert_eq!(&*id, "abc123def4");
+
+        let request = request.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /api/rpc HTTP/1.1\r\n"), "{head}");
+        assert!(head.contains(&format!("Host: {addr}\r\n")), "{head}");
+        assert!(head.contains("Authorization: Bearer secret\r\n"), "{head}");
+        let sent: Methods = serde_json::from_str(body).unwrap();
+        assert_eq!(sent.name(), "GrabId");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(body).unwrap()["data"],
+            serde_json::json!({ "roastery_id": "roastery", "origin_id": "origin" })
+        );
+    }
+
+    #[tokio::test]
+    async fn error_statuses_become_client_errors() {
+        let (addr, _) = serve_once(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 52\r\n\r\n\
+            {\"error\":{\"code\":\"not_found\",\"message\":\"no coffee\"}}",
+        )
+        .await;
+        let err = RpcClient::new(addr)
+            .call::<_, serde_json::Value>(&serde_json::json!({ "method": "GrabCoffee" }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NotFound));
+        assert!(
+            matches!(err, ClientError::Api { status: 404, ref error } if error.message == "no coffee")
+        );
+
+        let (addr, _) =
+            serve_once("HTTP/1.1 502 Bad Gateway\r\nContent-Length: 8\r\n\r\nupstream").await;
+        let err = RpcClient::new(addr)
+            .call::<_, serde_json::Value>(&serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), None);
+        assert!(matches!(err, ClientError::Server { status: 502, ref body } if body == "upstream"));
+    }
+}
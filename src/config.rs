@@ -0,0 +1,379 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::api::Methods;
+
+/// Config file read when no `--config` is given; it's fine for it not to exist.
+pub const DEFAULT_CONFIG_PATH: &str = "coffee.toml";
+
+/// Everything the server needs to start, loaded from a TOML file and overridden by
+/// `COFFEE_*` environment variables and command line flags, in that order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub database_url: String,
+    pub min_connections: u32,
+    pub max_connections: u32,
+    pub request_timeout_secs: u64,
+    /// Seconds open connections get to finish once shutdown starts.
+    pub shutdown_timeout_secs: u64,
+    /// Largest accepted request body in bytes.
+    pub max_body_size: usize,
+    /// What `POST /rpc` speaks.
+    pub rpc_protocol: RpcProtocol,
+    /// How often each client may call each method.
+    pub rate_limits: RateLimits,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum RpcProtocol {
+    /// `{ "method", "data" }` requests, as sent by the generated clients
+    #[default]
+    Native,
+    /// JSON-RPC 2.0, batches included
+    JsonRpc,
+}
+
+/// Rate limits by method name, counted per client: the API key a request
+/// authenticated with, or else its peer address.
+///
+/// ```toml
+/// [rate_limits.default]
+/// burst = 60
+/// per_second = 10
+///
+/// [rate_limits.methods.GetRandomCoffee]
+/// burst = 5
+/// per_second = 1
+/// daily_quota = 1000
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Limit of the methods not listed in `methods`, unlimited if unset.
+    pub default: Option<RateLimit>,
+    pub methods: BTreeMap<String, RateLimit>,
+}
+
+impl RateLimits {
+    pub fn for_method(&self, method: &str) -> Option<&RateLimit> {
+        self.methods.get(method).or(self.default.as_ref())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(limit) = &self.default {
+            limit.validate().context("invalid rate_limits.default")?;
+        }
+        for (method, limit) in &self.methods {
+            anyhow::ensure!(
+                Methods::NAMES.contains(&method.as_str()),
+                "rate_limits.methods.{method} names no method"
+            );
+            limit
+                .validate()
+                .with_context(|| format!("invalid rate_limits.methods.{method}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A token bucket, optionally with a cap on calls per UTC day.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Calls a client can make in a row, the size of its bucket.
+    pub burst: u32,
+    /// Calls per second the bucket refills with.
+    pub per_second: f64,
+    /// Calls a client gets per UTC day. Counted in the database, so restarts
+    /// don't reset it.
+    #[serde(default)]
+    pub daily_quota: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.burst > 0, "burst must be at least 1");
+        anyhow::ensure!(
+            self.per_second.is_finite() && self.per_second > 0.0,
+            "per_second must be positive"
+        );
+        anyhow::ensure!(
+            self.daily_quota != Some(0),
+            "daily_quota must be at least 1"
+        );
+        Ok(())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".into(),
+            database_url: "sqlite://./coffee.db".into(),
+            min_connections: 0,
+            max_connections: 10,
+            request_timeout_secs: 30,
+            shutdown_timeout_secs: 10,
+            max_body_size: crate::http::MAX_BODY_SIZE,
+            rpc_protocol: RpcProtocol::default(),
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Loads `path`, or [`DEFAULT_CONFIG_PATH`] if it exists, applies `overrides` and
+    /// validates the result.
+    pub fn load(path: Option<&Path>, overrides: ConfigOverrides) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+        overrides.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.max_connections > 0,
+            "max_connections must be at least 1"
+        );
+        anyhow::ensure!(
+            self.min_connections <= self.max_connections,
+            "min_connections must not exceed max_connections"
+        );
+        anyhow::ensure!(
+            self.request_timeout_secs > 0,
+            "request_timeout_secs must be at least 1"
+        );
+        self.rate_limits.validate()?;
+        Ok(())
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+/// Per-field overrides, from flags or their `COFFEE_*` environment variables.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct ConfigOverrides {
+    /// Address to listen on, e.g. 127.0.0.1:8080
+    #[arg(long, env = "COFFEE_LISTEN")]
+    pub listen: Option<String>,
+    /// SQLite database URL, e.g. sqlite://./coffee.db
+    #[arg(long, env = "COFFEE_DATABASE_URL")]
+    pub database_url: Option<String>,
+    /// Connections the pool keeps open while idle
+    #[arg(long, env = "COFFEE_MIN_CONNECTIONS")]
+    pub min_connections: Option<u32>,
+    /// Upper bound on pooled connections
+    #[arg(long, env = "COFFEE_MAX_CONNECTIONS")]
+    pub max_connections: Option<u32>,
+    /// Seconds a request may take before it is answered with a timeout error
+    #[arg(long, env = "COFFEE_REQUEST_TIMEOUT_SECS")]
+    pub request_timeout_secs: Option<u64>,
+    /// Seconds open connections get to finish on SIGINT/SIGTERM before they are cut
+    #[arg(long, env = "COFFEE_SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Largest accepted request body in bytes
+    #[arg(long, env = "COFFEE_MAX_BODY_SIZE")]
+    pub max_body_size: Option<usize>,
+    /// Protocol spoken on POST /rpc
+    #[arg(long, env = "COFFEE_RPC_PROTOCOL")]
+    pub rpc_protocol: Option<RpcProtocol>,
+}
+
+impl ConfigOverrides {
+    pub fn apply(self, config: &mut ServerConfig) {
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(database_url) = self.database_url {
+            config.database_url = database_url;
+        }
+        if let Some(min_connections) = self.min_connections {
+            config.min_connections = min_connections;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(request_timeout_secs) = self.request_timeout_secs {
+            config.request_timeout_secs = request_timeout_secs;
+        }
+        if let Some(shutdown_timeout_secs) = self.shutdown_timeout_secs {
+            config.shutdown_timeout_secs = shutdown_timeout_secs;
+        }
+        if let Some(max_body_size) = self.max_body_size {
+            config.max_body_size = max_body_size;
+        }
+        if let Some(rpc_protocol) = self.rpc_protocol {
+            config.rpc_protocol = rpc_protocol;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct Args {
+        #[command(flatten)]
+        overrides: ConfigOverrides,
+    }
+
+    /// Writes `contents` to a config file of its own in the temp directory.
+    fn config_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("coffee-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn files_fill_in_what_they_set() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            listen = "0.0.0.0:9000"
+            rpc_protocol = "json_rpc"
+
+            [rate_limits.default]
+            burst = 60
+            per_second = 10
+
+            [rate_limits.methods.GetRandomCoffee]
+            burst = 5
+            per_second = 1
+            daily_quota = 1000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:9000");
+        assert_eq!(config.rpc_protocol, RpcProtocol::JsonRpc);
+        assert_eq!(
+            config.max_connections,
+            ServerConfig::default().max_connections
+        );
+        assert_eq!(
+            config.rate_limits.for_method("GetRandomCoffee"),
+            Some(&RateLimit {
+                burst: 5,
+                per_second: 1.0,
+                daily_quota: Some(1000),
+            })
+        );
+        assert_eq!(
+            config.rate_limits.for_method("AddCoffee").unwrap().burst,
+            60
+        );
+        config.validate().unwrap();
+
+        let err = toml::from_str::<ServerConfig>("max_conections = 5").unwrap_err();
+        assert!(err.to_string().contains("max_conections"), "{err}");
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = config_file(
+            "overrides",
+            "listen = \"0.0.0.0:9000\"\nmax_connections = 4\nrequest_timeout_secs = 5\n",
+        );
+        let args = Args::try_parse_from([
+            "coffee",
+            "--max-connections",
+            "8",
+            "--rpc-protocol",
+            "json_rpc",
+        ])
+        .unwrap();
+        let config = ServerConfig::load(Some(&path), args.overrides).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:9000");
+        assert_eq!(config.max_connections, 8);
+        assert_eq!(config.request_timeout_secs, 5);
+        assert_eq!(config.rpc_protocol, RpcProtocol::JsonRpc);
+    }
+
+    #[test]
+    fn flags_override_the_environment() {
+        std::env::set_var("COFFEE_SHUTDOWN_TIMEOUT_SECS", "20");
+        std::env::set_var("COFFEE_MAX_BODY_SIZE", "1024");
+        let args = Args::try_parse_from(["coffee", "--max-body-size", "2048"]);
+        std::env::remove_var("COFFEE_SHUTDOWN_TIMEOUT_SECS");
+        std::env::remove_var("COFFEE_MAX_BODY_SIZE");
+        let overrides = args.unwrap().overrides;
+        assert_eq!(overrides.shutdown_timeout_secs, Some(20));
+        assert_eq!(overrides.max_body_size, Some(2048));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let path = config_file("invalid", "min_connections = 5\nmax_connections = 2\n");
+        let err = ServerConfig::load(Some(&path), ConfigOverrides::default()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "min_connections must not exceed max_connections"
+        );
+
+        let err = ServerConfig::load(
+            Some(Path::new("/nonexistent/coffee.toml")),
+            ConfigOverrides::default(),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().starts_with("failed to read config file"),
+            "{err}"
+        );
+
+        let mut config = ServerConfig::default();
+        config.rate_limits.methods.insert(
+            "GrabCoffees".into(),
+            RateLimit {
+                burst: 1,
+                per_second: 1.0,
+                daily_quota: None,
+            },
+        );
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rate_limits.methods.GrabCoffees names no method"
+        );
+
+        config.rate_limits.methods.clear();
+        config.rate_limits.default = Some(RateLimit {
+            burst: 1,
+            per_second: 0.0,
+            daily_quota: None,
+        });
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "invalid rate_limits.default: per_second must be positive"
+        );
+    }
+}
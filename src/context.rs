@@ -0,0 +1,97 @@
+//! Who a request comes from, available anywhere down the call stack of a handler
+//! without threading it through every service method.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
+
+use crate::{auth::Principal, http::HttpRequest};
+
+tokio::task_local! {
+    static CURRENT: RequestContext;
+}
+
+/// Actor recorded for work that isn't done on behalf of a request.
+pub const SYSTEM_ACTOR: &str = "system";
+
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub peer: SocketAddr,
+    /// The caller as named by the `X-Actor` header, self reported.
+    pub actor: Option<String>,
+    /// The API key of an `Authorization: Bearer` header, not yet checked.
+    pub api_key: Option<String>,
+    /// Who the API key belongs to, once the server has checked it. Shared by the
+    /// clones handed out by [`RequestContext::current`].
+    principal: Arc<OnceLock<Principal>>,
+}
+
+impl RequestContext {
+    pub fn new(peer: SocketAddr, req: &HttpRequest) -> Self {
+        let actor = req
+            .headers
+            .get("x-actor")
+            .map(str::trim)
+            .filter(|actor| !actor.is_empty())
+            .map(String::from);
+        let api_key = req
+            .headers
+            .get("authorization")
+            .and_then(|value| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, key)| key.trim().to_string());
+        Self {
+            peer,
+            actor,
+            api_key,
+            principal: Arc::default(),
+        }
+    }
+
+    /// The context of the request being handled, `None` outside of one.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Runs `fut` with `self` as the current context.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        CURRENT.scope(self, fut).await
+    }
+
+    /// Records who the request's API key belongs to. Only the first call counts.
+    pub fn authenticate(&self, principal: Principal) {
+        _ = self.principal.set(principal);
+    }
+
+    pub fn principal(&self) -> Option<&Principal> {
+        self.principal.get()
+    }
+
+    /// Who rate limits count the request against: its API key, or else the peer's
+    /// address.
+    pub fn client(&self) -> String {
+        match self.principal() {
+            Some(principal) => format!("key:{}", principal.key_id),
+            None => format!("ip:{}", self.peer.ip()),
+        }
+    }
+
+    /// Name changes made by the request are attributed to: the name of its API key,
+    /// or else what it said it was, or else the peer's address.
+    pub fn actor(&self) -> String {
+        if let Some(principal) = self.principal() {
+            return principal.name.clone();
+        }
+        match &self.actor {
+            Some(actor) => actor.clone(),
+            None => self.peer.ip().to_string(),
+        }
+    }
+}
+
+/// The actor of the current request, [`SYSTEM_ACTOR`] outside of one.
+pub fn current_actor() -> String {
+    RequestContext::current().map_or_else(|| SYSTEM_ACTOR.to_string(), |ctx| ctx.actor())
+}
@@ -1,9 +1,22 @@
-use std::ops::Deref;
+use std::{ops::Deref, str::FromStr};
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, SqliteConnection};
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    query, query_scalar,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    FromRow, SqliteConnection, SqlitePool,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{
+    config::ServerConfig,
+    error::{AppError, AppResult},
+    money::{Currency, Money},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(transparent)]
 pub struct Nanoid(String);
 
 impl Deref for Nanoid {
@@ -26,34 +39,530 @@ impl Default for Nanoid {
     }
 }
 
+impl From<&str> for Nanoid {
+    fn from(s: &str) -> Self {
+        Nanoid(s.to_string())
+    }
+}
+
+impl From<String> for Nanoid {
+    fn from(s: String) -> Self {
+        Nanoid(s)
+    }
+}
+
 impl From<Option<String>> for Nanoid {
     fn from(opt: Option<String>) -> Self {
-        opt.map_or_else(Nanoid::new, |s| Nanoid(s))
+        opt.map_or_else(Nanoid::new, Nanoid)
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coffee {
     pub id: Nanoid,
+    pub roastery_id: Nanoid,
     pub roastery: String,
     pub icon: String,
+    pub farmer_id: Nanoid,
     pub farmer: String,
-    pub price: i64,
+    pub origin_id: Nanoid,
     pub origin: String,
+    pub price: Money,
+    /// `price` in the currency a listing asked for with `convert_to`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub converted_price: Option<Money>,
+    /// When the coffee was added, in seconds since the Unix epoch.
+    pub created_at: i64,
+    /// Bumped by every write, see [`crate::api::EditCoffee::expected_version`].
+    pub version: i64,
+    /// When the coffee was moved to the trash, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
 }
 
-pub async fn initialize_db(db: &mut SqliteConnection) -> anyhow::Result<()> {
-    query!(
-        "CREATE TABLE IF NOT EXISTS coffees (
-            id TEXT PRIMARY KEY UNIQUE,
-            roastery TEXT NOT NULL,
-            icon TEXT NOT NULL,
-            farmer TEXT NOT NULL,
-            price INTEGER NOT NULL,
-            origin TEXT NOT NULL
-        )"
-    )
-    .execute(db)
-    .await?;
+/// A row of the `coffee_view` view, which stores the price as two columns. Queries
+/// read into it and hand out the [`Coffee`] it converts to.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct CoffeeRow {
+    pub id: Nanoid,
+    pub roastery_id: Nanoid,
+    pub roastery: String,
+    pub icon: String,
+    pub farmer_id: Nanoid,
+    pub farmer: String,
+    pub origin_id: Nanoid,
+    pub origin: String,
+    pub price_minor: i64,
+    pub price_currency: String,
+    pub created_at: i64,
+    pub version: i64,
+    pub deleted_at: Option<i64>,
+}
+
+impl From<CoffeeRow> for Coffee {
+    fn from(row: CoffeeRow) -> Self {
+        Coffee {
+            id: row.id,
+            roastery_id: row.roastery_id,
+            roastery: row.roastery,
+            icon: row.icon,
+            farmer_id: row.farmer_id,
+            farmer: row.farmer,
+            origin_id: row.origin_id,
+            origin: row.origin,
+            price: Money {
+                amount_minor: row.price_minor,
+                currency: Currency::from_db(row.price_currency),
+            },
+            converted_price: None,
+            created_at: row.created_at,
+            version: row.version,
+            deleted_at: row.deleted_at,
+        }
+    }
+}
+
+impl<'r> FromRow<'r, SqliteRow> for Coffee {
+    fn from_row(row: &'r SqliteRow) -> sqlx::Result<Self> {
+        CoffeeRow::from_row(row).map(Coffee::from)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Roastery {
+    pub id: Nanoid,
+    pub name: String,
+    pub website: Option<String>,
+    pub contact: Option<String>,
+    /// When the roastery was added, in seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Farmer {
+    pub id: Nanoid,
+    pub name: String,
+    pub region: Option<String>,
+    pub contact: Option<String>,
+    /// When the farmer was added, in seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// Where a coffee is grown, named in full, e.g. "El Mirador, Huila, Colombia".
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Origin {
+    pub id: Nanoid,
+    pub name: String,
+    pub region: Option<String>,
+    /// One of [`crate::validation::KNOWN_COUNTRIES`], or empty for an origin
+    /// migrated from free text that named none.
+    pub country: String,
+    /// When the origin was added, in seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// An API key as listed, its secret never leaves the moment it's issued.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ApiKey {
+    pub id: Nanoid,
+    pub name: String,
+    pub role: String,
+    /// The first characters of the key.
+    pub prefix: String,
+    /// When the key was issued, in seconds since the Unix epoch.
+    pub created_at: i64,
+    /// When the key was last replaced, in seconds since the Unix epoch.
+    pub rotated_at: Option<i64>,
+    /// When the key stopped working, in seconds since the Unix epoch.
+    pub revoked_at: Option<i64>,
+}
+
+/// A key just created or rotated, the only time `key` itself is shown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// A role and the permissions it grants, see `Methods::PERMISSIONS`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+/// One write to a coffee, as recorded in its history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoffeeChange {
+    pub seq: i64,
+    pub coffee_id: Nanoid,
+    /// The version the change left the coffee at, for a delete the one it had.
+    pub version: i64,
+    pub action: ChangeAction,
+    /// The coffee before the change, `None` for inserts.
+    pub before: Option<Coffee>,
+    /// The coffee after the change, `None` for deletes.
+    pub after: Option<Coffee>,
+    pub actor: String,
+    /// When the change was made, in seconds since the Unix epoch.
+    pub changed_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Position in a keyset paginated listing: the sort key and id of the last row of a
+/// page. Clients only ever see it as opaque URL-safe base64.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The order the listing was in, so a cursor isn't replayed against another one.
+    pub sort: String,
+    pub key: CursorKey,
+    pub id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CursorKey {
+    Int(i64),
+    Text(String),
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(cursor: &str) -> AppResult<Self> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| AppError::BadRequest("invalid cursor".into()))
+    }
+}
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    /// When the migration ran, `None` while it is pending.
+    pub installed_on: Option<String>,
+}
+
+/// Opens the configured database, creating it if needed, and runs pending migrations.
+pub async fn connect(config: &ServerConfig) -> anyhow::Result<SqlitePool> {
+    let pool = open(config).await?;
+    let mut conn = pool.acquire().await?;
+    migrate(&mut conn).await?;
+    Ok(pool)
+}
+
+/// Opens the configured database without touching its schema.
+pub async fn open(config: &ServerConfig) -> anyhow::Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(&config.database_url)?.create_if_missing(true);
+    let pool = SqlitePoolOptions::new()
+        .min_connections(config.min_connections)
+        .max_connections(config.max_connections)
+        .connect_with(options)
+        .await?;
+    Ok(pool)
+}
+
+/// Brings the schema up to date by running every pending migration in `migrations/`.
+pub async fn migrate(db: &mut SqliteConnection) -> anyhow::Result<()> {
+    baseline_legacy_schema(db).await?;
+    MIGRATOR.run(&mut *db).await?;
+    Ok(())
+}
+
+/// Databases created by the old `CREATE TABLE IF NOT EXISTS` startup code have a
+/// `coffees` table but no migration history. Record the migrations their schema
+/// already reflects as applied, so the migrator doesn't try to re-run them.
+async fn baseline_legacy_schema(db: &mut SqliteConnection) -> anyhow::Result<()> {
+    let tracked =
+        query!("SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'")
+            .fetch_optional(&mut *db)
+            .await?
+            .is_some();
+    if tracked {
+        return Ok(());
+    }
+    // Table valued pragmas can't be described at compile time, hence the runtime query.
+    let columns: Vec<String> = query_scalar("SELECT name FROM pragma_table_info('coffees')")
+        .fetch_all(&mut *db)
+        .await?;
+    if columns.is_empty() {
+        return Ok(());
+    }
+    let has = |column: &str| columns.iter().any(|c| c == column);
+    let applied = [
+        (20241018000000, true),
+        (20241018180128, has("icon")),
+        (20241019101408, has("roastery") && !has("name")),
+    ];
+
+    db.ensure_migrations_table().await?;
+    for migration in MIGRATOR.iter() {
+        let baselined = applied
+            .iter()
+            .any(|&(version, applied)| applied && version == migration.version);
+        if !baselined {
+            continue;
+        }
+        tracing::info!(
+            "🌱 Baselining migration {} {}",
+            migration.version,
+            migration.description
+        );
+        let checksum = migration.checksum.as_ref();
+        let description = migration.description.as_ref();
+        query!(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (?, ?, TRUE, ?, 0)",
+            migration.version,
+            description,
+            checksum
+        )
+        .execute(&mut *db)
+        .await?;
+    }
     Ok(())
 }
+
+/// Lists every known migration along with whether it was applied to `db`.
+pub async fn migration_status(db: &mut SqliteConnection) -> anyhow::Result<Vec<MigrationStatus>> {
+    let tracked =
+        query!("SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'")
+            .fetch_optional(&mut *db)
+            .await?
+            .is_some();
+    let applied = if tracked {
+        query!(
+            r#"SELECT version AS "version!", installed_on AS "installed_on: String"
+            FROM _sqlx_migrations WHERE success = TRUE"#
+        )
+        .fetch_all(&mut *db)
+        .await?
+        .into_iter()
+        .map(|row| (row.version, row.installed_on))
+        .collect()
+    } else {
+        Vec::new()
+    };
+    let status = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            installed_on: applied
+                .iter()
+                .find(|(version, _)| *version == migration.version)
+                .map(|(_, installed_on)| installed_on.clone()),
+        })
+        .collect();
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single connection in-memory database, fresh for every pool.
+    fn memory_config() -> ServerConfig {
+        ServerConfig {
+            database_url: "sqlite::memory:".into(),
+            min_connections: 1,
+            max_connections: 1,
+            ..ServerConfig::default()
+        }
+    }
+
+    /// A database as the old startup code created it, with no migration history.
+    async fn legacy_db(coffees: &[(&str, &str, &str)]) -> SqlitePool {
+        let db = open(&memory_config()).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE coffees (
+                id TEXT PRIMARY KEY UNIQUE,
+                farmer TEXT NOT NULL,
+                price INTEGER NOT NULL,
+                origin TEXT NOT NULL,
+                icon TEXT NOT NULL,
+                roastery TEXT NOT NULL
+            )",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        for (i, (roastery, farmer, origin)) in coffees.iter().enumerate() {
+            sqlx::query("INSERT INTO coffees VALUES (?, ?, 18, ?, '☕', ?)")
+                .bind(i.to_string())
+                .bind(farmer)
+                .bind(origin)
+                .bind(roastery)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        db
+    }
+
+    #[tokio::test]
+    async fn fresh_databases_run_every_migration() {
+        let db = open(&memory_config()).await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        let pending = migration_status(&mut conn).await.unwrap();
+        assert_eq!(pending.len(), MIGRATOR.iter().count());
+        assert!(pending
+            .iter()
+            .all(|migration| migration.installed_on.is_none()));
+        assert!(pending
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+
+        migrate(&mut conn).await.unwrap();
+        let status = migration_status(&mut conn).await.unwrap();
+        assert!(status
+            .iter()
+            .all(|migration| migration.installed_on.is_some()));
+        let coffees = query_scalar!("SELECT COUNT(*) FROM coffees")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(coffees, 0);
+    }
+
+    #[tokio::test]
+    async fn legacy_schemas_are_baselined_up_to_what_they_have() {
+        let db = open(&memory_config()).await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        // The very first schema, from before coffees had an icon.
+        sqlx::query(
+            "CREATE TABLE coffees (
+                id TEXT PRIMARY KEY UNIQUE,
+                name TEXT NOT NULL,
+                farmer TEXT NOT NULL,
+                price INTEGER NOT NULL,
+                origin TEXT NOT NULL
+            )",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        baseline_legacy_schema(&mut conn).await.unwrap();
+        let applied = migration_status(&mut conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|migration| migration.installed_on.is_some())
+            .map(|migration| migration.version)
+            .collect::<Vec<_>>();
+        assert_eq!(applied, [20241018000000]);
+
+        migrate(&mut conn).await.unwrap();
+        let columns: Vec<String> = query_scalar("SELECT name FROM pragma_table_info('coffees')")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        assert!(columns.iter().any(|column| column == "icon"));
+        assert!(!columns.iter().any(|column| column == "name"));
+    }
+
+    #[tokio::test]
+    async fn legacy_databases_are_baselined_and_migrated() {
+        let db = legacy_db(&[("Onyx", "Tadesse", "Guji, Ethiopia")]).await;
+        migrate(&mut db.acquire().await.unwrap()).await.unwrap();
+        let status = migration_status(&mut db.acquire().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(status.len(), MIGRATOR.iter().count());
+        assert!(status
+            .iter()
+            .all(|migration| migration.installed_on.is_some()));
+        let price = query_scalar!("SELECT price_minor FROM coffees WHERE id = '0'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(price, 1800);
+        // Running them again is a no-op.
+        migrate(&mut db.acquire().await.unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn free_text_names_become_entities() {
+        let db = legacy_db(&[
+            ("Onyx", "Tadesse", "Guji, ethiopia "),
+            (" onyx", "tadesse", "Finca El Paraiso, Huila, Colombia"),
+            ("Onyx Coffee Lab", "Tadesse", "Sidama"),
+            ("Miga", "Ana", "Kainantu Papua New Guinea"),
+        ])
+        .await;
+        migrate(&mut db.acquire().await.unwrap()).await.unwrap();
+        let roasteries = query_scalar!("SELECT name FROM roasteries ORDER BY name")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        // Case and spacing variants are merged, other spellings are left to
+        // MergeRoasteries.
+        assert_eq!(roasteries, ["Miga", "Onyx", "Onyx Coffee Lab"]);
+        let farmers = query_scalar!("SELECT COUNT(*) FROM farmers")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(farmers, 2);
+
+        let origins = query!("SELECT name, country FROM origins ORDER BY name")
+            .fetch_all(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|origin| (origin.name, origin.country))
+            .collect::<Vec<_>>();
+        let expected = [
+            ("Finca El Paraiso, Huila, Colombia", "Colombia"),
+            ("Guji, ethiopia", "Ethiopia"),
+            ("Kainantu Papua New Guinea", "Papua New Guinea"),
+            ("Sidama", ""),
+        ];
+        assert_eq!(
+            origins,
+            expected.map(|(name, country)| (name.to_string(), country.to_string()))
+        );
+    }
+
+    #[test]
+    fn cursors_round_trip_as_opaque_text() {
+        let cursors = [
+            Cursor {
+                sort: "price_minor DESC".into(),
+                key: CursorKey::Int(1850),
+                id: "V1StGXR8_Z5jdHi6B-myT".into(),
+            },
+            Cursor {
+                sort: "roastery ASC".into(),
+                key: CursorKey::Text("Onyx Coffee Lab".into()),
+                id: "x".into(),
+            },
+        ];
+        for cursor in cursors {
+            let encoded = cursor.encode();
+            assert!(encoded
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+            assert_eq!(Cursor::decode(&encoded).unwrap(), cursor);
+        }
+        for invalid in ["", "not base64!", &URL_SAFE_NO_PAD.encode("{}")] {
+            let err = Cursor::decode(invalid).unwrap_err();
+            assert_eq!(err.code(), crate::error::ErrorCode::BadRequest);
+        }
+    }
+}
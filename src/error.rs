@@ -0,0 +1,355 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ratelimit::RateLimited,
+    service::{IntoResponse, Response},
+};
+
+/// Machine readable error codes, stable across releases so clients can branch on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    ValidationFailed,
+    TooManyRequests,
+    Timeout,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "400 Bad Request",
+            ErrorCode::Unauthorized => "401 Unauthorized",
+            ErrorCode::Forbidden => "403 Forbidden",
+            ErrorCode::NotFound => "404 Not Found",
+            ErrorCode::MethodNotAllowed => "405 Method Not Allowed",
+            ErrorCode::Conflict => "409 Conflict",
+            ErrorCode::PayloadTooLarge => "413 Payload Too Large",
+            ErrorCode::ValidationFailed => "422 Unprocessable Entity",
+            ErrorCode::TooManyRequests => "429 Too Many Requests",
+            ErrorCode::Timeout => "503 Service Unavailable",
+            ErrorCode::Internal => "500 Internal Server Error",
+        }
+    }
+}
+
+/// The JSON error object sent for every failed request, wrapped as
+/// `{ "error": { "code", "message", "details" } }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Renders the error with an explicit status, for HTTP level failures whose
+    /// status is more specific than the code's.
+    pub fn with_status(self, status: &'static str) -> Response {
+        let body = serde_json::to_string(&ErrorEnvelope { error: self }).unwrap();
+        Response::new(status, body)
+    }
+}
+
+impl IntoResponse for ErrorBody {
+    fn into_response(self) -> Response {
+        let code = self.code;
+        let limited = match (code, &self.details) {
+            (ErrorCode::TooManyRequests, Some(details)) => {
+                serde_json::from_value::<RateLimited>(details.clone()).ok()
+            }
+            _ => None,
+        };
+        let mut res = self.with_status(code.status());
+        if code == ErrorCode::Unauthorized {
+            res = res.with_header("WWW-Authenticate", "Bearer");
+        }
+        for (name, value) in limited.iter().flat_map(RateLimited::headers) {
+            res = res.with_header(name, value);
+        }
+        res
+    }
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    /// Missing or invalid credentials.
+    Unauthorized(String),
+    /// Valid credentials that don't allow the request.
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Unprocessable(String),
+    /// Over a rate limit, with [`RateLimited`] details.
+    TooManyRequests(String),
+    Timeout,
+    Internal(anyhow::Error),
+    /// Any of the above with structured details for the client, see
+    /// [`AppError::with_details`].
+    Detailed {
+        error: Box<AppError>,
+        details: serde_json::Value,
+    },
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::BadRequest(_) => ErrorCode::BadRequest,
+            AppError::Unauthorized(_) => ErrorCode::Unauthorized,
+            AppError::Forbidden(_) => ErrorCode::Forbidden,
+            AppError::NotFound(_) => ErrorCode::NotFound,
+            AppError::Conflict(_) => ErrorCode::Conflict,
+            AppError::Unprocessable(_) => ErrorCode::ValidationFailed,
+            AppError::TooManyRequests(_) => ErrorCode::TooManyRequests,
+            AppError::Timeout => ErrorCode::Timeout,
+            AppError::Internal(_) => ErrorCode::Internal,
+            AppError::Detailed { error, .. } => error.code(),
+        }
+    }
+
+    /// Attaches `details` to the error body, e.g. the current state of a resource a
+    /// write conflicted with.
+    pub fn with_details(self, details: impl Serialize) -> Self {
+        match serde_json::to_value(details) {
+            Ok(details) => AppError::Detailed {
+                error: Box::new(self),
+                details,
+            },
+            Err(err) => {
+                tracing::error!("Failed to serialize error details: {err}");
+                self
+            }
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        if let AppError::Detailed { error, details } = self {
+            return ErrorBody {
+                details: Some(details.clone()),
+                ..error.body()
+            };
+        }
+        let message = match self {
+            // Internal failures are logged, not leaked to the client.
+            AppError::Internal(_) => "internal server error".to_string(),
+            err => err.to_string(),
+        };
+        ErrorBody::new(self.code(), message)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::Unprocessable(msg)
+            | AppError::TooManyRequests(msg) => f.write_str(msg),
+            AppError::Timeout => f.write_str("request timed out"),
+            AppError::Internal(err) => write!(f, "{err:#}"),
+            AppError::Detailed { error, .. } => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if self.code() == ErrorCode::Internal {
+            tracing::error!("Internal error: {self}");
+        }
+        self.body().into_response()
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => AppError::NotFound("resource not found".into()),
+            // SQLite's message names tables and columns, which clients have no
+            // business knowing about.
+            sqlx::Error::Database(err) if err.is_unique_violation() => {
+                tracing::debug!("Unique violation: {}", err.message());
+                AppError::Conflict("conflicts with an existing record".into())
+            }
+            sqlx::Error::Database(err)
+                if err.is_check_violation() || err.is_foreign_key_violation() =>
+            {
+                AppError::Unprocessable(err.message().to_string())
+            }
+            err => AppError::Internal(err.into()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(err: serde_json::Error) -> Self {
+        match err.classify() {
+            serde_json::error::Category::Data => AppError::Unprocessable(err.to_string()),
+            _ => AppError::BadRequest(err.to_string()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Internal(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(res: &Response) -> ErrorBody {
+        serde_json::from_str::<ErrorEnvelope>(&res.body)
+            .unwrap()
+            .error
+    }
+
+    fn header<'a>(res: &'a Response, name: &str) -> Option<&'a str> {
+        res.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn errors_map_to_their_status() {
+        let cases = [
+            (
+                AppError::BadRequest("bad".into()),
+                "400 Bad Request",
+                "bad_request",
+            ),
+            (
+                AppError::Forbidden("no".into()),
+                "403 Forbidden",
+                "forbidden",
+            ),
+            (
+                AppError::NotFound("gone".into()),
+                "404 Not Found",
+                "not_found",
+            ),
+            (
+                AppError::Conflict("stale".into()),
+                "409 Conflict",
+                "conflict",
+            ),
+            (
+                AppError::Unprocessable("invalid".into()),
+                "422 Unprocessable Entity",
+                "validation_failed",
+            ),
+            (AppError::Timeout, "503 Service Unavailable", "timeout"),
+        ];
+        for (err, status, code) in cases {
+            let message = err.to_string();
+            let res = err.into_response();
+            assert_eq!(res.status, status);
+            let value: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+            assert_eq!(
+                value,
+                serde_json::json!({ "error": { "code": code, "message": message } })
+            );
+        }
+    }
+
+    #[test]
+    fn unauthorized_asks_for_a_bearer_token() {
+        let res = AppError::Unauthorized("missing API key".into()).into_response();
+        assert_eq!(res.status, "401 Unauthorized");
+        assert_eq!(header(&res, "WWW-Authenticate"), Some("Bearer"));
+        assert_eq!(envelope(&res).message, "missing API key");
+    }
+
+    #[test]
+    fn internal_errors_are_not_leaked() {
+        let err = AppError::from(anyhow::anyhow!("disk /var/db is full"));
+        let res = err.into_response();
+        assert_eq!(res.status, "500 Internal Server Error");
+        let body = envelope(&res);
+        assert_eq!(body.code, ErrorCode::Internal);
+        assert_eq!(body.message, "internal server error");
+    }
+
+    #[test]
+    fn details_are_kept_next_to_the_original_error() {
+        let err = AppError::Conflict("coffee was edited".into())
+            .with_details(serde_json::json!({ "version": 3 }));
+        assert_eq!(err.code(), ErrorCode::Conflict);
+        assert_eq!(err.to_string(), "coffee was edited");
+        let res = err.into_response();
+        assert_eq!(res.status, "409 Conflict");
+        let body = envelope(&res);
+        assert_eq!(body.message, "coffee was edited");
+        assert_eq!(body.details, Some(serde_json::json!({ "version": 3 })));
+    }
+
+    #[test]
+    fn rate_limits_are_sent_as_headers() {
+        let limited = RateLimited {
+            limit: 10,
+            reset: 60,
+            retry_after: 6,
+        };
+        let res = AppError::TooManyRequests("slow down".into())
+            .with_details(limited)
+            .into_response();
+        assert_eq!(res.status, "429 Too Many Requests");
+        assert_eq!(header(&res, "Retry-After"), Some("6"));
+        assert_eq!(header(&res, "RateLimit-Limit"), Some("10"));
+        assert_eq!(header(&res, "RateLimit-Remaining"), Some("0"));
+        assert_eq!(header(&res, "RateLimit-Reset"), Some("60"));
+
+        // Other errors never send rate limit headers, whatever their details.
+        let res = AppError::Conflict("stale".into())
+            .with_details(limited)
+            .into_response();
+        assert_eq!(header(&res, "Retry-After"), None);
+    }
+
+    #[test]
+    fn library_errors_are_classified() {
+        assert_eq!(
+            AppError::from(sqlx::Error::RowNotFound).code(),
+            ErrorCode::NotFound
+        );
+        let syntax = serde_json::from_str::<u32>("1 2").unwrap_err();
+        assert_eq!(AppError::from(syntax).code(), ErrorCode::BadRequest);
+        let data = serde_json::from_str::<u32>("\"one\"").unwrap_err();
+        assert_eq!(AppError::from(data).code(), ErrorCode::ValidationFailed);
+    }
+}
@@ -0,0 +1,325 @@
+//! Change notifications for the catalog, streamed to clients as Server-Sent Events.
+//!
+//! Published events are kept in a bounded in-memory buffer so a client that
+//! reconnects with `Last-Event-ID` gets whatever it missed. Event ids are
+//! `{epoch}-{seq}`, the epoch changing with every server start, so an id from
+//! before a restart is recognised as unresumable rather than silently skipping
+//! events.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::db::Coffee;
+
+/// Number of events kept for `Last-Event-ID` resume.
+pub const EVENT_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoffeeEventKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+}
+
+impl CoffeeEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CoffeeEventKind::Created => "created",
+            CoffeeEventKind::Updated => "updated",
+            CoffeeEventKind::Deleted => "deleted",
+            CoffeeEventKind::Restored => "restored",
+            CoffeeEventKind::Purged => "purged",
+        }
+    }
+
+    /// Whether the event is about a coffee in the trash, which only those allowed
+    /// to list the trash get to see.
+    pub fn is_trash(self) -> bool {
+        matches!(self, CoffeeEventKind::Deleted | CoffeeEventKind::Purged)
+    }
+}
+
+/// A change to one coffee, carrying its state right after the change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoffeeEvent {
+    pub id: String,
+    pub kind: CoffeeEventKind,
+    pub coffee: Coffee,
+}
+
+impl CoffeeEvent {
+    /// Formats the event as an SSE message, named after its kind.
+    pub fn to_sse(&self) -> String {
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id,
+            self.kind.as_str(),
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+/// Sent instead of the missed events when they can't be replayed, telling the
+/// client to refetch whatever it shows.
+const RESYNC: &str = "event: resync\ndata: {}\n\n";
+
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    epoch: u64,
+    sender: broadcast::Sender<CoffeeEvent>,
+    log: Mutex<Log>,
+}
+
+struct Log {
+    next_seq: u64,
+    events: VecDeque<(u64, CoffeeEvent)>,
+    capacity: usize,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(EVENT_BUFFER_SIZE)
+    }
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Self {
+            inner: Arc::new(Inner {
+                epoch,
+                sender: broadcast::channel(capacity.max(1)).0,
+                log: Mutex::new(Log {
+                    next_seq: 1,
+                    events: VecDeque::with_capacity(capacity),
+                    capacity,
+                }),
+            }),
+        }
+    }
+
+    /// Publishes a change. Call it once the change is committed.
+    pub fn publish(&self, kind: CoffeeEventKind, coffee: Coffee) {
+        let mut log = self.inner.log.lock().unwrap();
+        let seq = log.next_seq;
+        log.next_seq += 1;
+        let event = CoffeeEvent {
+            id: format!("{}-{seq}", self.inner.epoch),
+            kind,
+            coffee,
+        };
+        if log.events.len() == log.capacity {
+            log.events.pop_front();
+        }
+        if log.capacity > 0 {
+            log.events.push_back((seq, event.clone()));
+        }
+        // Sent under the lock, so a concurrent subscribe sees every event exactly
+        // once, either replayed from the log or live. No subscribers is fine.
+        let _ = self.inner.sender.send(event);
+    }
+
+    /// Subscribes to the events `include` accepts as an SSE stream, first replaying
+    /// those published after `last_event_id` when it is given.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<&str>,
+        include: impl Fn(&CoffeeEvent) -> bool + Send + 'static,
+    ) -> impl Stream<Item = String> + Send {
+        let log = self.inner.log.lock().unwrap();
+        let receiver = self.inner.sender.subscribe();
+        let mut replay = VecDeque::new();
+        if let Some(last_event_id) = last_event_id {
+            match self.resume_point(&log, last_event_id) {
+                Some(seq) => replay.extend(
+                    log.events
+                        .iter()
+                        .filter(|(event_seq, event)| *event_seq > seq && include(event))
+                        .map(|(_, event)| event.to_sse()),
+                ),
+                None => replay.push_back(RESYNC.to_string()),
+            }
+        }
+        drop(log);
+
+        stream::unfold(
+            (replay, receiver, include),
+            |(mut replay, mut receiver, include)| async move {
+                if let Some(message) = replay.pop_front() {
+                    return Some((message, (replay, receiver, include)));
+                }
+                let message = loop {
+                    match receiver.recv().await {
+                        Ok(event) if include(&event) => break event.to_sse(),
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!("Event subscriber lagged, skipped {missed} events");
+                            break RESYNC.to_string();
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                };
+                Some((message, (replay, receiver, include)))
+            },
+        )
+    }
+
+    /// The sequence number to resume after, `None` if events since `last_event_id`
+    /// are no longer buffered or it is from another server run.
+    fn resume_point(&self, log: &Log, last_event_id: &str) -> Option<u64> {
+        let (epoch, seq) = last_event_id.trim().split_once('-')?;
+        let (epoch, seq) = (epoch.parse::<u64>().ok()?, seq.parse::<u64>().ok()?);
+        if epoch != self.inner.epoch || seq >= log.next_seq {
+            return None;
+        }
+        let oldest = log.events.front().map_or(log.next_seq, |(seq, _)| *seq);
+        // Resuming is fine as long as nothing after `seq` was evicted.
+        (seq + 1 >= oldest).then_some(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, StreamExt};
+    use serde_json::json;
+
+    use super::*;
+
+    fn coffee(version: i64) -> Coffee {
+        serde_json::from_value(json!({
+            "id": "c1",
+            "roastery_id": "r1",
+            "roastery": "Onyx",
+            "icon": "☕",
+            "farmer_id": "f1",
+            "farmer": "Tadesse",
+            "origin_id": "o1",
+            "origin": "Guji",
+            "price": { "amount_minor": 1850, "currency": "EUR" },
+            "created_at": 1_790_000_000,
+            "version": version,
+        }))
+        .unwrap()
+    }
+
+    /// Messages the stream has ready right now.
+    fn ready(stream: &mut (impl Stream<Item = String> + Unpin)) -> Vec<String> {
+        std::iter::from_fn(|| stream.next().now_or_never().flatten()).collect()
+    }
+
+    /// The id of each message, or its first line if it has none.
+    fn ids(messages: &[String]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| {
+                let line = message.lines().next().unwrap();
+                line.strip_prefix("id: ").unwrap_or(line)
+            })
+            .collect()
+    }
+
+    fn event_id(bus: &EventBus, seq: u64) -> String {
+        format!("{}-{seq}", bus.inner.epoch)
+    }
+
+    #[test]
+    fn events_are_sent_as_sse_messages() {
+        let bus = EventBus::new(8);
+        let mut stream = Box::pin(bus.subscribe(None, |_| true));
+        assert!(ready(&mut stream).is_empty());
+        bus.publish(CoffeeEventKind::Created, coffee(1));
+        let messages = ready(&mut stream);
+        assert_eq!(messages.len(), 1);
+        let (head, data) = messages[0].split_once("data: ").unwrap();
+        assert_eq!(head, format!("id: {}\nevent: created\n", event_id(&bus, 1)));
+        let event: CoffeeEvent = serde_json::from_str(data.trim_end()).unwrap();
+        assert_eq!(event.kind, CoffeeEventKind::Created);
+        assert_eq!(event.coffee.version, 1);
+        assert!(messages[0].ends_with("}\n\n"));
+    }
+
+    #[test]
+    fn subscribers_resume_after_the_last_event_they_saw() {
+        let bus = EventBus::new(8);
+        for version in 1..=3 {
+            bus.publish(CoffeeEventKind::Updated, coffee(version));
+        }
+        let last = event_id(&bus, 1);
+        let mut stream = Box::pin(bus.subscribe(Some(&last), |_| true));
+        bus.publish(CoffeeEventKind::Updated, coffee(4));
+        let messages = ready(&mut stream);
+        let expected = (2..=4).map(|seq| event_id(&bus, seq)).collect::<Vec<_>>();
+        assert_eq!(ids(&messages), expected);
+
+        // Caught up, so nothing is replayed.
+        let last = event_id(&bus, 4);
+        let mut stream = Box::pin(bus.subscribe(Some(&last), |_| true));
+        assert!(ready(&mut stream).is_empty());
+    }
+
+    #[test]
+    fn unresumable_ids_ask_for_a_resync() {
+        let bus = EventBus::new(2);
+        for version in 1..=4 {
+            bus.publish(CoffeeEventKind::Updated, coffee(version));
+        }
+        let other_run = format!("{}-2", bus.inner.epoch + 1);
+        let evicted = event_id(&bus, 1);
+        let future = event_id(&bus, 9);
+        for last in ["garbage", other_run.as_str(), &evicted, &future] {
+            let mut stream = Box::pin(bus.subscribe(Some(last), |_| true));
+            assert_eq!(ready(&mut stream), [RESYNC], "{last}");
+        }
+        // Only events 3 and 4 are buffered, which is all a client at 2 missed.
+        let last = event_id(&bus, 2);
+        let mut stream = Box::pin(bus.subscribe(Some(&last), |_| true));
+        assert_eq!(
+            ids(&ready(&mut stream)),
+            [event_id(&bus, 3), event_id(&bus, 4)]
+        );
+    }
+
+    #[test]
+    fn excluded_events_are_neither_replayed_nor_sent() {
+        let bus = EventBus::new(8);
+        bus.publish(CoffeeEventKind::Created, coffee(1));
+        bus.publish(CoffeeEventKind::Deleted, coffee(2));
+        let last = event_id(&bus, 0);
+        let mut stream = Box::pin(bus.subscribe(Some(&last), |event| !event.kind.is_trash()));
+        bus.publish(CoffeeEventKind::Purged, coffee(2));
+        bus.publish(CoffeeEventKind::Restored, coffee(3));
+        let kinds = ready(&mut stream)
+            .iter()
+            .map(|message| message.lines().nth(1).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["event: created", "event: restored"]);
+    }
+
+    #[test]
+    fn lagging_subscribers_are_told_to_resync() {
+        let bus = EventBus::new(2);
+        let mut stream = Box::pin(bus.subscribe(None, |_| true));
+        for version in 1..=5 {
+            bus.publish(CoffeeEventKind::Updated, coffee(version));
+        }
+        let messages = ready(&mut stream);
+        assert_eq!(messages[0], RESYNC);
+        assert_eq!(ids(&messages[1..]), [event_id(&bus, 4), event_id(&bus, 5)]);
+    }
+}
@@ -0,0 +1,628 @@
+use std::fmt;
+
+/// Upper bound for the request line plus headers.
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Default upper bound for a request body, chunked or not.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+    Other(String),
+}
+
+impl Method {
+    fn parse(s: &str) -> Self {
+        match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Returns every header with the given name, compared case-insensitively.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the first header with the given name.
+    pub fn get<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.get_all(name).next()
+    }
+
+    /// Checks whether a comma separated header such as `Connection` contains `token`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    }
+
+    fn push(&mut self, name: String, value: String) {
+        self.0.push((name, value));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Path parameters captured by the router.
+    pub params: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The decoded `name=value` pairs of the query string, in order.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        let Some(query) = &self.query else {
+            return Vec::new();
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |s: &str| percent_decode(&s.replace('+', " "));
+                Some((decode(name)?, decode(value)?))
+            })
+            .collect()
+    }
+
+    /// The request target as sent, path plus query string.
+    pub fn target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{query}", self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// HTTP/1.1 connections are persistent unless the client asks otherwise,
+    /// HTTP/1.0 ones only when the client explicitly opts in.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.headers.has_token("connection", "close"),
+            Version::Http10 => self.headers.has_token("connection", "keep-alive"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Malformed(&'static str),
+    HeadTooLarge,
+    BodyTooLarge,
+    UnsupportedVersion,
+    UnsupportedTransferEncoding,
+}
+
+impl ParseError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            ParseError::Malformed(_) => "400 Bad Request",
+            ParseError::HeadTooLarge => "431 Request Header Fields Too Large",
+            ParseError::BodyTooLarge => "413 Payload Too Large",
+            ParseError::UnsupportedVersion => "505 HTTP Version Not Supported",
+            ParseError::UnsupportedTransferEncoding => "501 Not Implemented",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Malformed(reason) => write!(f, "malformed request: {reason}"),
+            ParseError::HeadTooLarge => f.write_str("request head too large"),
+            ParseError::BodyTooLarge => f.write_str("request body too large"),
+            ParseError::UnsupportedVersion => f.write_str("unsupported HTTP version"),
+            ParseError::UnsupportedTransferEncoding => f.write_str("unsupported transfer encoding"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+enum BodyState {
+    Length(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkEnd,
+    Trailers,
+}
+
+#[derive(Debug)]
+struct Pending {
+    request: HttpRequest,
+    state: BodyState,
+}
+
+/// Incremental HTTP/1.1 request parser.
+///
+/// Bytes are pushed in with [`RequestParser::feed`] as they come off the socket and
+/// complete requests are pulled out with [`RequestParser::next_request`], so a request
+/// may span any number of reads and a single read may carry several pipelined requests.
+#[derive(Debug)]
+pub struct RequestParser {
+    buf: Vec<u8>,
+    pending: Option<Pending>,
+    max_body_size: usize,
+    expect_continue: bool,
+}
+
+impl Default for RequestParser {
+    fn default() -> Self {
+        Self::new(MAX_BODY_SIZE)
+    }
+}
+
+impl RequestParser {
+    pub fn new(max_body_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            pending: None,
+            max_body_size,
+            expect_continue: false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Hands over whatever was received past the last parsed request, for
+    /// connections that switch protocols.
+    pub fn into_buffered(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Whether bytes of an unfinished request are buffered.
+    pub fn is_mid_request(&self) -> bool {
+        self.pending.is_some() || !self.buf.is_empty()
+    }
+
+    /// Returns `true` once per request that sent `Expect: 100-continue` and is
+    /// still waiting on its body.
+    pub fn take_expect_continue(&mut self) -> bool {
+        std::mem::take(&mut self.expect_continue)
+    }
+
+    /// Pops the next complete request off the buffer, or `None` if more bytes are needed.
+    pub fn next_request(&mut self) -> Result<Option<HttpRequest>, ParseError> {
+        if self.pending.is_none() {
+            let Some(pending) = self.parse_head()? else {
+                return Ok(None);
+            };
+            self.pending = Some(pending);
+        }
+        self.parse_body()
+    }
+
+    fn parse_head(&mut self) -> Result<Option<Pending>, ParseError> {
+        // Robustness: ignore empty lines preceding the request line (RFC 9112 §2.2).
+        while self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+        }
+        let Some(end) = find(&self.buf, b"\r\n\r\n") else {
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(ParseError::HeadTooLarge);
+            }
+            return Ok(None);
+        };
+        if end > MAX_HEAD_SIZE {
+            return Err(ParseError::HeadTooLarge);
+        }
+        let head = self.buf.drain(..end + 4).collect::<Vec<_>>();
+        let head = std::str::from_utf8(&head[..end])
+            .map_err(|_| ParseError::Malformed("request head is not valid UTF-8"))?;
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::Malformed("invalid request line"));
+        };
+        if method.is_empty() || !method.bytes().all(is_token_byte) {
+            return Err(ParseError::Malformed("invalid method"));
+        }
+        if target.is_empty() {
+            return Err(ParseError::Malformed("empty request target"));
+        }
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::Malformed("invalid HTTP version")),
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+
+        let mut headers = Headers::default();
+        for line in lines {
+            if line.starts_with([' ', '\t']) {
+                return Err(ParseError::Malformed("obsolete header line folding"));
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(ParseError::Malformed("invalid header line"));
+            };
+            if name.is_empty() || !name.bytes().all(is_token_byte) {
+                return Err(ParseError::Malformed("invalid header name"));
+            }
+            headers.push(name.to_string(), value.trim().to_string());
+        }
+        let state = body_state(&headers)?;
+        if let BodyState::Length(len) = state {
+            if len > self.max_body_size {
+                return Err(ParseError::BodyTooLarge);
+            }
+        }
+        let has_body = !matches!(state, BodyState::Length(0));
+        self.expect_continue =
+            has_body && version == Version::Http11 && headers.has_token("expect", "100-continue");
+
+        let request = HttpRequest {
+            method: Method::parse(method),
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            params: Vec::new(),
+        };
+        Ok(Some(Pending { request, state }))
+    }
+
+    fn parse_body(&mut self) -> Result<Option<HttpRequest>, ParseError> {
+        let Some(pending) = self.pending.as_mut() else {
+            return Ok(None);
+        };
+        loop {
+            match pending.state {
+                BodyState::Length(len) => {
+                    if self.buf.len() < len {
+                        return Ok(None);
+                    }
+                    pending.request.body = self.buf.drain(..len).collect();
+                    break;
+                }
+                BodyState::ChunkSize => {
+                    let Some(end) = find(&self.buf, b"\r\n") else {
+                        if self.buf.len() > MAX_HEAD_SIZE {
+                            return Err(ParseError::Malformed("chunk size line too long"));
+                        }
+                        return Ok(None);
+                    };
+                    let line = std::str::from_utf8(&self.buf[..end])
+                        .map_err(|_| ParseError::Malformed("invalid chunk size"))?;
+                    // Chunk extensions are allowed but carry nothing we care about.
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| ParseError::Malformed("invalid chunk size"))?;
+                    self.buf.drain(..end + 2);
+                    if pending.request.body.len().saturating_add(size) > self.max_body_size {
+                        return Err(ParseError::BodyTooLarge);
+                    }
+                    pending.state = match size {
+                        0 => BodyState::Trailers,
+                        size => BodyState::ChunkData(size),
+                    };
+                }
+                BodyState::ChunkData(size) => {
+                    if self.buf.len() < size {
+                        return Ok(None);
+                    }
+                    pending.request.body.extend(self.buf.drain(..size));
+                    pending.state = BodyState::ChunkEnd;
+                }
+                BodyState::ChunkEnd => {
+                    if self.buf.len() < 2 {
+                        return Ok(None);
+                    }
+                    if !self.buf.starts_with(b"\r\n") {
+                        return Err(ParseError::Malformed("missing CRLF after chunk"));
+                    }
+                    self.buf.drain(..2);
+                    pending.state = BodyState::ChunkSize;
+                }
+                BodyState::Trailers => {
+                    // Trailer fields are read and discarded up to the terminating empty line.
+                    let Some(end) = find(&self.buf, b"\r\n") else {
+                        if self.buf.len() > MAX_HEAD_SIZE {
+                            return Err(ParseError::HeadTooLarge);
+                        }
+                        return Ok(None);
+                    };
+                    self.buf.drain(..end + 2);
+                    if end == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        self.expect_continue = false;
+        Ok(self.pending.take().map(|pending| pending.request))
+    }
+}
+
+fn body_state(headers: &Headers) -> Result<BodyState, ParseError> {
+    let transfer_encoding = headers
+        .get_all("transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    let content_length = headers.get_all("content-length").collect::<Vec<_>>();
+
+    if !transfer_encoding.is_empty() {
+        // A message carrying both is a request smuggling vector (RFC 9112 §6.1).
+        if !content_length.is_empty() {
+            return Err(ParseError::Malformed(
+                "both Transfer-Encoding and Content-Length present",
+            ));
+        }
+        return match transfer_encoding.as_slice() {
+            [encoding] if encoding.eq_ignore_ascii_case("chunked") => Ok(BodyState::ChunkSize),
+            _ => Err(ParseError::UnsupportedTransferEncoding),
+        };
+    }
+
+    let mut length = None;
+    for value in content_length.iter().flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::Malformed("invalid Content-Length"));
+        }
+        let value: usize = value.parse().map_err(|_| ParseError::BodyTooLarge)?;
+        if length.is_some_and(|length| length != value) {
+            return Err(ParseError::Malformed("conflicting Content-Length values"));
+        }
+        length = Some(value);
+    }
+    Ok(BodyState::Length(length.unwrap_or(0)))
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Decodes `%XX` escapes, `None` if an escape is malformed or the result isn't UTF-8.
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `pieces` one at a time, collecting every request completed so far.
+    fn parse_pieces(parser: &mut RequestParser, pieces: &[&[u8]]) -> Vec<HttpRequest> {
+        let mut requests = Vec::new();
+        for piece in pieces {
+            parser.feed(piece);
+            while let Some(request) = parser.next_request().unwrap() {
+                requests.push(request);
+            }
+        }
+        requests
+    }
+
+    fn parse_err(bytes: &[u8]) -> ParseError {
+        let mut parser = RequestParser::new(64);
+        parser.feed(bytes);
+        parser.next_request().unwrap_err()
+    }
+
+    #[test]
+    fn request_split_into_single_bytes() {
+        let raw = b"POST /rpc?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let pieces = raw.chunks(1).collect::<Vec<_>>();
+        let mut parser = RequestParser::default();
+        let requests = parse_pieces(&mut parser, &pieces);
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/rpc");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(request.headers.get("HOST"), Some("a"));
+        assert_eq!(request.body, b"hello");
+        assert!(!parser.is_mid_request());
+    }
+
+    #[test]
+    fn pipelined_requests_in_one_read() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\n\
+            POST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi\
+            GET /c HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /d";
+        let mut parser = RequestParser::default();
+        let requests = parse_pieces(&mut parser, &[raw]);
+        let paths = requests.iter().map(|r| r.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+        assert_eq!(requests[1].body, b"hi");
+        assert!(requests[2].keep_alive());
+        // The start of the fourth request stays buffered.
+        assert!(parser.is_mid_request());
+        assert_eq!(parser.into_buffered(), b"GET /d");
+    }
+
+    #[test]
+    fn chunked_body_with_extensions_and_trailers() {
+        let pieces: [&[u8]; 5] = [
+            b"POST /rpc HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\nWi",
+            b"ki\r\n5\r\npedia\r\n",
+            b"0\r\nExpires: never\r\n",
+            b"Checksum: 42\r\n",
+            b"\r\nGET /next HTTP/1.1\r\n\r\n",
+        ];
+        let mut parser = RequestParser::default();
+        let requests = parse_pieces(&mut parser, &pieces);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, b"Wikipedia");
+        assert_eq!(requests[1].path, "/next");
+    }
+
+    #[test]
+    fn expect_continue_reported_once_per_request() {
+        let mut parser = RequestParser::default();
+        parser.feed(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n");
+        assert!(parser.next_request().unwrap().is_none());
+        assert!(parser.take_expect_continue());
+        assert!(!parser.take_expect_continue());
+        parser.feed(b"abc");
+        assert_eq!(parser.next_request().unwrap().unwrap().body, b"abc");
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let err = parse_err(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n",
+        );
+        assert!(matches!(err, ParseError::Malformed(_)), "{err}");
+    }
+
+    #[test]
+    fn rejects_conflicting_content_lengths() {
+        let err = parse_err(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n");
+        assert!(matches!(err, ParseError::Malformed(_)), "{err}");
+    }
+
+    #[test]
+    fn rejects_unsupported_transfer_encoding() {
+        let err = parse_err(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert!(
+            matches!(err, ParseError::UnsupportedTransferEncoding),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_bodies() {
+        let err = parse_err(b"POST / HTTP/1.1\r\nContent-Length: 65\r\n\r\n");
+        assert!(matches!(err, ParseError::BodyTooLarge), "{err}");
+        // Chunked bodies are counted as they arrive.
+        let err = parse_err(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n41\r\n");
+        assert!(matches!(err, ParseError::BodyTooLarge), "{err}");
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let err = parse_err(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n");
+        assert!(
+            matches!(err, ParseError::Malformed("invalid chunk size")),
+            "{err}"
+        );
+        let err = parse_err(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabcd");
+        assert!(
+            matches!(err, ParseError::Malformed("missing CRLF after chunk")),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_heads() {
+        assert!(matches!(
+            parse_err(b"GET / HTTP/2.0\r\n\r\n"),
+            ParseError::UnsupportedVersion
+        ));
+        assert!(matches!(
+            parse_err(b"GET /\r\n\r\n"),
+            ParseError::Malformed("invalid request line")
+        ));
+        assert!(matches!(
+            parse_err(b"GET / HTTP/1.1\r\nX: a\r\n folded\r\n\r\n"),
+            ParseError::Malformed("obsolete header line folding")
+        ));
+        let mut huge = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        huge.resize(MAX_HEAD_SIZE + 10, b'a');
+        assert!(matches!(parse_err(&huge), ParseError::HeadTooLarge));
+    }
+
+    #[test]
+    fn query_params_are_decoded() {
+        let mut parser = RequestParser::default();
+        parser.feed(b"GET /s?q=caf%C3%A9+au+lait&empty&bad=%zz HTTP/1.1\r\n\r\n");
+        let request = parser.next_request().unwrap().unwrap();
+        assert_eq!(
+            request.query_params(),
+            [
+                ("q".to_string(), "café au lait".to_string()),
+                ("empty".to_string(), String::new()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,459 @@
+//! [JSON-RPC 2.0](https://www.jsonrpc.org/specification) framing for services
+//! generated by `#[rpissc::service]`.
+//!
+//! Method names are the same as in the native `{ "method", "data" }` protocol.
+//! `params` may be given by position or by name. A method taking a single argument
+//! also accepts that argument's fields directly as the params object.
+
+use std::future::Future;
+
+use futures_util::future::join_all;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    error::{ErrorBody, ErrorCode, ErrorEnvelope},
+    http::HttpRequest,
+    service::{BoxFuture, Endpoint, Response},
+};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Application errors that aren't about the params, e.g. `not_found` or `conflict`.
+/// The error's `data` carries the service's own error object.
+pub const SERVER_ERROR: i64 = -32000;
+
+/// Most requests a batch may hold, as they all run at once.
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// A single method call, taken out of its request envelope.
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub method: String,
+    pub params: Option<Value>,
+}
+
+/// The JSON-RPC error object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+pub type Outcome = Result<Value, Error>;
+
+impl Error {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(METHOD_NOT_FOUND, format!("method not found: {method}"))
+    }
+
+    pub fn invalid_params(message: impl std::fmt::Display) -> Self {
+        Self::new(INVALID_PARAMS, format!("invalid params: {message}"))
+    }
+}
+
+impl From<ErrorBody> for Error {
+    fn from(body: ErrorBody) -> Self {
+        let code = match body.code {
+            ErrorCode::BadRequest | ErrorCode::ValidationFailed => INVALID_PARAMS,
+            ErrorCode::Internal => INTERNAL_ERROR,
+            _ => SERVER_ERROR,
+        };
+        Self {
+            code,
+            message: body.message.clone(),
+            data: serde_json::to_value(body).ok(),
+        }
+    }
+}
+
+/// Turns the response of a native dispatch into a JSON-RPC result or error.
+pub fn outcome(res: Response) -> Outcome {
+    if res.status.starts_with('2') {
+        return serde_json::from_str(&res.body)
+            .map_err(|err| Error::new(INTERNAL_ERROR, format!("invalid result: {err}")));
+    }
+    match serde_json::from_str::<ErrorEnvelope>(&res.body) {
+        Ok(envelope) => Err(envelope.error.into()),
+        Err(_) => Err(Error::new(INTERNAL_ERROR, res.status)),
+    }
+}
+
+/// The single argument of a method, see the module docs.
+pub fn single_param(params: Option<Value>, name: &str) -> Result<Value, Error> {
+    match params {
+        None => Ok(Value::Null),
+        Some(Value::Array(mut params)) if params.len() == 1 => Ok(params.remove(0)),
+        Some(Value::Array(params)) => Err(Error::invalid_params(format!(
+            "expected 1 parameter, got {}",
+            params.len()
+        ))),
+        Some(Value::Object(mut params)) if params.len() == 1 && params.contains_key(name) => {
+            Ok(params.remove(name).unwrap())
+        }
+        Some(params) => Ok(params),
+    }
+}
+
+/// The arguments of a method taking several, keyed by name.
+pub fn named_params(params: Option<Value>, names: &[&str]) -> Result<Map<String, Value>, Error> {
+    match params {
+        None => Ok(Map::new()),
+        Some(Value::Array(params)) if params.len() <= names.len() => Ok(names
+            .iter()
+            .map(|name| name.to_string())
+            .zip(params)
+            .collect()),
+        Some(Value::Array(params)) => Err(Error::invalid_params(format!(
+            "expected at most {} parameters, got {}",
+            names.len(),
+            params.len()
+        ))),
+        Some(Value::Object(params)) => {
+            match params.keys().find(|key| !names.contains(&key.as_str())) {
+                Some(unknown) => Err(Error::invalid_params(format!(
+                    "unknown parameter {unknown}"
+                ))),
+                None => Ok(params),
+            }
+        }
+        Some(_) => Err(Error::invalid_params("params must be an array or object")),
+    }
+}
+
+/// Takes the argument `name` out of `params`, a missing one decodes from `null`.
+pub fn take_param<T: DeserializeOwned>(
+    params: &mut Map<String, Value>,
+    name: &str,
+) -> Result<T, Error> {
+    let value = params.remove(name).unwrap_or(Value::Null);
+    serde_json::from_value(value).map_err(|err| Error::invalid_params(format!("{name}: {err}")))
+}
+
+/// Checks that a method without arguments got none.
+pub fn no_params(params: Option<Value>) -> Result<(), Error> {
+    match params {
+        None | Some(Value::Null) => Ok(()),
+        Some(Value::Array(params)) if params.is_empty() => Ok(()),
+        Some(Value::Object(params)) if params.is_empty() => Ok(()),
+        Some(_) => Err(Error::invalid_params("method takes no parameters")),
+    }
+}
+
+/// An async function answering a single [`Call`] with a borrow of the state `S`.
+/// Blanket implemented like [`crate::service::Handler`].
+pub trait Handler<'a, S: 'a>: Fn(Call, &'a S) -> Self::Future {
+    type Future: Future<Output = Outcome> + Send + 'a;
+}
+
+impl<'a, S, F, Ft> Handler<'a, S> for F
+where
+    S: 'a,
+    F: Fn(Call, &'a S) -> Ft,
+    Ft: Future<Output = Outcome> + Send + 'a,
+{
+    type Future = Ft;
+}
+
+#[derive(Serialize)]
+struct ResponseObject {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: OutcomeField,
+    id: Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutcomeField {
+    Result(Value),
+    Error(Error),
+}
+
+impl ResponseObject {
+    fn new(outcome: Outcome, id: Value) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            outcome: match outcome {
+                Ok(result) => OutcomeField::Result(result),
+                Err(error) => OutcomeField::Error(error),
+            },
+            id,
+        }
+    }
+}
+
+/// Serves JSON-RPC requests and batches over HTTP POST.
+pub(crate) struct JsonRpc<H>(pub H);
+
+impl<H> JsonRpc<H> {
+    /// Answers one request object, `None` for notifications.
+    async fn handle<'a, S>(&'a self, request: Value, state: &'a S) -> Option<ResponseObject>
+    where
+        H: for<'b> Handler<'b, S>,
+    {
+        let (call, id) = match parse_call(request) {
+            Ok(parsed) => parsed,
+            Err((error, id)) => return Some(ResponseObject::new(Err(error), id)),
+        };
+        let outcome = (self.0)(call, state).await;
+        id.map(|id| ResponseObject::new(outcome, id))
+    }
+}
+
+impl<S, H> Endpoint<S> for JsonRpc<H>
+where
+    S: Sync,
+    H: for<'a> Handler<'a, S> + Send + Sync,
+{
+    fn call<'a>(&'a self, req: HttpRequest, state: &'a S) -> BoxFuture<'a, Response> {
+        Box::pin(async move {
+            let body = match serde_json::from_slice::<Value>(&req.body) {
+                Ok(body) => body,
+                Err(err) => {
+                    let error = Error::new(PARSE_ERROR, format!("parse error: {err}"));
+                    return reply(&ResponseObject::new(Err(error), Value::Null));
+                }
+            };
+            match body {
+                Value::Array(batch) if batch.is_empty() => {
+                    let error = Error::new(INVALID_REQUEST, "empty batch");
+                    reply(&ResponseObject::new(Err(error), Value::Null))
+                }
+                Value::Array(batch) if batch.len() > MAX_BATCH_SIZE => {
+                    let message = format!(
+                        "batch of {} requests, at most {MAX_BATCH_SIZE} are allowed",
+                        batch.len()
+                    );
+                    let error = Error::new(INVALID_REQUEST, message);
+                    reply(&ResponseObject::new(Err(error), Value::Null))
+                }
+                // Batched calls run concurrently, each taking its own connection from
+                // the pool. Responses keep the order of the requests.
+                Value::Array(batch) => {
+                    let responses = join_all(batch.into_iter().map(|req| self.handle(req, state)))
+                        .await
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>();
+                    if responses.is_empty() {
+                        Response::new("204 No Content", "")
+                    } else {
+                        reply(&responses)
+                    }
+                }
+                request => match self.handle(request, state).await {
+                    Some(response) => reply(&response),
+                    None => Response::new("204 No Content", ""),
+                },
+            }
+        })
+    }
+}
+
+fn reply(body: &impl Serialize) -> Response {
+    Response::new("200 OK", serde_json::to_string(body).unwrap())
+}
+
+/// Validates a request object, returning the call and its id, `None` for a
+/// notification. Errors carry the id to answer with.
+fn parse_call(request: Value) -> Result<(Call, Option<Value>), (Error, Value)> {
+    let Value::Object(mut request) = request else {
+        return Err((
+            Error::new(INVALID_REQUEST, "request must be an object"),
+            Value::Null,
+        ));
+    };
+    let id = request.remove("id");
+    let invalid = |message: &str| {
+        let id = match &id {
+            Some(id @ (Value::String(_) | Value::Number(_))) => id.clone(),
+            _ => Value::Null,
+        };
+        Err((Error::new(INVALID_REQUEST, message), id))
+    };
+    if !matches!(
+        &id,
+        None | Some(Value::String(_) | Value::Number(_) | Value::Null)
+    ) {
+        return invalid("id must be a string, number or null");
+    }
+    if request.remove("jsonrpc").as_ref().and_then(Value::as_str) != Some("2.0") {
+        return invalid("jsonrpc must be \"2.0\"");
+    }
+    let Some(Value::String(method)) = request.remove("method") else {
+        return invalid("method must be a string");
+    };
+    let params = request.remove("params");
+    if !matches!(&params, None | Some(Value::Array(_) | Value::Object(_))) {
+        return invalid("params must be an array or object");
+    }
+    if let Some(unknown) = request.keys().next() {
+        return invalid(&format!("unknown member {unknown}"));
+    }
+    Ok((Call { method, params }, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::http::RequestParser;
+
+    /// Echoes the params back, failing calls to `fail`.
+    async fn echo(call: Call, _state: &()) -> Outcome {
+        match call.method.as_str() {
+            "fail" => Err(ErrorBody::new(ErrorCode::NotFound, "no such thing").into()),
+            _ => Ok(call.params.unwrap_or(Value::Null)),
+        }
+    }
+
+    async fn post(body: Value) -> Response {
+        post_raw(&body.to_string()).await
+    }
+
+    async fn post_raw(body: &str) -> Response {
+        let head = format!(
+            "POST /rpc HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        let mut parser = RequestParser::default();
+        parser.feed(head.as_bytes());
+        parser.feed(body.as_bytes());
+        let req = parser.next_request().unwrap().unwrap();
+        JsonRpc(echo).call(req, &()).await
+    }
+
+    async fn post_json(body: Value) -> Value {
+        let res = post(body).await;
+        assert_eq!(res.status, "200 OK");
+        serde_json::from_str(&res.body).unwrap()
+    }
+
+    fn request(id: u64) -> Value {
+        json!({ "jsonrpc": "2.0", "method": "echo", "params": [id], "id": id })
+    }
+
+    #[tokio::test]
+    async fn answers_calls_with_their_id() {
+        let res = post_json(request(7)).await;
+        assert_eq!(res, json!({ "jsonrpc": "2.0", "result": [7], "id": 7 }));
+        let res = post_json(json!({ "jsonrpc": "2.0", "method": "fail", "id": "a" })).await;
+        assert_eq!(res["id"], "a");
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+        assert_eq!(res["error"]["data"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let res = post(json!({ "jsonrpc": "2.0", "method": "echo" })).await;
+        assert_eq!(res.status, "204 No Content");
+        let res = post(json!([{ "jsonrpc": "2.0", "method": "echo" }])).await;
+        assert_eq!(res.status, "204 No Content");
+    }
+
+    #[tokio::test]
+    async fn batches_answer_in_order_leaving_out_notifications() {
+        let batch = json!([
+            request(1),
+            { "jsonrpc": "2.0", "method": "echo" },
+            request(2),
+            { "jsonrpc": "2.0", "id": 3 },
+        ]);
+        let res = post_json(batch).await;
+        let ids = res
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|res| res["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [json!(1), json!(2), json!(3)]);
+        assert_eq!(res[2]["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_and_oversized_batches() {
+        let res = post_json(json!([])).await;
+        assert_eq!(res["error"]["code"], INVALID_REQUEST);
+        let batch = (0..=MAX_BATCH_SIZE as u64).map(request).collect::<Vec<_>>();
+        let res = post_json(Value::Array(batch)).await;
+        assert_eq!(res["error"]["code"], INVALID_REQUEST);
+        assert_eq!(res["id"], Value::Null);
+        let batch = (0..MAX_BATCH_SIZE as u64).map(request).collect::<Vec<_>>();
+        let res = post_json(Value::Array(batch)).await;
+        assert_eq!(res.as_array().unwrap().len(), MAX_BATCH_SIZE);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests() {
+        let cases = [
+            json!(1),
+            json!({ "jsonrpc": "1.0", "method": "echo", "id": 1 }),
+            json!({ "jsonrpc": "2.0", "method": 5, "id": 1 }),
+            json!({ "jsonrpc": "2.0", "method": "echo", "params": 5, "id": 1 }),
+            json!({ "jsonrpc": "2.0", "method": "echo", "id": [1] }),
+            json!({ "jsonrpc": "2.0", "method": "echo", "extra": true, "id": 1 }),
+        ];
+        for case in cases {
+            let res = post_json(case.clone()).await;
+            assert_eq!(res["error"]["code"], INVALID_REQUEST, "{case}");
+        }
+        let res = post_raw("{").await;
+        let res: Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(res["error"]["code"], PARSE_ERROR);
+        assert_eq!(res["id"], Value::Null);
+    }
+
+    #[test]
+    fn params_by_position_or_name() {
+        assert_eq!(single_param(Some(json!(["a"])), "id").unwrap(), "a");
+        assert_eq!(single_param(Some(json!({ "id": "a" })), "id").unwrap(), "a");
+        // A single struct argument may be given as the params object itself.
+        let fields = json!({ "name": "Onyx", "website": null });
+        assert_eq!(
+            single_param(Some(fields.clone()), "roastery").unwrap(),
+            fields
+        );
+        assert!(single_param(Some(json!(["a", "b"])), "id").is_err());
+
+        let mut named = named_params(Some(json!(["r"])), &["roastery_id", "origin_id"]).unwrap();
+        assert_eq!(
+            take_param::<String>(&mut named, "roastery_id").unwrap(),
+            "r"
+        );
+        assert_eq!(
+            take_param::<Option<String>>(&mut named, "origin_id").unwrap(),
+            None
+        );
+        let err = named_params(Some(json!({ "color": 1 })), &["id"]).unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+        assert!(named_params(Some(json!([1, 2])), &["id"]).is_err());
+
+        assert!(no_params(Some(json!([]))).is_ok());
+        assert!(no_params(Some(json!({}))).is_ok());
+        assert!(no_params(Some(json!([1]))).is_err());
+    }
+
+    #[test]
+    fn service_errors_map_to_json_rpc_codes() {
+        let code = |code| Error::from(ErrorBody::new(code, "")).code;
+        assert_eq!(code(ErrorCode::ValidationFailed), INVALID_PARAMS);
+        assert_eq!(code(ErrorCode::BadRequest), INVALID_PARAMS);
+        assert_eq!(code(ErrorCode::Internal), INTERNAL_ERROR);
+        assert_eq!(code(ErrorCode::Conflict), SERVER_ERROR);
+    }
+}
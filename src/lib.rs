@@ -0,0 +1,16 @@
+pub mod api;
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod context;
+pub mod db;
+pub mod error;
+pub mod events;
+pub mod http;
+pub mod jsonrpc;
+pub mod money;
+pub mod ratelimit;
+pub mod service;
+pub mod state;
+pub mod validation;
+pub mod websocket;
@@ -2,146 +2,2576 @@
 #![feature(associated_type_defaults)]
 #![feature(impl_trait_in_assoc_type)]
 
-use std::ops::Deref;
+use std::{future::Future, ops::Deref, path::PathBuf};
 
-use db::{Coffee, Nanoid};
-use serde::{Deserialize, Serialize};
-use service::{server_loop, IntoResponse, Request};
-use sqlx::{query, query_as, Pool, Sqlite};
+use clap::{Parser, Subcommand};
+use db_test_rs::{
+    api::{
+        AddCoffeeParams, AddFarmer, AddOrigin, AddRoastery, CoffeePage, CoffeeRpc, CoffeeSort,
+        CreateApiKey, EditCoffee, EditFarmer, EditOrigin, EditRoastery, ListCoffees, Methods,
+        RevertCoffee, SearchHit, SearchParams, SetExchangeRate, SetRole, SortOrder, MAX_PAGE_SIZE,
+    },
+    auth::{hash_key, GeneratedKey, Principal, ALL_ROASTERIES},
+    config::{ConfigOverrides, RpcProtocol, ServerConfig},
+    context::{self, RequestContext},
+    db::{
+        self, ApiKey, ChangeAction, Coffee, CoffeeChange, CoffeeRow, Cursor, CursorKey, Farmer,
+        IssuedApiKey, Nanoid, Origin, Roastery, Role,
+    },
+    error::{AppError, AppResult, ErrorCode},
+    events::CoffeeEventKind,
+    http::{HttpRequest, Method},
+    jsonrpc,
+    money::{Currency, ExchangeRate, ExchangeRates},
+    service::{IntoResponse, Request, Response, Router},
+    state::AppState,
+    validation::{Validate, ValidationErrors},
+};
+use sqlx::{
+    query, query_as, query_scalar, types::Json, QueryBuilder, Sqlite, SqliteConnection, Transaction,
+};
 
-mod db;
-mod service;
+#[derive(Debug, Parser)]
+#[command(about = "Coffee catalog RPC server")]
+struct Cli {
+    /// Path to a TOML config file, `coffee.toml` is used if present
+    #[arg(long, short, env = "COFFEE_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    overrides: ConfigOverrides,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List applied and pending database migrations
+    Migrations,
+    /// Issue an API key and print it, e.g. the first one, which can't be created
+    /// over the API without a key
+    CreateKey {
+        /// Who the key is for
+        name: String,
+        /// The role the key gets
+        #[arg(long, default_value = "admin")]
+        role: String,
+    },
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
-    server_loop(handle_with_timeout).await?;
+    let cli = Cli::parse();
+    let config = ServerConfig::load(cli.config.as_deref(), cli.overrides)?;
+    match cli.command {
+        Some(Command::Migrations) => return print_migrations(&config).await,
+        Some(Command::CreateKey { name, role }) => return create_key(config, name, role).await,
+        None => {}
+    }
+    let db = db::connect(&config).await?;
+    let router = router(&config);
+    let server = CoffeeServer(AppState::new(db.clone(), config.clone()));
+    server.serve(router, config).await?;
+    // Every connection has been drained or aborted by now, closing the pool waits
+    // for checked out connections to come back and shuts SQLite down cleanly.
+    db.close().await;
+    tracing::info!("🌙 Database closed");
     Ok(())
 }
 
-async fn handle_with_timeout(req: Request, db: &Pool<Sqlite>) -> impl IntoResponse {
-    let result =
-        tokio::time::timeout(tokio::time::Duration::from_secs(30), rpc_router(req, db)).await?;
-    anyhow::Ok(result.into_response().body)
+/// Every route the server answers.
+fn router(config: &ServerConfig) -> Router<CoffeeServer> {
+    let router = match config.rpc_protocol {
+        RpcProtocol::Native => Router::new().rpc("/rpc", rpc_call),
+        RpcProtocol::JsonRpc => Router::new().json_rpc("/rpc", json_rpc_call),
+    };
+    router
+        .websocket("/ws", rpc_call)
+        .route(Method::Get, "/health", health)
+        .route(Method::Get, "/events", events)
+        .route(Method::Get, "/coffees", rest_list_coffees)
+        .route(Method::Post, "/coffees", rest_add_coffee)
+        .route(Method::Get, "/coffees/random", rest_random_coffee)
+        .route(Method::Get, "/coffees/search", rest_search_coffees)
+        .route(Method::Get, "/coffees/{id}", rest_grab_coffee)
+        .route(Method::Patch, "/coffees/{id}", rest_edit_coffee)
+        .route(Method::Delete, "/coffees/{id}", rest_delete_coffee)
+        .route(Method::Get, "/coffees/deleted", rest_list_deleted_coffees)
+        .route(Method::Get, "/coffees/{id}/history", rest_coffee_history)
+        .route(Method::Post, "/coffees/{id}/revert", rest_revert_coffee)
+        .route(Method::Post, "/coffees/{id}/restore", rest_restore_coffee)
+        .route(Method::Delete, "/coffees/deleted/{id}", rest_purge_coffee)
+        .route(Method::Get, "/exchange-rates", rest_list_exchange_rates)
+        .route(
+            Method::Put,
+            "/exchange-rates/{from}/{to}",
+            rest_set_exchange_rate,
+        )
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "method", content = "data")]
-pub enum Methods {
-    GetRandomCoffee,
-    AddCoffee(AddCoffeeParams),
-    GrabId { roastery: String, origin: String },
-    GrabCoffee(Nanoid),
-    EditCoffee(EditCoffee),
+async fn print_migrations(config: &ServerConfig) -> anyhow::Result<()> {
+    let db = db::open(config).await?;
+    let mut conn = db.acquire().await?;
+    for migration in db::migration_status(&mut conn).await? {
+        match migration.installed_on {
+            Some(installed_on) => println!(
+                "applied  {} {} ({installed_on})",
+                migration.version, migration.description
+            ),
+            None => println!("pending  {} {}", migration.version, migration.description),
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AddCoffeeParams {
-    pub roastery: String,
-    pub icon: String,
-    pub farmer: String,
-    pub price: i64,
-    pub origin: String,
+async fn create_key(config: ServerConfig, name: String, role: String) -> anyhow::Result<()> {
+    let params = CreateApiKey { name, role };
+    params.validate().map_err(AppError::from)?;
+    let db = db::connect(&config).await?;
+    let server = CoffeeServer(AppState::new(db.clone(), config));
+    let issued = server.create_api_key(params).await?;
+    println!("{}", issued.key);
+    eprintln!(
+        "Issued key {} for {}, it won't be shown again",
+        issued.api_key.id.deref(),
+        issued.api_key.name
+    );
+    db.close().await;
+    Ok(())
 }
 
-async fn rpc_router(req: Request, db: &Pool<Sqlite>) -> impl IntoResponse {
-    let value = serde_json::to_value(req)?;
-    let method: Methods = serde_json::from_value(value)?;
-    tracing::info!("🌸 Received request: {:?}", method);
-    let response = match method {
-        Methods::AddCoffee(params) => add_coffee(params, db).await.into_response(),
-        Methods::GetRandomCoffee => get_random_coffee(db).await.into_response(),
-        Methods::GrabId { roastery, origin } => {
-            grab_id(&roastery, &origin, db).await.into_response()
+/// The coffee service, implemented on top of the shared [`AppState`].
+#[derive(Clone)]
+pub struct CoffeeServer(pub AppState);
+
+impl Deref for CoffeeServer {
+    type Target = AppState;
+
+    fn deref(&self) -> &AppState {
+        &self.0
+    }
+}
+
+impl CoffeeServer {
+    /// Starts a transaction whose writes the coffee history attributes to the
+    /// current actor. Finish it with [`CoffeeServer::commit_audited`].
+    async fn begin_audited(&self) -> AppResult<Transaction<'static, Sqlite>> {
+        let mut tx = self.db.begin().await?;
+        let actor = context::current_actor();
+        let now = self.clock.unix_now();
+        query!(
+            "INSERT INTO audit_context (actor, changed_at) VALUES (?, ?)",
+            actor,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(tx)
+    }
+
+    async fn commit_audited(mut tx: Transaction<'static, Sqlite>) -> AppResult<()> {
+        query!("DELETE FROM audit_context")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// A coffee as the API shows it, trashed or not.
+    async fn view_coffee(conn: &mut SqliteConnection, id: &str) -> AppResult<Option<Coffee>> {
+        let coffee = query_as!(CoffeeRow, "SELECT * FROM coffee_view WHERE id = ?", id)
+            .fetch_optional(conn)
+            .await?
+            .map(Coffee::from);
+        Ok(coffee)
+    }
+
+    /// Checks that the roastery, farmer and origin a coffee is to point at exist,
+    /// reporting every missing one like an invalid field.
+    async fn check_references(
+        &self,
+        roastery_id: Option<&str>,
+        farmer_id: Option<&str>,
+        origin_id: Option<&str>,
+    ) -> AppResult<()> {
+        let mut errors = ValidationErrors::default();
+        if let Some(id) = roastery_id {
+            let found = query!("SELECT id FROM roasteries WHERE id = ?", id)
+                .fetch_optional(&self.db)
+                .await?;
+            if found.is_none() {
+                errors.add("roastery_id", format!("no roastery {id}"));
+            }
+        }
+        if let Some(id) = farmer_id {
+            let found = query!("SELECT id FROM farmers WHERE id = ?", id)
+                .fetch_optional(&self.db)
+                .await?;
+            if found.is_none() {
+                errors.add("farmer_id", format!("no farmer {id}"));
+            }
+        }
+        if let Some(id) = origin_id {
+            let found = query!("SELECT id FROM origins WHERE id = ?", id)
+                .fetch_optional(&self.db)
+                .await?;
+            if found.is_none() {
+                errors.add("origin_id", format!("no origin {id}"));
+            }
         }
-        Methods::GrabCoffee(id) => grab_coffee(id, db).await.into_response(),
-        Methods::EditCoffee(params) => edit_coffee(params, db).await.into_response(),
+        Ok(errors.into_result()?)
+    }
+
+    /// Points the coffees of `id`, trashed ones included, at `into` instead, for
+    /// merging two entries of `table` that coffees point at with `column`. The
+    /// coffees moved get a new version, so edits made against the old one
+    /// conflict. Returns them as they are now.
+    async fn move_coffees(
+        tx: &mut SqliteConnection,
+        (kind, table, column): (&str, &str, &str),
+        id: &str,
+        into: &str,
+    ) -> AppResult<Vec<Coffee>> {
+        if id == into {
+            return Err(AppError::BadRequest(format!(
+                "can't merge {kind} {id} into itself"
+            )));
+        }
+        for id in [id, into] {
+            sqlx::query(&format!("SELECT id FROM {table} WHERE id = ?"))
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| AppError::NotFound(format!("{kind} {id} not found")))?;
+        }
+        let moved: Vec<String> = query_scalar(&format!(
+            "UPDATE coffees SET {column} = ?1, version = version + 1
+            WHERE {column} = ?2
+            RETURNING id"
+        ))
+        .bind(into)
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut coffees = Vec::with_capacity(moved.len());
+        for id in moved {
+            coffees.extend(Self::view_coffee(tx, &id).await?);
+        }
+        Ok(coffees)
+    }
+
+    /// Announces coffees a merge moved, once it is committed. Trashed ones stay
+    /// out of the stream like any other change to them.
+    fn publish_moved(&self, coffees: Vec<Coffee>) {
+        for coffee in coffees {
+            if coffee.deleted_at.is_none() {
+                self.events.publish(CoffeeEventKind::Updated, coffee);
+            }
+        }
+    }
+}
+
+impl CoffeeServer {
+    /// Looks up the API key the current request sent, recording who it belongs to
+    /// in the request context. `None` when no key was sent, or outside a request.
+    async fn authenticate(&self) -> AppResult<Option<Principal>> {
+        let Some(ctx) = RequestContext::current() else {
+            return Ok(None);
+        };
+        let Some(key) = ctx.api_key.as_deref() else {
+            return Ok(None);
+        };
+        let hash = hash_key(key);
+        let row = query!(
+            "SELECT id, name, role FROM api_keys WHERE key_hash = ? AND revoked_at IS NULL",
+            hash
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("invalid or revoked API key".into()))?;
+        let permissions = query_scalar!(
+            "SELECT permission FROM role_permissions WHERE role = ?",
+            row.role
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let principal = Principal {
+            key_id: row.id,
+            name: row.name,
+            role: row.role,
+            permissions,
+        };
+        ctx.authenticate(principal.clone());
+        Ok(Some(principal))
+    }
+}
+
+impl CoffeeServer {
+    /// The API key whose roasteries the current request may write coffees of,
+    /// `None` when it may write any, i.e. its role grants [`ALL_ROASTERIES`] or it
+    /// isn't made with a key at all.
+    ///
+    /// Writes pass it to their queries, which only match rows of roasteries the
+    /// key acts for.
+    fn owner_scope() -> Option<String> {
+        let ctx = RequestContext::current()?;
+        ctx.principal()
+            .filter(|principal| !principal.has_permission(ALL_ROASTERIES))
+            .map(|principal| principal.key_id.clone())
+    }
+
+    /// Whether `key_id` acts for `roastery_id`, to tell a write its query refused
+    /// apart from one that found nothing.
+    async fn acts_for(
+        conn: &mut SqliteConnection,
+        key_id: &str,
+        roastery_id: &str,
+    ) -> AppResult<bool> {
+        let found = query!(
+            "SELECT key_id FROM api_key_roasteries WHERE key_id = ? AND roastery_id = ?",
+            key_id,
+            roastery_id
+        )
+        .fetch_optional(conn)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    /// Why a write to roastery `id` matched nothing.
+    async fn roastery_refused(&self, id: &str) -> AppResult<AppError> {
+        let found = query!("SELECT id FROM roasteries WHERE id = ?", id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(match found {
+            Some(_) => Self::not_acting_for(id),
+            None => AppError::NotFound(format!("roastery {id} not found")),
+        })
+    }
+
+    fn not_acting_for(roastery_id: &str) -> AppError {
+        AppError::Forbidden(format!(
+            "this API key doesn't act for roastery {roastery_id}"
+        ))
+    }
+
+    async fn check_api_key(&self, id: &str) -> AppResult<()> {
+        query!("SELECT id FROM api_keys WHERE id = ?", id)
+            .fetch_optional(&self.db)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("no API key {id}")))?;
+        Ok(())
+    }
+
+    /// Fails like an invalid `role` field unless the role exists.
+    async fn check_role(&self, role: &str) -> AppResult<()> {
+        let found = query!("SELECT name FROM roles WHERE name = ?", role)
+            .fetch_optional(&self.db)
+            .await?;
+        if found.is_none() {
+            let mut errors = ValidationErrors::default();
+            errors.add("role", format!("no role {role}"));
+            errors.into_result()?;
+        }
+        Ok(())
+    }
+
+    /// The rates needed to convert prices to `currency`.
+    async fn exchange_rates(&self, currency: Currency) -> AppResult<ExchangeRates> {
+        let target = currency.deref();
+        let rates = query!(
+            "SELECT from_currency, to_currency, rate, updated_at FROM exchange_rates
+            WHERE from_currency = ?1 OR to_currency = ?1",
+            target
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|row| ExchangeRate {
+            from: Currency::from_db(row.from_currency),
+            to: Currency::from_db(row.to_currency),
+            rate: row.rate,
+            updated_at: row.updated_at,
+        });
+        Ok(ExchangeRates::new(currency, rates))
+    }
+}
+
+/// Replaces SQLite's message for a write that broke the unique name of a `kind`
+/// with one naming it, along with the id `existing` finds for it.
+async fn name_taken<T>(
+    result: Result<T, sqlx::Error>,
+    kind: &str,
+    name: Option<&str>,
+    existing: impl Future<Output = Result<Option<String>, sqlx::Error>>,
+) -> AppResult<T> {
+    match result {
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            let message = format!("a {kind} named {} already exists", name.unwrap_or_default());
+            Err(match existing.await? {
+                Some(id) => AppError::Conflict(message)
+                    .with_details(serde_json::json!({ "existing_id": id })),
+                None => AppError::Conflict(message),
+            })
+        }
+        result => Ok(result?),
+    }
+}
+
+async fn rpc_call(req: Request, server: &CoffeeServer) -> Response {
+    server.call(req).await
+}
+
+async fn json_rpc_call(call: jsonrpc::Call, server: &CoffeeServer) -> jsonrpc::Outcome {
+    server.call_json_rpc(call).await
+}
+
+async fn health(_req: HttpRequest, server: &CoffeeServer) -> Response {
+    match query!("SELECT 1 AS ok").fetch_one(&server.db).await {
+        Ok(_) => Response::new("200 OK", r#"{"status":"ok"}"#),
+        Err(err) => {
+            tracing::error!("Health check failed: {err}");
+            Response::new("503 Service Unavailable", r#"{"status":"unavailable"}"#)
+        }
+    }
+}
+
+/// Streams catalog changes, resuming after the `Last-Event-ID` the client sends
+/// when it reconnects. Subscribing is authorized and rate limited as listing
+/// coffees, and changes to the trash are only streamed to keys that may list it.
+async fn events(req: HttpRequest, server: &CoffeeServer) -> Response {
+    if let Err(err) = server
+        .authorize(&Methods::ListCoffees(ListCoffees::default()))
+        .await
+    {
+        return err.into_response();
+    }
+    let trash_permission = Methods::ListDeletedCoffees.permission().unwrap_or_default();
+    let with_trash = RequestContext::current()
+        .and_then(|ctx| ctx.principal().cloned())
+        .is_some_and(|principal| principal.has_permission(trash_permission));
+    let last_event_id = req.headers.get("last-event-id");
+    Response::event_stream(server.events.subscribe(last_event_id, move |event| {
+        with_trash || !event.kind.is_trash()
+    }))
+}
+
+async fn rest_grab_coffee(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let id = path_id(&req);
+    if let Err(err) = server.authorize(&Methods::GrabCoffee(id.clone())).await {
+        return err.into_response();
+    }
+    with_etag(server.grab_coffee(id).await)
+}
+
+async fn rest_random_coffee(_req: HttpRequest, server: &CoffeeServer) -> AppResult<Coffee> {
+    server.authorize(&Methods::GetRandomCoffee).await?;
+    server.get_random_coffee().await
+}
+
+async fn rest_list_coffees(req: HttpRequest, server: &CoffeeServer) -> AppResult<CoffeePage> {
+    let query = list_query(&req)?;
+    server
+        .authorize(&Methods::ListCoffees(query.clone()))
+        .await?;
+    query.validate()?;
+    server.list_coffees(query).await
+}
+
+/// Reads a [`ListCoffees`] from `?roastery_id=..&min_price=..&sort=price&order=desc`.
+fn list_query(req: &HttpRequest) -> AppResult<ListCoffees> {
+    fn parse<T: std::str::FromStr>(name: &str, value: &str) -> AppResult<T> {
+        value
+            .parse()
+            .map_err(|_| AppError::BadRequest(format!("invalid {name}: {value}")))
+    }
+    fn decode<T: serde::de::DeserializeOwned>(name: &str, value: String) -> AppResult<T> {
+        serde_json::from_value(value.clone().into())
+            .map_err(|_| AppError::BadRequest(format!("invalid {name}: {value}")))
+    }
+
+    let mut query = ListCoffees::default();
+    for (name, value) in req.query_params() {
+        match name.as_str() {
+            "roastery_id" => query.roastery_id = Some(value.into()),
+            "origin_id" => query.origin_id = Some(value.into()),
+            "farmer_id" => query.farmer_id = Some(value.into()),
+            "mine" => query.mine = parse(&name, &value)?,
+            "currency" => query.currency = Some(decode(&name, value)?),
+            "convert_to" => query.convert_to = Some(decode(&name, value)?),
+            "min_price" => query.min_price = Some(parse(&name, &value)?),
+            "max_price" => query.max_price = Some(parse(&name, &value)?),
+            "sort" => query.sort = decode(&name, value)?,
+            "order" => query.order = decode(&name, value)?,
+            "limit" => query.limit = Some(parse(&name, &value)?),
+            "cursor" => query.cursor = Some(value),
+            _ => return Err(AppError::BadRequest(format!("unknown parameter {name}"))),
+        }
+    }
+    Ok(query)
+}
+
+async fn rest_search_coffees(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let mut search = SearchParams {
+        query: String::new(),
+        limit: None,
+        convert_to: None,
     };
-    anyhow::Ok(response.body)
-}
-
-async fn get_random_coffee(db: &Pool<Sqlite>) -> impl IntoResponse {
-    let coffee = query_as!(
-        Coffee,
-        "SELECT * FROM coffees
-        ORDER BY RANDOM()
-        LIMIT 1 
-        "
-    )
-    .fetch_one(db)
-    .await?;
-
-    anyhow::Ok(coffee)
-}
-
-async fn grab_id(roastery: &str, origin: &str, db: &Pool<Sqlite>) -> impl IntoResponse {
-    let id = query!(
-        "SELECT id FROM coffees WHERE roastery = ? AND origin = ?",
-        roastery,
-        origin
-    )
-    .fetch_one(db)
-    .await?;
-    let id = id.id.ok_or(anyhow::Error::msg("No coffee found"))?;
-    anyhow::Ok(id)
-}
-
-async fn grab_coffee(id: Nanoid, db: &Pool<Sqlite>) -> impl IntoResponse {
-    let id = id.deref();
-    let coffee = query_as!(Coffee, "SELECT * FROM coffees WHERE id = ?", id)
-        .fetch_one(db)
-        .await?;
-    anyhow::Ok(coffee)
-}
-
-async fn add_coffee(coffee: AddCoffeeParams, db: &Pool<Sqlite>) -> impl IntoResponse {
-    let id = Nanoid::new().to_string();
-    query!(
-        "INSERT INTO coffees (id, roastery, icon, farmer, price, origin) 
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        id,
-        coffee.roastery,
-        coffee.icon,
-        coffee.farmer,
-        coffee.price,
-        coffee.origin
-    )
-    .execute(db)
-    .await?;
-    anyhow::Ok(())
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EditCoffee {
-    pub id: Nanoid,
-    pub roastery: Option<String>,
-    pub icon: Option<String>,
-    pub farmer: Option<String>,
-    pub price: Option<i64>,
-    pub origin: Option<String>,
-}
-
-async fn edit_coffee(coffee: EditCoffee, db: &Pool<Sqlite>) -> impl IntoResponse {
-    let id = coffee.id.deref();
-    let res = query_as!(
-        Coffee,
-        "UPDATE coffees 
-        SET 
-            roastery = COALESCE(?, roastery),
-            icon = COALESCE(?, icon),
-            farmer = COALESCE(?, farmer),
-            price = COALESCE(?, price),
-            origin = COALESCE(?, origin)
-        WHERE id = ?
-        RETURNING *",
-        coffee.roastery,
-        coffee.icon,
-        coffee.farmer,
-        coffee.price,
-        coffee.origin,
-        id
-    )
-    .fetch_one(db)
-    .await?;
-    anyhow::Ok(res)
+    for (name, value) in req.query_params() {
+        match name.as_str() {
+            "q" => search.query = value,
+            "convert_to" => match serde_json::from_value(value.into()) {
+                Ok(currency) => search.convert_to = Some(currency),
+                Err(err) => return AppError::BadRequest(err.to_string()).into_response(),
+            },
+            "limit" => match value.parse() {
+                Ok(limit) => search.limit = Some(limit),
+                Err(_) => {
+                    return AppError::BadRequest(format!("invalid limit: {value}")).into_response()
+                }
+            },
+            _ => return AppError::BadRequest(format!("unknown parameter {name}")).into_response(),
+        }
+    }
+    if let Err(err) = server
+        .authorize(&Methods::SearchCoffees(search.clone()))
+        .await
+    {
+        return err.into_response();
+    }
+    if let Err(errors) = search.validate() {
+        return AppError::from(errors).into_response();
+    }
+    server.search_coffees(search).await.into_response()
+}
+
+async fn rest_add_coffee(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let params: AddCoffeeParams = match serde_json::from_slice(&req.body) {
+        Ok(params) => params,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    if let Err(err) = server.authorize(&Methods::AddCoffee(params.clone())).await {
+        return err.into_response();
+    }
+    if let Err(errors) = params.validate() {
+        return AppError::from(errors).into_response();
+    }
+    match server.add_coffee(params).await {
+        Ok(coffee) => {
+            let location = format!("/coffees/{}", coffee.id.deref());
+            let mut res = with_etag(Ok(coffee));
+            res.status = "201 Created";
+            res.with_header("Location", location)
+        }
+        err => err.into_response(),
+    }
+}
+
+async fn rest_edit_coffee(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        match serde_json::from_slice(&req.body) {
+            Ok(fields) => fields,
+            Err(err) => return AppError::from(err).into_response(),
+        };
+    fields.insert("id".into(), path_id(&req).deref().into());
+    let mut params: EditCoffee = match serde_json::from_value(fields.into()) {
+        Ok(params) => params,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    let if_match = req.headers.get("if-match").map(str::trim);
+    match if_match {
+        None | Some("*") => {}
+        Some(etag) => match parse_etag(etag) {
+            Some(version) => params.expected_version = Some(version),
+            None => {
+                let message = format!("If-Match must be an ETag of this API, got {etag}");
+                return AppError::BadRequest(message).into_response();
+            }
+        },
+    }
+    if let Err(err) = server.authorize(&Methods::EditCoffee(params.clone())).await {
+        return err.into_response();
+    }
+    if let Err(errors) = params.validate() {
+        return AppError::from(errors).into_response();
+    }
+    match server.edit_coffee(params).await {
+        // A failed If-Match is a failed precondition, a stale version in the
+        // body a plain conflict.
+        Err(err) if if_match.is_some() && err.code() == ErrorCode::Conflict => {
+            err.body().with_status("412 Precondition Failed")
+        }
+        res => with_etag(res),
+    }
+}
+
+/// Renders a coffee along with its version as `ETag`, for use with `If-Match`.
+fn with_etag(res: AppResult<Coffee>) -> Response {
+    match res {
+        Ok(coffee) => {
+            let etag = format!("\"{}\"", coffee.version);
+            AppResult::Ok(coffee)
+                .into_response()
+                .with_header("ETag", etag)
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+fn parse_etag(etag: &str) -> Option<i64> {
+    etag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+}
+
+async fn rest_delete_coffee(req: HttpRequest, server: &CoffeeServer) -> AppResult<Coffee> {
+    let id = path_id(&req);
+    server.authorize(&Methods::DeleteCoffee(id.clone())).await?;
+    server.delete_coffee(id).await
+}
+
+async fn rest_list_deleted_coffees(
+    _req: HttpRequest,
+    server: &CoffeeServer,
+) -> AppResult<Vec<Coffee>> {
+    server.authorize(&Methods::ListDeletedCoffees).await?;
+    server.list_deleted_coffees().await
+}
+
+async fn rest_restore_coffee(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let id = path_id(&req);
+    if let Err(err) = server.authorize(&Methods::RestoreCoffee(id.clone())).await {
+        return err.into_response();
+    }
+    with_etag(server.restore_coffee(id).await)
+}
+
+async fn rest_purge_coffee(req: HttpRequest, server: &CoffeeServer) -> AppResult<()> {
+    let id = path_id(&req);
+    server.authorize(&Methods::PurgeCoffee(id.clone())).await?;
+    server.purge_coffee(id).await
+}
+
+async fn rest_coffee_history(
+    req: HttpRequest,
+    server: &CoffeeServer,
+) -> AppResult<Vec<CoffeeChange>> {
+    let id = path_id(&req);
+    server
+        .authorize(&Methods::GetCoffeeHistory(id.clone()))
+        .await?;
+    server.get_coffee_history(id).await
+}
+
+/// Takes `{ "to_version": 3 }`.
+async fn rest_revert_coffee(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        match serde_json::from_slice(&req.body) {
+            Ok(fields) => fields,
+            Err(err) => return AppError::from(err).into_response(),
+        };
+    fields.insert("id".into(), path_id(&req).deref().into());
+    let revert: RevertCoffee = match serde_json::from_value(fields.into()) {
+        Ok(revert) => revert,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    if let Err(err) = server
+        .authorize(&Methods::RevertCoffee(revert.clone()))
+        .await
+    {
+        return err.into_response();
+    }
+    with_etag(server.revert_coffee(revert).await)
+}
+
+async fn rest_list_exchange_rates(
+    _req: HttpRequest,
+    server: &CoffeeServer,
+) -> AppResult<Vec<ExchangeRate>> {
+    server.authorize(&Methods::ListExchangeRates).await?;
+    server.list_exchange_rates().await
+}
+
+/// Takes `{ "rate": 1.08 }`.
+async fn rest_set_exchange_rate(req: HttpRequest, server: &CoffeeServer) -> Response {
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        match serde_json::from_slice(&req.body) {
+            Ok(fields) => fields,
+            Err(err) => return AppError::from(err).into_response(),
+        };
+    for name in ["from", "to"] {
+        fields.insert(name.into(), req.param(name).unwrap_or_default().into());
+    }
+    let rate: SetExchangeRate = match serde_json::from_value(fields.into()) {
+        Ok(rate) => rate,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    if let Err(err) = server
+        .authorize(&Methods::SetExchangeRate(rate.clone()))
+        .await
+    {
+        return err.into_response();
+    }
+    if let Err(errors) = rate.validate() {
+        return AppError::from(errors).into_response();
+    }
+    server.set_exchange_rate(rate).await.into_response()
+}
+
+fn path_id(req: &HttpRequest) -> Nanoid {
+    Nanoid::from(req.param("id").unwrap_or_default())
+}
+
+impl CoffeeRpc for CoffeeServer {
+    /// A key that is sent has to be valid, even for public methods, so a client
+    /// with a revoked key notices. Every call counts against the rate limits,
+    /// whether it's then allowed or not.
+    ///
+    /// Calls with an invalid key count against their peer address, in buckets of
+    /// their own that valid keys never touch, so junk keys sent from a shared
+    /// address can't lock out the valid keys behind it.
+    async fn authorize(&self, method: &Methods) -> AppResult<()> {
+        let principal = match self.authenticate().await {
+            Ok(principal) => principal,
+            Err(err) => {
+                if let Some(ctx) = RequestContext::current() {
+                    let client = format!("invalid_key:{}", ctx.peer.ip());
+                    self.rate_limiter
+                        .take(self.clock.as_ref(), &client, method.name())?;
+                }
+                return Err(err);
+            }
+        };
+        if let Some(ctx) = RequestContext::current() {
+            self.rate_limiter
+                .check(&self.db, self.clock.as_ref(), &ctx.client(), method.name())
+                .await?;
+        }
+        if method.is_public() {
+            return Ok(());
+        }
+        let Some(principal) = principal else {
+            return Err(AppError::Unauthorized(format!(
+                "{} needs an API key",
+                method.name()
+            )));
+        };
+        match method.permission() {
+            Some(permission) if !principal.has_permission(permission) => {
+                let message = format!(
+                    "{} needs the {permission} permission, which role {} doesn't grant",
+                    method.name(),
+                    principal.role
+                );
+                Err(AppError::Forbidden(message)
+                    .with_details(serde_json::json!({ "missing_permission": permission })))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_random_coffee(&self) -> AppResult<Coffee> {
+        let coffee = query_as!(
+            CoffeeRow,
+            "SELECT * FROM coffee_view
+            WHERE deleted_at IS NULL
+            ORDER BY RANDOM()
+            LIMIT 1 
+            "
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .map(Coffee::from)
+        .ok_or_else(|| AppError::NotFound("there are no coffees yet".into()))?;
+
+        Ok(coffee)
+    }
+
+    async fn add_coffee(&self, coffee: AddCoffeeParams) -> AppResult<Coffee> {
+        let id = Nanoid::new().to_string();
+        let now = self.clock.unix_now();
+        let currency = coffee.price.currency.deref();
+        let (roastery_id, farmer_id, origin_id) = (
+            coffee.roastery_id.deref(),
+            coffee.farmer_id.deref(),
+            coffee.origin_id.deref(),
+        );
+        self.check_references(Some(roastery_id), Some(farmer_id), Some(origin_id))
+            .await?;
+        let scope = Self::owner_scope();
+        let mut tx = self.begin_audited().await?;
+        let inserted = query!(
+            "INSERT INTO coffees
+                (id, roastery_id, icon, farmer_id, price_minor, price_currency, origin_id, created_at)
+            SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+            WHERE ?9 IS NULL
+                OR ?2 IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?9)",
+            id,
+            roastery_id,
+            coffee.icon,
+            farmer_id,
+            coffee.price.amount_minor,
+            currency,
+            origin_id,
+            now,
+            scope
+        )
+        .execute(&mut *tx)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            return Err(Self::not_acting_for(roastery_id));
+        }
+        let coffee = Self::view_coffee(&mut tx, &id)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Self::commit_audited(tx).await?;
+        self.events
+            .publish(CoffeeEventKind::Created, coffee.clone());
+        Ok(coffee)
+    }
+
+    async fn grab_id(&self, roastery_id: Nanoid, origin_id: Nanoid) -> AppResult<Nanoid> {
+        let (roastery_id, origin_id) = (roastery_id.deref(), origin_id.deref());
+        let id = query!(
+            "SELECT id FROM coffees
+            WHERE roastery_id = ? AND origin_id = ? AND deleted_at IS NULL",
+            roastery_id,
+            origin_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "no coffee from roastery {roastery_id} in origin {origin_id}"
+            ))
+        })?
+        .id;
+        Ok(Nanoid::from(id))
+    }
+
+    async fn grab_coffee(&self, id: Nanoid) -> AppResult<Coffee> {
+        let id = id.deref();
+        let coffee = query_as!(
+            CoffeeRow,
+            "SELECT * FROM coffee_view WHERE id = ? AND deleted_at IS NULL",
+            id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .map(Coffee::from)
+        .ok_or_else(|| AppError::NotFound(format!("coffee {id} not found")))?;
+        Ok(coffee)
+    }
+
+    async fn edit_coffee(&self, coffee: EditCoffee) -> AppResult<Coffee> {
+        let id = coffee.id.deref();
+        let price_minor = coffee.price.as_ref().map(|price| price.amount_minor);
+        let price_currency = coffee.price.as_ref().map(|price| price.currency.deref());
+        let (roastery_id, farmer_id, origin_id) = (
+            coffee.roastery_id.as_deref(),
+            coffee.farmer_id.as_deref(),
+            coffee.origin_id.as_deref(),
+        );
+        self.check_references(roastery_id, farmer_id, origin_id)
+            .await?;
+        let scope = Self::owner_scope();
+        let mut tx = self.begin_audited().await?;
+        let updated = query!(
+            "UPDATE coffees 
+            SET 
+                roastery_id = COALESCE(?1, roastery_id),
+                icon = COALESCE(?2, icon),
+                farmer_id = COALESCE(?3, farmer_id),
+                price_minor = COALESCE(?4, price_minor),
+                price_currency = COALESCE(?5, price_currency),
+                origin_id = COALESCE(?6, origin_id),
+                version = version + 1
+            WHERE id = ?7 AND deleted_at IS NULL AND (?8 IS NULL OR version = ?8)
+                AND (?9 IS NULL OR (
+                    roastery_id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?9)
+                    AND COALESCE(?1, roastery_id)
+                        IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?9)
+                ))
+            RETURNING id",
+            roastery_id,
+            coffee.icon,
+            farmer_id,
+            price_minor,
+            price_currency,
+            origin_id,
+            id,
+            coffee.expected_version,
+            scope
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let current = Self::view_coffee(&mut tx, id)
+            .await?
+            .filter(|current| current.deleted_at.is_none())
+            .ok_or_else(|| AppError::NotFound(format!("coffee {id} not found")))?;
+        let Some(_) = updated else {
+            // Either the key doesn't act for the coffee's roastery, old or new, or
+            // someone else got there first.
+            if let Some(key_id) = &scope {
+                let roasteries = [Some(current.roastery_id.deref()), roastery_id];
+                for roastery_id in roasteries.into_iter().flatten() {
+                    if !Self::acts_for(&mut tx, key_id, roastery_id).await? {
+                        return Err(Self::not_acting_for(roastery_id));
+                    }
+                }
+            }
+            let message = format!(
+                "coffee {id} is at version {}, not {}",
+                current.version,
+                coffee.expected_version.unwrap_or_default()
+            );
+            return Err(AppError::Conflict(message).with_details(current));
+        };
+        Self::commit_audited(tx).await?;
+        self.events
+            .publish(CoffeeEventKind::Updated, current.clone());
+        Ok(current)
+    }
+
+    async fn list_coffees(&self, query: ListCoffees) -> AppResult<CoffeePage> {
+        let limit = query.limit.unwrap_or(50).clamp(1, MAX_PAGE_SIZE) as usize;
+        let column = match query.sort {
+            CoffeeSort::Price => "price_minor",
+            CoffeeSort::Roastery => "roastery",
+            CoffeeSort::CreatedAt => "created_at",
+        };
+        let (direction, cmp) = match query.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        let sort = format!("{column} {direction}");
+        let cursor = query.cursor.as_deref().map(Cursor::decode).transpose()?;
+        if cursor.as_ref().is_some_and(|cursor| cursor.sort != sort) {
+            return Err(AppError::BadRequest(
+                "cursor was issued for a different sort".into(),
+            ));
+        }
+
+        let mut sql = QueryBuilder::new("SELECT * FROM coffee_view WHERE deleted_at IS NULL");
+        if let Some(roastery_id) = query.roastery_id {
+            sql.push(" AND roastery_id = ")
+                .push_bind(roastery_id.to_string());
+        }
+        if let Some(origin_id) = query.origin_id {
+            sql.push(" AND origin_id = ")
+                .push_bind(origin_id.to_string());
+        }
+        if let Some(farmer_id) = query.farmer_id {
+            sql.push(" AND farmer_id = ")
+                .push_bind(farmer_id.to_string());
+        }
+        if query.mine {
+            let principal = RequestContext::current()
+                .and_then(|ctx| ctx.principal().cloned())
+                .ok_or_else(|| AppError::Unauthorized("mine needs an API key".into()))?;
+            sql.push(
+                " AND roastery_id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ",
+            )
+            .push_bind(principal.key_id)
+            .push(")");
+        }
+        if let Some(currency) = query.currency {
+            sql.push(" AND price_currency = ")
+                .push_bind(currency.to_string());
+        }
+        if let Some(min_price) = query.min_price {
+            sql.push(" AND price_minor >= ").push_bind(min_price);
+        }
+        if let Some(max_price) = query.max_price {
+            sql.push(" AND price_minor <= ").push_bind(max_price);
+        }
+        // Keyset pagination: resume right after the last row of the previous page,
+        // with the id breaking ties so rows sharing a sort key are neither skipped
+        // nor repeated.
+        if let Some(cursor) = cursor {
+            sql.push(format!(" AND ({column}, id) {cmp} ("));
+            match cursor.key {
+                CursorKey::Int(key) => sql.push_bind(key),
+                CursorKey::Text(key) => sql.push_bind(key),
+            };
+            sql.push(", ").push_bind(cursor.id).push(")");
+        }
+        sql.push(format!(
+            " ORDER BY {column} {direction}, id {direction} LIMIT "
+        ))
+        .push_bind(limit as i64 + 1);
+
+        let mut coffees: Vec<Coffee> = sql.build_query_as().fetch_all(&self.db).await?;
+        let next_cursor = if coffees.len() > limit {
+            coffees.truncate(limit);
+            coffees.last().map(|last| {
+                let key = match query.sort {
+                    CoffeeSort::Price => CursorKey::Int(last.price.amount_minor),
+                    CoffeeSort::Roastery => CursorKey::Text(last.roastery.clone()),
+                    CoffeeSort::CreatedAt => CursorKey::Int(last.created_at),
+                };
+                Cursor {
+                    sort,
+                    key,
+                    id: last.id.to_string(),
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        if let Some(currency) = query.convert_to {
+            let rates = self.exchange_rates(currency).await?;
+            for coffee in &mut coffees {
+                coffee.converted_price = Some(rates.convert(&coffee.price)?);
+            }
+        }
+        Ok(CoffeePage {
+            coffees,
+            next_cursor,
+        })
+    }
+
+    async fn search_coffees(&self, search: SearchParams) -> AppResult<Vec<SearchHit>> {
+        let limit = search.limit.unwrap_or(20).clamp(1, MAX_PAGE_SIZE);
+        // Quote every word so FTS5 syntax in the input is matched literally, and
+        // make each one a prefix query.
+        let terms = search
+            .query
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            return Err(AppError::BadRequest("search query is empty".into()));
+        }
+        let terms = terms.join(" ");
+        // FTS5 tables can't be described at compile time, hence the runtime query.
+        // bm25 weighs roastery over farmer over origin and ranks better matches lower.
+        let mut hits: Vec<SearchHit> = query_as(
+            "SELECT coffee_view.*,
+                -bm25(coffees_fts, 0.0, 3.0, 2.0, 1.0) AS score,
+                snippet(coffees_fts, -1, '<mark>', '</mark>', '…', 12) AS snippet
+            FROM coffees_fts
+            JOIN coffee_view ON coffee_view.id = coffees_fts.id
+            WHERE coffees_fts MATCH ? AND coffee_view.deleted_at IS NULL
+            ORDER BY bm25(coffees_fts, 0.0, 3.0, 2.0, 1.0), coffee_view.id
+            LIMIT ?",
+        )
+        .bind(terms)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        if let Some(currency) = search.convert_to {
+            let rates = self.exchange_rates(currency).await?;
+            for SearchHit { coffee, .. } in &mut hits {
+                coffee.converted_price = Some(rates.convert(&coffee.price)?);
+            }
+        }
+        Ok(hits)
+    }
+
+    async fn delete_coffee(&self, id: Nanoid) -> AppResult<Coffee> {
+        let id = id.deref();
+        let now = self.clock.unix_now();
+        let scope = Self::owner_scope();
+        let mut tx = self.begin_audited().await?;
+        let deleted = query!(
+            "UPDATE coffees SET deleted_at = ?1, version = version + 1
+            WHERE id = ?2 AND deleted_at IS NULL
+                AND (?3 IS NULL
+                    OR roastery_id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?3))
+            RETURNING id",
+            now,
+            id,
+            scope
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if deleted.is_none() {
+            let coffee = Self::view_coffee(&mut tx, id)
+                .await?
+                .filter(|coffee| coffee.deleted_at.is_none());
+            return Err(match coffee {
+                Some(coffee) => Self::not_acting_for(&coffee.roastery_id),
+                None => AppError::NotFound(format!("coffee {id} not found")),
+            });
+        }
+        let coffee = Self::view_coffee(&mut tx, id)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Self::commit_audited(tx).await?;
+        self.events
+            .publish(CoffeeEventKind::Deleted, coffee.clone());
+        Ok(coffee)
+    }
+
+    async fn list_deleted_coffees(&self) -> AppResult<Vec<Coffee>> {
+        let coffees = query_as!(
+            CoffeeRow,
+            "SELECT * FROM coffee_view
+            WHERE deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC"
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(Coffee::from)
+        .collect();
+        Ok(coffees)
+    }
+
+    async fn restore_coffee(&self, id: Nanoid) -> AppResult<Coffee> {
+        let id = id.deref();
+        let scope = Self::owner_scope();
+        let mut tx = self.begin_audited().await?;
+        let restored = query!(
+            "UPDATE coffees SET deleted_at = NULL, version = version + 1
+            WHERE id = ?1 AND deleted_at IS NOT NULL
+                AND (?2 IS NULL
+                    OR roastery_id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?2))
+            RETURNING id",
+            id,
+            scope
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if restored.is_none() {
+            let coffee = Self::view_coffee(&mut tx, id)
+                .await?
+                .filter(|coffee| coffee.deleted_at.is_some());
+            return Err(match coffee {
+                Some(coffee) => Self::not_acting_for(&coffee.roastery_id),
+                None => AppError::NotFound(format!("coffee {id} is not in the trash")),
+            });
+        }
+        let coffee = Self::view_coffee(&mut tx, id)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Self::commit_audited(tx).await?;
+        self.events
+            .publish(CoffeeEventKind::Restored, coffee.clone());
+        Ok(coffee)
+    }
+
+    async fn purge_coffee(&self, id: Nanoid) -> AppResult<()> {
+        let id = id.deref();
+        let scope = Self::owner_scope();
+        let mut tx = self.begin_audited().await?;
+        // Read before deleting, the view can't show a row that's gone.
+        let coffee = Self::view_coffee(&mut tx, id)
+            .await?
+            .filter(|coffee| coffee.deleted_at.is_some())
+            .ok_or_else(|| AppError::NotFound(format!("coffee {id} is not in the trash")))?;
+        let purged = query!(
+            "DELETE FROM coffees
+            WHERE id = ?1
+                AND (?2 IS NULL
+                    OR roastery_id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?2))",
+            id,
+            scope
+        )
+        .execute(&mut *tx)
+        .await?;
+        if purged.rows_affected() == 0 {
+            return Err(Self::not_acting_for(&coffee.roastery_id));
+        }
+        Self::commit_audited(tx).await?;
+        self.events.publish(CoffeeEventKind::Purged, coffee);
+        Ok(())
+    }
+
+    async fn get_coffee_history(&self, id: Nanoid) -> AppResult<Vec<CoffeeChange>> {
+        let id = id.deref();
+        let changes = query!(
+            r#"SELECT
+                seq AS "seq!",
+                coffee_id AS "coffee_id: Nanoid",
+                version,
+                action AS "action: ChangeAction",
+                before AS "before: Json<Coffee>",
+                after AS "after: Json<Coffee>",
+                actor,
+                changed_at
+            FROM coffee_history
+            WHERE coffee_id = ?
+            ORDER BY seq"#,
+            id
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|row| CoffeeChange {
+            seq: row.seq,
+            coffee_id: row.coffee_id,
+            version: row.version,
+            action: row.action,
+            before: row.before.map(|before| before.0),
+            after: row.after.map(|after| after.0),
+            actor: row.actor,
+            changed_at: row.changed_at,
+        })
+        .collect::<Vec<_>>();
+        if changes.is_empty() {
+            return Err(AppError::NotFound(format!("coffee {id} has no history")));
+        }
+        Ok(changes)
+    }
+
+    async fn revert_coffee(&self, revert: RevertCoffee) -> AppResult<Coffee> {
+        let id = revert.id.deref();
+        let snapshot = query!(
+            r#"SELECT after AS "after!: Json<Coffee>" FROM coffee_history
+            WHERE coffee_id = ? AND version = ? AND after IS NOT NULL"#,
+            id,
+            revert.to_version
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!("coffee {id} has no version {}", revert.to_version))
+        })?
+        .after
+        .0;
+        // Goes through the regular edit so the revert is versioned and audited
+        // like any other change.
+        self.edit_coffee(EditCoffee {
+            id: revert.id,
+            roastery_id: Some(snapshot.roastery_id),
+            icon: Some(snapshot.icon),
+            farmer_id: Some(snapshot.farmer_id),
+            price: Some(snapshot.price),
+            origin_id: Some(snapshot.origin_id),
+            expected_version: None,
+        })
+        .await
+    }
+
+    async fn set_exchange_rate(&self, rate: SetExchangeRate) -> AppResult<ExchangeRate> {
+        let (from, to) = (rate.from.deref(), rate.to.deref());
+        if from == to {
+            return Err(AppError::Unprocessable(format!(
+                "can't set a rate from {from} to itself"
+            )));
+        }
+        let now = self.clock.unix_now();
+        query!(
+            "INSERT INTO exchange_rates (from_currency, to_currency, rate, updated_at)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (from_currency, to_currency)
+            DO UPDATE SET rate = excluded.rate, updated_at = excluded.updated_at",
+            from,
+            to,
+            rate.rate,
+            now
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(ExchangeRate {
+            from: rate.from,
+            to: rate.to,
+            rate: rate.rate,
+            updated_at: now,
+        })
+    }
+
+    async fn list_exchange_rates(&self) -> AppResult<Vec<ExchangeRate>> {
+        let rates = query!(
+            "SELECT from_currency, to_currency, rate, updated_at FROM exchange_rates
+            ORDER BY from_currency, to_currency"
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|row| ExchangeRate {
+            from: Currency::from_db(row.from_currency),
+            to: Currency::from_db(row.to_currency),
+            rate: row.rate,
+            updated_at: row.updated_at,
+        })
+        .collect();
+        Ok(rates)
+    }
+
+    async fn add_roastery(&self, roastery: AddRoastery) -> AppResult<Roastery> {
+        let id = Nanoid::new().to_string();
+        let now = self.clock.unix_now();
+        let inserted = query_as!(
+            Roastery,
+            "INSERT INTO roasteries (id, name, website, contact, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING *",
+            id,
+            roastery.name,
+            roastery.website,
+            roastery.contact,
+            now
+        )
+        .fetch_one(&self.db)
+        .await;
+        let existing = query_scalar!("SELECT id FROM roasteries WHERE name = ?", roastery.name)
+            .fetch_optional(&self.db);
+        name_taken(inserted, "roastery", Some(&roastery.name), existing).await
+    }
+
+    async fn grab_roastery(&self, id: Nanoid) -> AppResult<Roastery> {
+        let id = id.deref();
+        let roastery = query_as!(Roastery, "SELECT * FROM roasteries WHERE id = ?", id)
+            .fetch_optional(&self.db)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("roastery {id} not found")))?;
+        Ok(roastery)
+    }
+
+    async fn list_roasteries(&self) -> AppResult<Vec<Roastery>> {
+        let roasteries = query_as!(Roastery, "SELECT * FROM roasteries ORDER BY name, id")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(roasteries)
+    }
+
+    async fn edit_roastery(&self, roastery: EditRoastery) -> AppResult<Roastery> {
+        let id = roastery.id.deref();
+        let scope = Self::owner_scope();
+        let edited = query_as!(
+            Roastery,
+            "UPDATE roasteries
+            SET
+                name = COALESCE(?1, name),
+                website = COALESCE(?2, website),
+                contact = COALESCE(?3, contact)
+            WHERE id = ?4
+                AND (?5 IS NULL
+                    OR id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?5))
+            RETURNING *",
+            roastery.name,
+            roastery.website,
+            roastery.contact,
+            id,
+            scope
+        )
+        .fetch_optional(&self.db)
+        .await;
+        let existing = query_scalar!("SELECT id FROM roasteries WHERE name = ?", roastery.name)
+            .fetch_optional(&self.db);
+        let edited = name_taken(edited, "roastery", roastery.name.as_deref(), existing).await?;
+        match edited {
+            Some(roastery) => Ok(roastery),
+            None => Err(self.roastery_refused(id).await?),
+        }
+    }
+
+    async fn delete_roastery(&self, id: Nanoid) -> AppResult<Roastery> {
+        let id = id.deref();
+        let mut tx = self.db.begin().await?;
+        // Checked first, so keys acting for other roasteries learn nothing of its
+        // coffees.
+        let scope = Self::owner_scope();
+        if let Some(key_id) = &scope {
+            if !Self::acts_for(&mut tx, key_id, id).await? {
+                drop(tx);
+                return Err(self.roastery_refused(id).await?);
+            }
+        }
+        let coffees = query_scalar!("SELECT COUNT(*) FROM coffees WHERE roastery_id = ?", id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if coffees > 0 {
+            return Err(AppError::Conflict(format!(
+                "roastery {id} still has {coffees} coffees"
+            )));
+        }
+        let deleted = query_as!(
+            Roastery,
+            "DELETE FROM roasteries
+            WHERE id = ?1
+                AND (?2 IS NULL
+                    OR id IN (SELECT roastery_id FROM api_key_roasteries WHERE key_id = ?2))
+            RETURNING *",
+            id,
+            scope
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(roastery) = deleted else {
+            drop(tx);
+            return Err(self.roastery_refused(id).await?);
+        };
+        tx.commit().await?;
+        Ok(roastery)
+    }
+
+    async fn merge_roasteries(&self, id: Nanoid, into: Nanoid) -> AppResult<Roastery> {
+        let (id, into) = (id.deref(), into.deref());
+        let mut tx = self.begin_audited().await?;
+        let moved =
+            Self::move_coffees(&mut tx, ("roastery", "roasteries", "roastery_id"), id, into)
+                .await?;
+        query!(
+            "INSERT OR IGNORE INTO api_key_roasteries (key_id, roastery_id)
+            SELECT key_id, ?1 FROM api_key_roasteries WHERE roastery_id = ?2",
+            into,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!("DELETE FROM roasteries WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        Self::commit_audited(tx).await?;
+        self.publish_moved(moved);
+        self.grab_roastery(into.into()).await
+    }
+
+    async fn add_farmer(&self, farmer: AddFarmer) -> AppResult<Farmer> {
+        let id = Nanoid::new().to_string();
+        let now = self.clock.unix_now();
+        let inserted = query_as!(
+            Farmer,
+            "INSERT INTO farmers (id, name, region, contact, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING *",
+            id,
+            farmer.name,
+            farmer.region,
+            farmer.contact,
+            now
+        )
+        .fetch_one(&self.db)
+        .await;
+        let existing = query_scalar!("SELECT id FROM farmers WHERE name = ?", farmer.name)
+            .fetch_optional(&self.db);
+        let farmer = name_taken(inserted, "farmer", Some(&farmer.name), existing).await?;
+        Ok(farmer)
+    }
+
+    async fn grab_farmer(&self, id: Nanoid) -> AppResult<Farmer> {
+        let id = id.deref();
+        let farmer = query_as!(Farmer, "SELECT * FROM farmers WHERE id = ?", id)
+            .fetch_optional(&self.db)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("farmer {id} not found")))?;
+        Ok(farmer)
+    }
+
+    async fn list_farmers(&self) -> AppResult<Vec<Farmer>> {
+        let farmers = query_as!(Farmer, "SELECT * FROM farmers ORDER BY name, id")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(farmers)
+    }
+
+    async fn edit_farmer(&self, farmer: EditFarmer) -> AppResult<Farmer> {
+        let id = farmer.id.deref();
+        let edited = query_as!(
+            Farmer,
+            "UPDATE farmers
+            SET
+                name = COALESCE(?1, name),
+                region = COALESCE(?2, region),
+                contact = COALESCE(?3, contact)
+            WHERE id = ?4
+            RETURNING *",
+            farmer.name,
+            farmer.region,
+            farmer.contact,
+            id
+        )
+        .fetch_optional(&self.db)
+        .await;
+        let existing = query_scalar!("SELECT id FROM farmers WHERE name = ?", farmer.name)
+            .fetch_optional(&self.db);
+        let farmer = name_taken(edited, "farmer", farmer.name.as_deref(), existing)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("farmer {id} not found")))?;
+        Ok(farmer)
+    }
+
+    async fn delete_farmer(&self, id: Nanoid) -> AppResult<Farmer> {
+        let id = id.deref();
+        let mut tx = self.db.begin().await?;
+        let coffees = query_scalar!("SELECT COUNT(*) FROM coffees WHERE farmer_id = ?", id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if coffees > 0 {
+            return Err(AppError::Conflict(format!(
+                "farmer {id} still has {coffees} coffees"
+            )));
+        }
+        let farmer = query_as!(Farmer, "DELETE FROM farmers WHERE id = ? RETURNING *", id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("farmer {id} not found")))?;
+        tx.commit().await?;
+        Ok(farmer)
+    }
+
+    async fn merge_farmers(&self, id: Nanoid, into: Nanoid) -> AppResult<Farmer> {
+        let (id, into) = (id.deref(), into.deref());
+        let mut tx = self.begin_audited().await?;
+        let moved =
+            Self::move_coffees(&mut tx, ("farmer", "farmers", "farmer_id"), id, into).await?;
+        query!("DELETE FROM farmers WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        Self::commit_audited(tx).await?;
+        self.publish_moved(moved);
+        self.grab_farmer(into.into()).await
+    }
+
+    async fn add_origin(&self, origin: AddOrigin) -> AppResult<Origin> {
+        let id = Nanoid::new().to_string();
+        let now = self.clock.unix_now();
+        let inserted = query_as!(
+            Origin,
+            "INSERT INTO origins (id, name, region, country, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING *",
+            id,
+            origin.name,
+            origin.region,
+            origin.country,
+            now
+        )
+        .fetch_one(&self.db)
+        .await;
+        let existing = query_scalar!("SELECT id FROM origins WHERE name = ?", origin.name)
+            .fetch_optional(&self.db);
+        name_taken(inserted, "origin", Some(&origin.name), existing).await
+    }
+
+    async fn grab_origin(&self, id: Nanoid) -> AppResult<Origin> {
+        let id = id.deref();
+        let origin = query_as!(Origin, "SELECT * FROM origins WHERE id = ?", id)
+            .fetch_optional(&self.db)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("origin {id} not found")))?;
+        Ok(origin)
+    }
+
+    async fn list_origins(&self) -> AppResult<Vec<Origin>> {
+        let origins = query_as!(Origin, "SELECT * FROM origins ORDER BY name, id")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(origins)
+    }
+
+    async fn edit_origin(&self, origin: EditOrigin) -> AppResult<Origin> {
+        let id = origin.id.deref();
+        let edited = query_as!(
+            Origin,
+            "UPDATE origins
+            SET
+                name = COALESCE(?1, name),
+                region = COALESCE(?2, region),
+                country = COALESCE(?3, country)
+            WHERE id = ?4
+            RETURNING *",
+            origin.name,
+            origin.region,
+            origin.country,
+            id
+        )
+        .fetch_optional(&self.db)
+        .await;
+        let existing = query_scalar!("SELECT id FROM origins WHERE name = ?", origin.name)
+            .fetch_optional(&self.db);
+        let origin = name_taken(edited, "origin", origin.name.as_deref(), existing)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("origin {id} not found")))?;
+        Ok(origin)
+    }
+
+    async fn delete_origin(&self, id: Nanoid) -> AppResult<Origin> {
+        let id = id.deref();
+        let mut tx = self.db.begin().await?;
+        let coffees = query_scalar!("SELECT COUNT(*) FROM coffees WHERE origin_id = ?", id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if coffees > 0 {
+            return Err(AppError::Conflict(format!(
+                "origin {id} still has {coffees} coffees"
+            )));
+        }
+        let origin = query_as!(Origin, "DELETE FROM origins WHERE id = ? RETURNING *", id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("origin {id} not found")))?;
+        tx.commit().await?;
+        Ok(origin)
+    }
+
+    async fn merge_origins(&self, id: Nanoid, into: Nanoid) -> AppResult<Origin> {
+        let (id, into) = (id.deref(), into.deref());
+        let mut tx = self.begin_audited().await?;
+        let moved =
+            Self::move_coffees(&mut tx, ("origin", "origins", "origin_id"), id, into).await?;
+        query!("DELETE FROM origins WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        Self::commit_audited(tx).await?;
+        self.publish_moved(moved);
+        self.grab_origin(into.into()).await
+    }
+
+    async fn create_api_key(&self, key: CreateApiKey) -> AppResult<IssuedApiKey> {
+        self.check_role(&key.role).await?;
+        let id = Nanoid::new().to_string();
+        let now = self.clock.unix_now();
+        let generated = GeneratedKey::new();
+        let api_key = query_as!(
+            ApiKey,
+            "INSERT INTO api_keys (id, name, role, key_hash, prefix, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            RETURNING id, name, role, prefix, created_at, rotated_at, revoked_at",
+            id,
+            key.name,
+            key.role,
+            generated.hash,
+            generated.prefix,
+            now
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(IssuedApiKey {
+            api_key,
+            key: generated.key,
+        })
+    }
+
+    async fn list_api_keys(&self) -> AppResult<Vec<ApiKey>> {
+        let keys = query_as!(
+            ApiKey,
+            "SELECT id, name, role, prefix, created_at, rotated_at, revoked_at FROM api_keys
+            ORDER BY created_at, id"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(keys)
+    }
+
+    async fn rotate_api_key(&self, id: Nanoid) -> AppResult<IssuedApiKey> {
+        let id = id.deref();
+        let now = self.clock.unix_now();
+        let generated = GeneratedKey::new();
+        let api_key = query_as!(
+            ApiKey,
+            "UPDATE api_keys SET key_hash = ?, prefix = ?, rotated_at = ?
+            WHERE id = ? AND revoked_at IS NULL
+            RETURNING id, name, role, prefix, created_at, rotated_at, revoked_at",
+            generated.hash,
+            generated.prefix,
+            now,
+            id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("no live API key {id}")))?;
+        Ok(IssuedApiKey {
+            api_key,
+            key: generated.key,
+        })
+    }
+
+    async fn revoke_api_key(&self, id: Nanoid) -> AppResult<ApiKey> {
+        let id = id.deref();
+        let now = self.clock.unix_now();
+        let api_key = query_as!(
+            ApiKey,
+            "UPDATE api_keys SET revoked_at = ?
+            WHERE id = ? AND revoked_at IS NULL
+            RETURNING id, name, role, prefix, created_at, rotated_at, revoked_at",
+            now,
+            id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("no live API key {id}")))?;
+        Ok(api_key)
+    }
+
+    async fn list_roles(&self) -> AppResult<Vec<Role>> {
+        let mut roles = Vec::<Role>::new();
+        let grants = query!(
+            r#"SELECT roles.name, role_permissions.permission AS "permission?"
+            FROM roles
+            LEFT JOIN role_permissions ON role_permissions.role = roles.name
+            ORDER BY roles.name, role_permissions.permission"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        for grant in grants {
+            if roles.last().is_none_or(|role| role.name != grant.name) {
+                roles.push(Role {
+                    name: grant.name,
+                    permissions: Vec::new(),
+                });
+            }
+            let role = roles.last_mut().unwrap();
+            role.permissions.extend(grant.permission);
+        }
+        Ok(roles)
+    }
+
+    async fn set_role(&self, role: SetRole) -> AppResult<Role> {
+        let name = role.name.as_str();
+        let mut tx = self.db.begin().await?;
+        query!("INSERT OR IGNORE INTO roles (name) VALUES (?)", name)
+            .execute(&mut *tx)
+            .await?;
+        query!("DELETE FROM role_permissions WHERE role = ?", name)
+            .execute(&mut *tx)
+            .await?;
+        for permission in &role.permissions {
+            query!(
+                "INSERT OR IGNORE INTO role_permissions (role, permission) VALUES (?, ?)",
+                name,
+                permission
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        let mut permissions = role.permissions;
+        permissions.sort();
+        permissions.dedup();
+        Ok(Role {
+            name: role.name,
+            permissions,
+        })
+    }
+
+    async fn set_api_key_role(&self, id: Nanoid, role: String) -> AppResult<ApiKey> {
+        self.check_role(&role).await?;
+        let id = id.deref();
+        let api_key = query_as!(
+            ApiKey,
+            "UPDATE api_keys SET role = ?
+            WHERE id = ? AND revoked_at IS NULL
+            RETURNING id, name, role, prefix, created_at, rotated_at, revoked_at",
+            role,
+            id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("no live API key {id}")))?;
+        Ok(api_key)
+    }
+
+    async fn assign_roastery(
+        &self,
+        key_id: Nanoid,
+        roastery_id: Nanoid,
+    ) -> AppResult<Vec<Roastery>> {
+        self.check_references(Some(&roastery_id), None, None)
+            .await?;
+        let (key, roastery) = (key_id.deref(), roastery_id.deref());
+        let assigned = query!(
+            "INSERT OR IGNORE INTO api_key_roasteries (key_id, roastery_id)
+            SELECT id, ? FROM api_keys WHERE id = ?",
+            roastery,
+            key
+        )
+        .execute(&self.db)
+        .await?;
+        if assigned.rows_affected() == 0 {
+            // Either the key is missing or it acts for the roastery already.
+            self.check_api_key(key).await?;
+        }
+        self.list_key_roasteries(key_id).await
+    }
+
+    async fn unassign_roastery(
+        &self,
+        key_id: Nanoid,
+        roastery_id: Nanoid,
+    ) -> AppResult<Vec<Roastery>> {
+        let (key, roastery) = (key_id.deref(), roastery_id.deref());
+        let unassigned = query!(
+            "DELETE FROM api_key_roasteries WHERE key_id = ? AND roastery_id = ?",
+            key,
+            roastery
+        )
+        .execute(&self.db)
+        .await?;
+        if unassigned.rows_affected() == 0 {
+            self.check_api_key(key).await?;
+            return Err(AppError::NotFound(format!(
+                "API key {key} doesn't act for roastery {roastery}"
+            )));
+        }
+        self.list_key_roasteries(key_id).await
+    }
+
+    async fn list_key_roasteries(&self, key_id: Nanoid) -> AppResult<Vec<Roastery>> {
+        let key = key_id.deref();
+        self.check_api_key(key).await?;
+        let roasteries = query_as!(
+            Roastery,
+            "SELECT roasteries.* FROM roasteries
+            JOIN api_key_roasteries ON api_key_roasteries.roastery_id = roasteries.id
+            WHERE api_key_roasteries.key_id = ?
+            ORDER BY roasteries.name, roasteries.id",
+            key
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(roasteries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db_test_rs::{http::RequestParser, state::FixedClock};
+    use serde_json::json;
+
+    use super::*;
+
+    const NOW: i64 = 1_790_000_000;
+
+    /// A server on a fresh in-memory database whose clock reads `now`.
+    async fn server_at(now: i64) -> CoffeeServer {
+        // Every in-memory connection is its own database, so keep exactly one.
+        let config = ServerConfig {
+            database_url: "sqlite::memory:".into(),
+            min_connections: 1,
+            max_connections: 1,
+            ..ServerConfig::default()
+        };
+        let db = db::connect(&config).await.unwrap();
+        CoffeeServer(AppState::new(db, config).with_clock(FixedClock(now)))
+    }
+
+    /// The same database seen through a clock reading `now`.
+    fn at(server: &CoffeeServer, now: i64) -> CoffeeServer {
+        CoffeeServer(server.0.clone().with_clock(FixedClock(now)))
+    }
+
+    /// The context of a request from `peer`, sending `key` if any.
+    fn request(peer: &str, key: Option<&str>) -> RequestContext {
+        let authorization = key.map_or_else(String::new, |key| {
+            format!("Authorization: Bearer {key}\r\n")
+        });
+        let mut parser = RequestParser::default();
+        parser.feed(format!("POST /rpc HTTP/1.1\r\n{authorization}\r\n").as_bytes());
+        let req = parser.next_request().unwrap().unwrap();
+        RequestContext::new(peer.parse().unwrap(), &req)
+    }
+
+    /// Params read from `value` the way dispatch reads them, validation included,
+    /// so tests don't call methods with data the API would refuse.
+    fn params<T: serde::de::DeserializeOwned + Validate>(value: serde_json::Value) -> T {
+        let params: T = serde_json::from_value(value).unwrap();
+        if let Err(errors) = params.validate() {
+            panic!("invalid params: {errors:?}");
+        }
+        params
+    }
+
+    /// Sends a request through the server's routes from 10.0.0.1. `head` is the
+    /// request line, e.g. `GET /coffees`, optionally followed by header lines.
+    async fn send(server: &CoffeeServer, key: Option<&str>, head: &str, body: &str) -> Response {
+        let (line, headers) = head.split_once('\n').unwrap_or((head, ""));
+        let mut text = format!("{line} HTTP/1.1\r\nContent-Length: {}\r\n", body.len());
+        for header in headers.lines() {
+            text.push_str(&format!("{header}\r\n"));
+        }
+        if let Some(key) = key {
+            text.push_str(&format!("Authorization: Bearer {key}\r\n"));
+        }
+        text.push_str("\r\n");
+        text.push_str(body);
+        let mut parser = RequestParser::default();
+        parser.feed(text.as_bytes());
+        let req = parser.next_request().unwrap().unwrap();
+        let ctx = RequestContext::new("10.0.0.1:4000".parse().unwrap(), &req);
+        ctx.scope(router(&server.config).dispatch(req, server))
+            .await
+    }
+
+    fn header<'a>(res: &'a Response, name: &str) -> Option<&'a str> {
+        res.headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json_body(res: &Response) -> serde_json::Value {
+        serde_json::from_str(&res.body).unwrap()
+    }
+
+    async fn issue_key(server: &CoffeeServer, role: &str) -> IssuedApiKey {
+        let params = CreateApiKey {
+            name: format!("{role} key"),
+            role: role.into(),
+        };
+        server.create_api_key(params).await.unwrap()
+    }
+
+    async fn add_coffee(server: &CoffeeServer) -> Coffee {
+        let roastery = server
+            .add_roastery(params(json!({ "name": "Onyx" })))
+            .await
+            .unwrap();
+        let farmer = server
+            .add_farmer(params(json!({ "name": "Tadesse" })))
+            .await
+            .unwrap();
+        let origin = server
+            .add_origin(params(json!({ "name": "Guji", "country": "Ethiopia" })))
+            .await
+            .unwrap();
+        let coffee = params(json!({
+            "roastery_id": roastery.id,
+            "icon": "☕",
+            "farmer_id": farmer.id,
+            "price": { "amount_minor": 1850, "currency": "EUR" },
+            "origin_id": origin.id,
+        }));
+        server.add_coffee(coffee).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn coffees_are_stamped_with_the_clock() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        assert_eq!(coffee.created_at, NOW);
+        assert_eq!(coffee.deleted_at, None);
+
+        let history = server.get_coffee_history(coffee.id.clone()).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].changed_at, NOW);
+        assert_eq!(history[0].actor, context::SYSTEM_ACTOR);
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_conflict_with_the_existing_entity() {
+        let server = server_at(NOW).await;
+        let onyx = server
+            .add_roastery(params(json!({ "name": "Onyx" })))
+            .await
+            .unwrap();
+        let err = server
+            .add_roastery(params(json!({ "name": "onyx" })))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+        let body = err.body();
+        assert_eq!(body.message, "a roastery named onyx already exists");
+        assert_eq!(body.details, Some(json!({ "existing_id": onyx.id })));
+
+        let other = server
+            .add_roastery(params(json!({ "name": "Miga" })))
+            .await
+            .unwrap();
+        let rename = json!({ "id": other.id, "name": "ONYX" });
+        let err = server.edit_roastery(params(rename)).await.unwrap_err();
+        assert_eq!(err.to_string(), "a roastery named ONYX already exists");
+    }
+
+    #[tokio::test]
+    async fn merging_roasteries_moves_coffees_and_keys() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        server.delete_coffee(coffee.id.clone()).await.unwrap();
+        let duplicate = coffee.roastery_id.clone();
+        let kept = server
+            .add_roastery(params(json!({ "name": "Onyx Coffee Lab" })))
+            .await
+            .unwrap();
+        let issued = issue_key(&server, "roastery").await;
+        let key_id = issued.api_key.id.clone();
+        server
+            .assign_roastery(key_id.clone(), duplicate.clone())
+            .await
+            .unwrap();
+
+        let err = server
+            .merge_roasteries(kept.id.clone(), kept.id.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::BadRequest);
+        let err = server
+            .merge_roasteries("missing".into(), kept.id.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let merged = server
+            .merge_roasteries(duplicate.clone(), kept.id.clone())
+            .await
+            .unwrap();
+        assert_eq!(merged.name, "Onyx Coffee Lab");
+        // Trashed coffees move too, under a new version.
+        let trash = server.list_deleted_coffees().await.unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(&*trash[0].roastery_id, &*kept.id);
+        assert_eq!(trash[0].roastery, "Onyx Coffee Lab");
+        assert_eq!(trash[0].version, coffee.version + 2);
+        let roasteries = server.list_key_roasteries(key_id).await.unwrap();
+        assert_eq!(roasteries.len(), 1);
+        assert_eq!(&*roasteries[0].id, &*kept.id);
+        let err = server.grab_roastery(duplicate).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn coffees_are_rest_resources() {
+        let server = server_at(NOW).await;
+        let admin = issue_key(&server, "admin").await;
+        let template = add_coffee(&server).await;
+        let body = json!({
+            "roastery_id": template.roastery_id,
+            "icon": "🫘",
+            "farmer_id": template.farmer_id,
+            "price": { "amount_minor": 2200, "currency": "EUR" },
+            "origin_id": template.origin_id,
+        })
+        .to_string();
+        let res = send(&server, None, "POST /coffees", &body).await;
+        assert_eq!(res.status, "401 Unauthorized");
+        let res = send(&server, Some(&admin.key), "POST /coffees", "{").await;
+        assert_eq!(res.status, "400 Bad Request");
+
+        let res = send(&server, Some(&admin.key), "POST /coffees", &body).await;
+        assert_eq!(res.status, "201 Created");
+        let created = json_body(&res);
+        let location = format!("/coffees/{}", created["id"].as_str().unwrap());
+        assert_eq!(header(&res, "Location"), Some(location.as_str()));
+        let res = send(&server, None, &format!("GET {location}"), "").await;
+        assert_eq!(res.status, "200 OK");
+        assert_eq!(json_body(&res), created);
+        assert_eq!(header(&res, "ETag"), Some("\"1\""));
+
+        let res = send(&server, None, "GET /coffees?sort=price&order=desc", "").await;
+        let page = json_body(&res);
+        assert_eq!(page["coffees"][0], created);
+        assert_eq!(page["coffees"].as_array().unwrap().len(), 2);
+        let res = send(&server, None, "GET /coffees?colour=brown", "").await;
+        assert_eq!(res.status, "400 Bad Request");
+        let res = send(&server, None, "GET /coffees/random", "").await;
+        assert_eq!(res.status, "200 OK");
+
+        let res = send(&server, Some(&admin.key), &format!("DELETE {location}"), "").await;
+        assert_eq!(res.status, "200 OK");
+        let res = send(&server, None, &format!("GET {location}"), "").await;
+        assert_eq!(res.status, "404 Not Found");
+        let res = send(&server, Some(&admin.key), "GET /coffees/deleted", "").await;
+        assert_eq!(json_body(&res)[0]["id"], created["id"]);
+    }
+
+    #[tokio::test]
+    async fn rest_edits_are_authorized_before_they_are_validated() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let editor = issue_key(&server, "editor").await;
+        let path = format!("PATCH /coffees/{}", &*coffee.id);
+        let body = r#"{"icon":""}"#;
+        let res = send(&server, None, &path, body).await;
+        assert_eq!(res.status, "401 Unauthorized");
+        let res = send(&server, Some(&editor.key), &path, body).await;
+        assert_eq!(res.status, "422 Unprocessable Entity");
+    }
+
+    #[tokio::test]
+    async fn roastery_keys_only_write_their_own_coffees() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let issued = issue_key(&server, "roastery").await;
+        let id = issued.api_key.id.clone();
+        server
+            .assign_roastery(id, coffee.roastery_id.clone())
+            .await
+            .unwrap();
+
+        let ctx = request("127.0.0.1:4000", Some(&issued.key));
+        let (own_edit, catalog_edits) = ctx
+            .scope(async {
+                let edit: EditCoffee = params(json!({ "id": coffee.id, "icon": "🔥" }));
+                server
+                    .authorize(&Methods::EditCoffee(edit.clone()))
+                    .await
+                    .unwrap();
+                let own_edit = server.edit_coffee(edit).await;
+                let farmer = json!({ "id": coffee.farmer_id, "name": "Hijacked" });
+                let rate = json!({ "from": "EUR", "to": "USD", "rate": 1000.0 });
+                let mut catalog_edits = Vec::new();
+                for method in [
+                    Methods::EditFarmer(params(farmer)),
+                    Methods::DeleteOrigin(coffee.origin_id.clone()),
+                    Methods::SetExchangeRate(params(rate)),
+                    Methods::AddRoastery(params(json!({ "name": "Mine" }))),
+                ] {
+                    catalog_edits.push(server.authorize(&method).await.unwrap_err());
+                }
+                (own_edit, catalog_edits)
+            })
+            .await;
+        assert_eq!(own_edit.unwrap().icon, "🔥");
+        for err in catalog_edits {
+            assert_eq!(err.code(), ErrorCode::Forbidden, "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn roastery_keys_cant_probe_other_roasteries() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let own = server
+            .add_roastery(params(json!({ "name": "Miga" })))
+            .await
+            .unwrap();
+        let issued = issue_key(&server, "roastery").await;
+        server
+            .assign_roastery(issued.api_key.id.clone(), own.id.clone())
+            .await
+            .unwrap();
+        let delete = |id: Nanoid| {
+            let server = server.clone();
+            request("127.0.0.1:4000", Some(&issued.key)).scope(async move {
+                server
+                    .authorize(&Methods::DeleteRoastery(id.clone()))
+                    .await?;
+                server.delete_roastery(id).await
+            })
+        };
+        let err = delete(coffee.roastery_id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Forbidden, "{err}");
+        let err = delete("missing".into()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound, "{err}");
+        let deleted = delete(own.id.clone()).await.unwrap();
+        assert_eq!(deleted.name, "Miga");
+    }
+
+    #[tokio::test]
+    async fn invalid_keys_are_rate_limited_by_address() {
+        let server = server_at(NOW).await;
+        let limits = db_test_rs::config::RateLimits {
+            default: serde_json::from_value(json!({ "burst": 2, "per_second": 0.1 })).unwrap(),
+            methods: Default::default(),
+        };
+        let server = CoffeeServer(AppState {
+            rate_limiter: std::sync::Arc::new(db_test_rs::ratelimit::RateLimiter::new(limits)),
+            ..server.0
+        });
+        let valid = issue_key(&server, "viewer").await;
+
+        let call = |key: String| {
+            let server = server.clone();
+            request("10.0.0.1:4000", Some(&key))
+                .scope(async move { server.authorize(&Methods::ListDeletedCoffees).await })
+        };
+        for _ in 0..2 {
+            let err = call("ck_guess".into()).await.unwrap_err();
+            assert_eq!(err.code(), ErrorCode::Unauthorized);
+        }
+        let err = call("ck_guess".into()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::TooManyRequests);
+        // Valid keys have buckets of their own, however many guesses were made
+        // from their address.
+        call(valid.key.clone()).await.unwrap();
+        call(valid.key.clone()).await.unwrap();
+        // Guesses from other addresses are still counted apart.
+        let other = request("10.0.0.2:4000", Some("ck_guess"));
+        let err = other
+            .scope(server.authorize(&Methods::ListDeletedCoffees))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn event_streams_only_show_the_trash_to_readers() {
+        use futures_util::StreamExt;
+
+        let server = server_at(NOW).await;
+        let reader = issue_key(&server, "viewer").await;
+        let err = send(&server, Some("ck_guess"), "GET /events", "").await;
+        assert_eq!(err.status, "401 Unauthorized");
+        let mut anonymous = send(&server, None, "GET /events", "").await.stream.unwrap();
+        let mut with_read = send(&server, Some(&reader.key), "GET /events", "")
+            .await
+            .stream
+            .unwrap();
+
+        let coffee = add_coffee(&server).await;
+        server.delete_coffee(coffee.id.clone()).await.unwrap();
+        server.restore_coffee(coffee.id.clone()).await.unwrap();
+        let event = |message: Option<String>| message.unwrap().lines().nth(1).unwrap().to_string();
+        for kind in ["created", "deleted", "restored"] {
+            assert_eq!(event(with_read.next().await), format!("event: {kind}"));
+        }
+        for kind in ["created", "restored"] {
+            assert_eq!(event(anonymous.next().await), format!("event: {kind}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn soft_delete_records_when_and_restore_clears_it() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+
+        let deleted = at(&server, NOW + 60)
+            .delete_coffee(coffee.id.clone())
+            .await
+            .unwrap();
+        assert_eq!(deleted.deleted_at, Some(NOW + 60));
+        assert_eq!(deleted.created_at, NOW);
+        let trash = server.list_deleted_coffees().await.unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].deleted_at, Some(NOW + 60));
+        let err = server.grab_coffee(coffee.id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let restored = at(&server, NOW + 120)
+            .restore_coffee(coffee.id.clone())
+            .await
+            .unwrap();
+        assert_eq!(restored.deleted_at, None);
+        assert_eq!(restored.version, deleted.version + 1);
+        let history = server.get_coffee_history(coffee.id).await.unwrap();
+        let stamps = history
+            .iter()
+            .map(|change| change.changed_at)
+            .collect::<Vec<_>>();
+        assert_eq!(stamps, [NOW, NOW + 60, NOW + 120]);
+    }
+
+    #[tokio::test]
+    async fn only_trashed_coffees_are_purged() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let id = coffee.id.clone();
+
+        let err = server.purge_coffee(id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(
+            err.to_string(),
+            format!("coffee {} is not in the trash", &*id)
+        );
+        let err = server.restore_coffee(id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        server.delete_coffee(id.clone()).await.unwrap();
+        let err = server.delete_coffee(id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        let page = server.list_coffees(ListCoffees::default()).await.unwrap();
+        assert!(page.coffees.is_empty());
+
+        server.purge_coffee(id.clone()).await.unwrap();
+        assert!(server.list_deleted_coffees().await.unwrap().is_empty());
+        let err = server.restore_coffee(id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        let err = server.purge_coffee(id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        // The history outlives the coffee.
+        let history = server.get_coffee_history(id).await.unwrap();
+        let actions = history
+            .iter()
+            .map(|change| change.action)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                ChangeAction::Insert,
+                ChangeAction::Update,
+                ChangeAction::Delete
+            ]
+        );
+        assert!(history[2].after.is_none());
+    }
+
+    #[tokio::test]
+    async fn listings_filter_sort_and_page() {
+        let server = server_at(NOW).await;
+        let template = add_coffee(&server).await;
+        let miga = server
+            .add_roastery(params(json!({ "name": "Miga" })))
+            .await
+            .unwrap();
+        // Prices tie in pairs, so paging has to break ties by id.
+        for (i, price) in [1200, 1200, 2400, 2400].into_iter().enumerate() {
+            let coffee = params(json!({
+                "roastery_id": miga.id,
+                "icon": "🫘",
+                "farmer_id": template.farmer_id,
+                "price": { "amount_minor": price, "currency": "EUR" },
+                "origin_id": template.origin_id,
+            }));
+            at(&server, NOW + 1 + i as i64)
+                .add_coffee(coffee)
+                .await
+                .unwrap();
+        }
+
+        let mut query = ListCoffees {
+            sort: CoffeeSort::Price,
+            order: SortOrder::Desc,
+            limit: Some(2),
+            ..ListCoffees::default()
+        };
+        let mut prices = Vec::new();
+        let mut ids = Vec::new();
+        loop {
+            let page = server.list_coffees(query.clone()).await.unwrap();
+            assert!(page.coffees.len() <= 2);
+            prices.extend(page.coffees.iter().map(|coffee| coffee.price.amount_minor));
+            ids.extend(page.coffees.into_iter().map(|coffee| coffee.id.to_string()));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(prices, [2400, 2400, 1850, 1200, 1200]);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+
+        // A cursor only continues the sort it came from.
+        let first = server
+            .list_coffees(ListCoffees {
+                limit: Some(1),
+                ..ListCoffees::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(first.coffees[0].id.to_string(), template.id.to_string());
+        let err = server
+            .list_coffees(ListCoffees {
+                sort: CoffeeSort::Roastery,
+                cursor: first.next_cursor,
+                ..ListCoffees::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "cursor was issued for a different sort");
+
+        let page = server
+            .list_coffees(ListCoffees {
+                roastery_id: Some(miga.id.clone()),
+                min_price: Some(1500),
+                ..ListCoffees::default()
+            })
+            .await
+            .unwrap();
+        let created = page
+            .coffees
+            .iter()
+            .map(|coffee| coffee.created_at)
+            .collect::<Vec<_>>();
+        assert_eq!(created, [NOW + 3, NOW + 4]);
+        let page = server
+            .list_coffees(ListCoffees {
+                sort: CoffeeSort::Roastery,
+                max_price: Some(1850),
+                ..ListCoffees::default()
+            })
+            .await
+            .unwrap();
+        let roasteries = page
+            .coffees
+            .iter()
+            .map(|coffee| coffee.roastery.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(roasteries, ["Miga", "Miga", "Onyx"]);
+        let page = server
+            .list_coffees(ListCoffees {
+                currency: Some(Currency::from("USD".to_string())),
+                ..ListCoffees::default()
+            })
+            .await
+            .unwrap();
+        assert!(page.coffees.is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_ranks_roasteries_first_and_skips_the_trash() {
+        let server = server_at(NOW).await;
+        let template = add_coffee(&server).await;
+        let roastery = server
+            .add_roastery(params(json!({ "name": "Café Imports Kenya" })))
+            .await
+            .unwrap();
+        let origin = server
+            .add_origin(params(json!({ "name": "Kenya Nyeri", "country": "Kenya" })))
+            .await
+            .unwrap();
+        let coffee = |roastery_id: &Nanoid, origin_id: &Nanoid| {
+            params::<AddCoffeeParams>(json!({
+                "roastery_id": roastery_id,
+                "icon": "🫘",
+                "farmer_id": template.farmer_id,
+                "price": { "amount_minor": 1500, "currency": "EUR" },
+                "origin_id": origin_id,
+            }))
+        };
+        let by_origin = server
+            .add_coffee(coffee(&template.roastery_id, &origin.id))
+            .await
+            .unwrap();
+        let by_roastery = server
+            .add_coffee(coffee(&roastery.id, &template.origin_id))
+            .await
+            .unwrap();
+
+        let search = |query: &str| params::<SearchParams>(json!({ "query": query }));
+        let hits = server.search_coffees(search("kenya")).await.unwrap();
+        let ids = hits
+            .iter()
+            .map(|hit| hit.coffee.id.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [by_roastery.id.to_string(), by_origin.id.to_string()]);
+        assert!(hits[0].score > hits[1].score);
+        assert_eq!(hits[0].snippet, "Café Imports <mark>Kenya</mark>");
+
+        // Prefixes and missing accents match, FTS syntax is taken literally.
+        let hits = server.search_coffees(search("caf imp")).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        let hits = server
+            .search_coffees(search("onyx OR \"kenya"))
+            .await
+            .unwrap();
+        assert!(hits.is_empty());
+        let err = server.search_coffees(search("  ")).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::BadRequest);
+
+        server.delete_coffee(by_roastery.id.clone()).await.unwrap();
+        let hits = server.search_coffees(search("kenya")).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].coffee.id.to_string(), by_origin.id.to_string());
+    }
+
+    #[tokio::test]
+    async fn stale_versions_conflict_with_the_current_coffee() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let edit = |icon: &str, expected_version: Option<i64>| {
+            params::<EditCoffee>(json!({
+                "id": coffee.id,
+                "icon": icon,
+                "expected_version": expected_version,
+            }))
+        };
+        let edited = server.edit_coffee(edit("🫘", Some(1))).await.unwrap();
+        assert_eq!(edited.version, 2);
+
+        let err = server.edit_coffee(edit("🌱", Some(1))).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+        let body = err.body();
+        assert_eq!(
+            body.message,
+            format!("coffee {} is at version 2, not 1", &*coffee.id)
+        );
+        let current: Coffee = serde_json::from_value(body.details.unwrap()).unwrap();
+        assert_eq!(current.version, 2);
+        assert_eq!(current.icon, "🫘");
+
+        // Without an expected version the last write wins.
+        let edited = server.edit_coffee(edit("🌱", None)).await.unwrap();
+        assert_eq!(edited.version, 3);
+    }
+
+    #[tokio::test]
+    async fn rest_edits_check_if_match() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let editor = issue_key(&server, "editor").await;
+        let path = format!("PATCH /coffees/{}", &*coffee.id);
+        let key = Some(editor.key.as_str());
+
+        let res = send(
+            &server,
+            key,
+            &format!("{path}\nIf-Match: \"1\""),
+            r#"{"icon":"🫘"}"#,
+        )
+        .await;
+        assert_eq!(res.status, "200 OK");
+        assert_eq!(header(&res, "ETag"), Some("\"2\""));
+
+        let res = send(
+            &server,
+            key,
+            &format!("{path}\nIf-Match: \"1\""),
+            r#"{"icon":"🌱"}"#,
+        )
+        .await;
+        assert_eq!(res.status, "412 Precondition Failed");
+        assert_eq!(json_body(&res)["error"]["details"]["version"], 2);
+        let res = send(&server, key, &format!("{path}\nIf-Match: W/\"2\""), "{}").await;
+        assert_eq!(res.status, "400 Bad Request");
+        // A stale version in the body is a plain conflict.
+        let res = send(&server, key, &path, r#"{"icon":"🌱","expected_version":1}"#).await;
+        assert_eq!(res.status, "409 Conflict");
+
+        let res = send(
+            &server,
+            key,
+            &format!("{path}\nIf-Match: *"),
+            r#"{"icon":"🌱"}"#,
+        )
+        .await;
+        assert_eq!(res.status, "200 OK");
+        assert_eq!(header(&res, "ETag"), Some("\"3\""));
+    }
+
+    #[tokio::test]
+    async fn history_records_each_change_and_reverts_add_one() {
+        let server = server_at(NOW).await;
+        let coffee = add_coffee(&server).await;
+        let editor = issue_key(&server, "editor").await;
+        let edit: EditCoffee = params(json!({
+            "id": coffee.id,
+            "icon": "🫘",
+            "price": { "amount_minor": 2100, "currency": "EUR" },
+        }));
+        request("127.0.0.1:4000", Some(&editor.key))
+            .scope(async {
+                server
+                    .authorize(&Methods::EditCoffee(edit.clone()))
+                    .await
+                    .unwrap();
+                at(&server, NOW + 60).edit_coffee(edit).await.unwrap();
+            })
+            .await;
+
+        let history = server.get_coffee_history(coffee.id.clone()).await.unwrap();
+        assert_eq!(history.len(), 2);
+        let update = &history[1];
+        assert_eq!(update.action, ChangeAction::Update);
+        assert_eq!(update.version, 2);
+        assert_eq!(update.actor, "editor key");
+        assert_eq!(update.changed_at, NOW + 60);
+        assert_eq!(update.before.as_ref().unwrap().icon, "☕");
+        let after = update.after.as_ref().unwrap();
+        assert_eq!(after.icon, "🫘");
+        assert_eq!(after.price.amount_minor, 2100);
+
+        let revert = RevertCoffee {
+            id: coffee.id.clone(),
+            to_version: 1,
+        };
+        let reverted = server.revert_coffee(revert).await.unwrap();
+        assert_eq!(reverted.version, 3);
+        assert_eq!(reverted.icon, "☕");
+        assert_eq!(reverted.price, coffee.price);
+        let history = server.get_coffee_history(coffee.id.clone()).await.unwrap();
+        let versions = history
+            .iter()
+            .map(|change| change.version)
+            .collect::<Vec<_>>();
+        assert_eq!(versions, [1, 2, 3]);
+
+        let revert = RevertCoffee {
+            id: coffee.id.clone(),
+            to_version: 7,
+        };
+        let err = server.revert_coffee(revert).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(
+            err.to_string(),
+            format!("coffee {} has no version 7", &*coffee.id)
+        );
+        let err = server
+            .get_coffee_history("missing".into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "coffee missing has no history");
+    }
 }
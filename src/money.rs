@@ -0,0 +1,276 @@
+//! Amounts of money in [ISO 4217](https://www.iso.org/iso-4217-currency-codes.html)
+//! currencies, and converting them with the rates of the `exchange_rates` table.
+
+use std::{collections::HashMap, fmt, ops::Deref};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppError, AppResult};
+
+/// Accepted currencies and the number of digits of their minor unit.
+pub const CURRENCIES: &[(&str, u32)] = &[
+    ("AUD", 2),
+    ("BHD", 3),
+    ("BRL", 2),
+    ("CAD", 2),
+    ("CHF", 2),
+    ("CLP", 0),
+    ("CNY", 2),
+    ("COP", 2),
+    ("CZK", 2),
+    ("DKK", 2),
+    ("ETB", 2),
+    ("EUR", 2),
+    ("GBP", 2),
+    ("HKD", 2),
+    ("HUF", 2),
+    ("IDR", 2),
+    ("INR", 2),
+    ("ISK", 0),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KES", 2),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("MXN", 2),
+    ("NOK", 2),
+    ("NZD", 2),
+    ("OMR", 3),
+    ("PEN", 2),
+    ("PLN", 2),
+    ("RWF", 0),
+    ("SEK", 2),
+    ("SGD", 2),
+    ("TND", 3),
+    ("TRY", 2),
+    ("TZS", 2),
+    ("UGX", 0),
+    ("USD", 2),
+    ("VND", 0),
+    ("ZAR", 2),
+];
+
+/// An ISO 4217 currency code such as `EUR`, upper cased.
+///
+/// Any code deserializes, so an unknown one is reported along with every other
+/// invalid field by `validation::currency` rather than failing the whole request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct Currency(String);
+
+impl Currency {
+    /// Whether the code is one of [`CURRENCIES`].
+    pub fn is_known(&self) -> bool {
+        CURRENCIES.iter().any(|(code, _)| *code == self.0)
+    }
+
+    /// Digits of the minor unit, 2 for cents.
+    pub fn minor_unit(&self) -> u32 {
+        CURRENCIES
+            .iter()
+            .find(|(code, _)| *code == self.0)
+            .map_or(2, |(_, digits)| *digits)
+    }
+
+    /// Wraps a code read back from the database, which only holds accepted ones.
+    pub fn from_db(code: String) -> Self {
+        Self(code)
+    }
+}
+
+impl From<String> for Currency {
+    fn from(code: String) -> Self {
+        Self(code.to_ascii_uppercase())
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.0
+    }
+}
+
+impl AsRef<Currency> for Currency {
+    fn as_ref(&self) -> &Currency {
+        self
+    }
+}
+
+impl Deref for Currency {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An amount in the minor unit of its currency, e.g. `{ "amount_minor": 1250,
+/// "currency": "EUR" }` for €12.50.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Money {
+    pub amount_minor: i64,
+    pub currency: Currency,
+}
+
+impl AsRef<Currency> for Money {
+    fn as_ref(&self) -> &Currency {
+        &self.currency
+    }
+}
+
+impl Money {
+    /// Converts to `to` at `rate` units of `to` per unit of this currency, rounding
+    /// to the nearest minor unit.
+    pub fn convert(&self, to: &Currency, rate: f64) -> Money {
+        let scale = 10f64.powi(to.minor_unit() as i32 - self.currency.minor_unit() as i32);
+        Money {
+            amount_minor: (self.amount_minor as f64 * rate * scale).round() as i64,
+            currency: to.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeRate {
+    pub from: Currency,
+    pub to: Currency,
+    /// Units of `to` one unit of `from` is worth.
+    pub rate: f64,
+    /// When the rate was set, in seconds since the Unix epoch.
+    pub updated_at: i64,
+}
+
+/// The rates into one target currency, see [`ExchangeRates::convert`].
+#[derive(Debug, Clone)]
+pub struct ExchangeRates {
+    target: Currency,
+    rates: HashMap<Currency, f64>,
+}
+
+impl ExchangeRates {
+    /// Collects the rates into `target` out of `rates`, using the inverse of a rate
+    /// out of `target` where there is no direct one.
+    pub fn new(target: Currency, rates: impl IntoIterator<Item = ExchangeRate>) -> Self {
+        let mut direct = HashMap::new();
+        let mut inverse = HashMap::new();
+        for rate in rates {
+            if rate.to == target {
+                direct.insert(rate.from, rate.rate);
+            } else if rate.from == target {
+                inverse.insert(rate.to, 1.0 / rate.rate);
+            }
+        }
+        inverse.extend(direct);
+        Self {
+            target,
+            rates: inverse,
+        }
+    }
+
+    pub fn convert(&self, money: &Money) -> AppResult<Money> {
+        if money.currency == self.target {
+            return Ok(money.clone());
+        }
+        let rate = self.rates.get(&money.currency).ok_or_else(|| {
+            AppError::Unprocessable(format!(
+                "no exchange rate from {} to {}",
+                money.currency, self.target
+            ))
+        })?;
+        Ok(money.convert(&self.target, *rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn currency(code: &str) -> Currency {
+        Currency::from(code.to_string())
+    }
+
+    fn money(amount_minor: i64, code: &str) -> Money {
+        Money {
+            amount_minor,
+            currency: currency(code),
+        }
+    }
+
+    fn rate(from: &str, to: &str, rate: f64) -> ExchangeRate {
+        ExchangeRate {
+            from: currency(from),
+            to: currency(to),
+            rate,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn currencies_are_upper_cased_and_checked() {
+        let eur: Currency = serde_json::from_str("\"eur\"").unwrap();
+        assert_eq!(&*eur, "EUR");
+        assert!(eur.is_known());
+        assert_eq!(serde_json::to_string(&eur).unwrap(), "\"EUR\"");
+        assert!(!currency("XYZ").is_known());
+        assert_eq!(currency("CLP").minor_unit(), 0);
+        assert_eq!(currency("BHD").minor_unit(), 3);
+        assert!(CURRENCIES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn conversions_account_for_minor_units() {
+        // €18.50 at 1.1 USD per EUR is $20.35.
+        assert_eq!(
+            money(1850, "EUR").convert(&currency("USD"), 1.1),
+            money(2035, "USD")
+        );
+        // CLP has no minor unit, BHD three digits of one.
+        assert_eq!(
+            money(1850, "EUR").convert(&currency("CLP"), 1000.0),
+            money(18500, "CLP")
+        );
+        assert_eq!(
+            money(18500, "CLP").convert(&currency("BHD"), 0.0004),
+            money(7400, "BHD")
+        );
+        // Rounded to the nearest minor unit.
+        assert_eq!(
+            money(1, "EUR").convert(&currency("USD"), 1.5),
+            money(2, "USD")
+        );
+    }
+
+    #[test]
+    fn rates_are_used_directly_or_inverted() {
+        let rates = ExchangeRates::new(
+            currency("EUR"),
+            [
+                rate("USD", "EUR", 0.9),
+                rate("EUR", "USD", 2.0),
+                rate("EUR", "CHF", 0.5),
+                rate("USD", "CHF", 0.8),
+            ],
+        );
+        assert_eq!(
+            rates.convert(&money(1000, "EUR")).unwrap(),
+            money(1000, "EUR")
+        );
+        // The direct rate wins over the inverse of the one the other way.
+        assert_eq!(
+            rates.convert(&money(1000, "USD")).unwrap(),
+            money(900, "EUR")
+        );
+        assert_eq!(
+            rates.convert(&money(1000, "CHF")).unwrap(),
+            money(2000, "EUR")
+        );
+        let err = rates.convert(&money(1000, "GBP")).unwrap_err();
+        assert_eq!(err.to_string(), "no exchange rate from GBP to EUR");
+    }
+}
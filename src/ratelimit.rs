@@ -0,0 +1,433 @@
+//! Per client rate limits: a token bucket for each client and method, kept in
+//! memory, and optional daily quotas counted in the database so they survive
+//! restarts.
+
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_scalar, SqlitePool};
+
+use crate::{
+    config::{RateLimit, RateLimits},
+    error::{AppError, AppResult},
+    state::Clock,
+};
+
+/// Buckets kept before full ones, which are no different from new ones, are
+/// dropped. If that isn't enough, the least recently used ones are dropped too.
+const MAX_BUCKETS: usize = 10_000;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Why a call was turned away, sent as the details of a 429 error and as its
+/// `RateLimit-*` and `Retry-After` headers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimited {
+    /// Calls allowed in the limit's window, its burst or daily quota.
+    pub limit: u32,
+    /// Seconds until the limit is back to full.
+    pub reset: u64,
+    /// Seconds until the next call is let through.
+    pub retry_after: u64,
+}
+
+impl RateLimited {
+    pub fn headers(&self) -> [(&'static str, String); 4] {
+        [
+            ("Retry-After", self.retry_after.to_string()),
+            ("RateLimit-Limit", self.limit.to_string()),
+            ("RateLimit-Remaining", "0".to_string()),
+            ("RateLimit-Reset", self.reset.to_string()),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: SystemTime,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: SystemTime, limit: &RateLimit) {
+        // A clock going backwards refills nothing.
+        let elapsed = now
+            .duration_since(self.updated)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.burst as f64
+    }
+}
+
+/// Applies [`RateLimits`] to calls, see [`RateLimiter::check`].
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<(String, &'static str), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Counts a call to `method` by `client` against the method's limit, failing
+    /// with [`AppError::TooManyRequests`] if it's used up.
+    pub async fn check(
+        &self,
+        db: &SqlitePool,
+        clock: &dyn Clock,
+        client: &str,
+        method: &'static str,
+    ) -> AppResult<()> {
+        let Some(limit) = self.limits.for_method(method) else {
+            return Ok(());
+        };
+        self.take_token(clock, client, method, limit)?;
+        if let Some(quota) = limit.daily_quota {
+            take_quota(db, clock, client, method, quota).await?;
+        }
+        Ok(())
+    }
+
+    /// Counts a call against `client`'s bucket for `method` only, leaving the
+    /// daily quota alone. For calls that only count once they turn out to fail.
+    pub fn take(&self, clock: &dyn Clock, client: &str, method: &'static str) -> AppResult<()> {
+        match self.limits.for_method(method) {
+            Some(limit) => self.take_token(clock, client, method, limit),
+            None => Ok(()),
+        }
+    }
+
+    fn take_token(
+        &self,
+        clock: &dyn Clock,
+        client: &str,
+        method: &'static str,
+        limit: &RateLimit,
+    ) -> AppResult<()> {
+        let now = clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // Refills a copy, `updated` is what tells recently used buckets apart.
+            buckets.retain(|(_, method), bucket| match self.limits.for_method(method) {
+                Some(limit) => {
+                    let mut bucket = *bucket;
+                    bucket.refill(now, limit);
+                    !bucket.is_full(limit)
+                }
+                None => false,
+            });
+        }
+        if buckets.len() >= MAX_BUCKETS {
+            // Too many clients are mid-burst, likely as many made up ones. Forget
+            // the least recently used, down to half the cap so this doesn't run on
+            // every call.
+            let mut updated = buckets
+                .values()
+                .map(|bucket| bucket.updated)
+                .collect::<Vec<_>>();
+            let evicted = buckets.len() - MAX_BUCKETS / 2;
+            let (_, &mut cutoff, _) = updated.select_nth_unstable(evicted);
+            buckets.retain(|_, bucket| bucket.updated >= cutoff);
+        }
+        let bucket = buckets
+            .entry((client.to_string(), method))
+            .or_insert(Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            });
+        bucket.refill(now, limit);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let secs_until = |tokens: f64| ((tokens - bucket.tokens) / limit.per_second).ceil() as u64;
+        let limited = RateLimited {
+            limit: limit.burst,
+            reset: secs_until(limit.burst as f64),
+            retry_after: secs_until(1.0),
+        };
+        Err(AppError::TooManyRequests(format!(
+            "too many calls to {method}, retry in {}s",
+            limited.retry_after
+        ))
+        .with_details(limited))
+    }
+}
+
+/// Counts a call against today's `quota`, in UTC days.
+async fn take_quota(
+    db: &SqlitePool,
+    clock: &dyn Clock,
+    client: &str,
+    method: &str,
+    quota: u32,
+) -> AppResult<()> {
+    let now = clock.unix_now();
+    let today = now.div_euclid(SECS_PER_DAY);
+    let calls = query_scalar!(
+        "INSERT INTO rate_limit_usage (day, client, method, calls) VALUES (?1, ?2, ?3, 1)
+        ON CONFLICT (day, client, method) DO UPDATE SET calls = calls + 1 WHERE calls < ?4
+        RETURNING calls",
+        today,
+        client,
+        method,
+        quota
+    )
+    .fetch_optional(db)
+    .await?;
+    match calls {
+        // The first call of a client's day clears out the days before.
+        Some(1) => {
+            query!("DELETE FROM rate_limit_usage WHERE day < ?", today)
+                .execute(db)
+                .await?;
+            Ok(())
+        }
+        Some(_) => Ok(()),
+        None => {
+            let until_tomorrow = (SECS_PER_DAY - now.rem_euclid(SECS_PER_DAY)) as u64;
+            let limited = RateLimited {
+                limit: quota,
+                reset: until_tomorrow,
+                retry_after: until_tomorrow,
+            };
+            Err(AppError::TooManyRequests(format!(
+                "daily quota of {quota} calls to {method} used up"
+            ))
+            .with_details(limited))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{config::ServerConfig, db, service::IntoResponse, state::FixedClock};
+
+    const NOW: i64 = 1_790_000_000;
+    const MIDNIGHT: i64 = NOW - NOW % SECS_PER_DAY + SECS_PER_DAY;
+
+    async fn memory_db() -> SqlitePool {
+        // Every in-memory connection is its own database, so keep exactly one.
+        let config = ServerConfig {
+            database_url: "sqlite::memory:".into(),
+            min_connections: 1,
+            max_connections: 1,
+            ..ServerConfig::default()
+        };
+        db::connect(&config).await.unwrap()
+    }
+
+    fn limiter(method: &str, limit: RateLimit) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            default: None,
+            methods: BTreeMap::from([(method.to_string(), limit)]),
+        })
+    }
+
+    fn bucket(burst: u32, per_second: f64) -> RateLimit {
+        RateLimit {
+            burst,
+            per_second,
+            daily_quota: None,
+        }
+    }
+
+    fn limited(result: AppResult<()>) -> RateLimited {
+        let err = result.unwrap_err();
+        let details = err.body().details.expect("no details");
+        serde_json::from_value(details).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bucket_allows_a_burst_then_refills() {
+        let db = memory_db().await;
+        let limiter = limiter("GetRandomCoffee", bucket(3, 0.5));
+        let clock = FixedClock(NOW);
+        for _ in 0..3 {
+            limiter
+                .check(&db, &clock, "ip:1", "GetRandomCoffee")
+                .await
+                .unwrap();
+        }
+        let over = limited(limiter.check(&db, &clock, "ip:1", "GetRandomCoffee").await);
+        assert_eq!(over.limit, 3);
+        assert_eq!(over.retry_after, 2);
+        assert_eq!(over.reset, 6);
+
+        // One token every two seconds.
+        let later = FixedClock(NOW + 2);
+        limiter
+            .check(&db, &later, "ip:1", "GetRandomCoffee")
+            .await
+            .unwrap();
+        let over = limited(limiter.check(&db, &later, "ip:1", "GetRandomCoffee").await);
+        assert_eq!(over.retry_after, 2);
+        // Never more than the burst, however long the client waited.
+        let much_later = FixedClock(NOW + 3600);
+        for _ in 0..3 {
+            limiter
+                .check(&db, &much_later, "ip:1", "GetRandomCoffee")
+                .await
+                .unwrap();
+        }
+        assert!(limiter
+            .check(&db, &much_later, "ip:1", "GetRandomCoffee")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn buckets_are_per_client_and_method() {
+        let db = memory_db().await;
+        let limiter = RateLimiter::new(RateLimits {
+            default: Some(bucket(1, 1.0)),
+            methods: BTreeMap::new(),
+        });
+        let clock = FixedClock(NOW);
+        for (client, method) in [
+            ("ip:1", "GrabCoffee"),
+            ("ip:2", "GrabCoffee"),
+            ("ip:1", "ListCoffees"),
+            ("key:a", "GrabCoffee"),
+        ] {
+            limiter.check(&db, &clock, client, method).await.unwrap();
+        }
+        assert!(limiter
+            .check(&db, &clock, "ip:1", "GrabCoffee")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn unlisted_methods_without_a_default_are_unlimited() {
+        let db = memory_db().await;
+        let limiter = limiter("GetRandomCoffee", bucket(1, 1.0));
+        let clock = FixedClock(NOW);
+        for _ in 0..10 {
+            limiter
+                .check(&db, &clock, "ip:1", "GrabCoffee")
+                .await
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn buckets_are_capped_even_if_none_are_full() {
+        let limiter = limiter("GrabCoffee", bucket(2, 1e-6));
+        for i in 0..MAX_BUCKETS as i64 {
+            let clock = FixedClock(NOW + i);
+            limiter
+                .take(&clock, &format!("ip:{i}"), "GrabCoffee")
+                .unwrap();
+        }
+        let clock = FixedClock(NOW + MAX_BUCKETS as i64);
+        limiter.take(&clock, "ip:new", "GrabCoffee").unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_BUCKETS / 2 + 1);
+        // The least recently used are the ones forgotten.
+        assert!(!buckets.contains_key(&("ip:0".to_string(), "GrabCoffee")));
+        let last = format!("ip:{}", MAX_BUCKETS - 1);
+        assert!(buckets.contains_key(&(last, "GrabCoffee")));
+        assert!(buckets.contains_key(&("ip:new".to_string(), "GrabCoffee")));
+    }
+
+    #[tokio::test]
+    async fn take_leaves_the_quota_alone() {
+        let db = memory_db().await;
+        let limit = RateLimit {
+            burst: 2,
+            per_second: 1.0,
+            daily_quota: Some(1),
+        };
+        let limiter = limiter("GrabCoffee", limit);
+        let clock = FixedClock(NOW);
+        limiter.take(&clock, "ip:1", "GrabCoffee").unwrap();
+        limiter
+            .check(&db, &clock, "ip:1", "GrabCoffee")
+            .await
+            .unwrap();
+        assert!(limiter.take(&clock, "ip:1", "GrabCoffee").is_err());
+    }
+
+    #[tokio::test]
+    async fn daily_quota_survives_restarts_and_rolls_over_at_midnight() {
+        let db = memory_db().await;
+        let limit = RateLimit {
+            burst: 100,
+            per_second: 100.0,
+            daily_quota: Some(2),
+        };
+        let clock = FixedClock(NOW);
+        limiter("GrabCoffee", limit)
+            .check(&db, &clock, "ip:1", "GrabCoffee")
+            .await
+            .unwrap();
+        // A new limiter, as after a restart, starts with full buckets but the
+        // same count.
+        let restarted = limiter("GrabCoffee", limit);
+        restarted
+            .check(&db, &clock, "ip:1", "GrabCoffee")
+            .await
+            .unwrap();
+        let over = limited(restarted.check(&db, &clock, "ip:1", "GrabCoffee").await);
+        assert_eq!(over.limit, 2);
+        assert_eq!(over.retry_after, (MIDNIGHT - NOW) as u64);
+        assert_eq!(over.reset, over.retry_after);
+        // Other clients have quotas of their own.
+        restarted
+            .check(&db, &clock, "ip:2", "GrabCoffee")
+            .await
+            .unwrap();
+
+        let tomorrow = FixedClock(MIDNIGHT);
+        restarted
+            .check(&db, &tomorrow, "ip:1", "GrabCoffee")
+            .await
+            .unwrap();
+        let days = query_scalar!("SELECT DISTINCT day FROM rate_limit_usage")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(days, [MIDNIGHT / SECS_PER_DAY]);
+    }
+
+    #[tokio::test]
+    async fn limited_responses_carry_rate_limit_headers() {
+        let db = memory_db().await;
+        let limiter = limiter("GrabCoffee", bucket(1, 0.25));
+        let clock = FixedClock(NOW);
+        limiter
+            .check(&db, &clock, "ip:1", "GrabCoffee")
+            .await
+            .unwrap();
+        let err = limiter
+            .check(&db, &clock, "ip:1", "GrabCoffee")
+            .await
+            .unwrap_err();
+        let res = err.into_response();
+        assert_eq!(res.status, "429 Too Many Requests");
+        let header = |name: &str| {
+            res.headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("Retry-After"), Some("4"));
+        assert_eq!(header("RateLimit-Limit"), Some("1"));
+        assert_eq!(header("RateLimit-Remaining"), Some("0"));
+        assert_eq!(header("RateLimit-Reset"), Some("4"));
+    }
+}
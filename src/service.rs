@@ -1,43 +1,75 @@
-use std::{error::Error, future::Future, ops::DerefMut, pin::Pin, process::Output};
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
 
-use anyhow::Context;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use sqlx::{Pool, Sqlite, SqliteConnection, SqlitePool};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
 };
 
-use crate::db;
+use crate::{
+    config::ServerConfig,
+    context::RequestContext,
+    error::{AppError, ErrorBody, ErrorCode},
+    http::{percent_decode, HttpRequest, Method, ParseError, RequestParser},
+    jsonrpc::{self, JsonRpc},
+    websocket::{self, MessageHandler},
+};
 
 pub struct Response {
     pub status: &'static str,
+    pub headers: Vec<(&'static str, String)>,
     pub body: String,
+    /// Chunks written after `body` for as long as the stream runs, e.g. an event
+    /// stream. Streamed responses close the connection when they end.
+    pub stream: Option<BodyStream>,
 }
 
-impl IntoResponse for Response {
-    fn into_response(self) -> Response {
-        self
+pub type BodyStream = Pin<Box<dyn Stream<Item = String> + Send>>;
+
+/// How often an idle streamed response gets a comment line, so dead clients are
+/// noticed and proxies keep the connection open.
+const STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+impl Response {
+    pub fn new(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+            stream: None,
+        }
     }
-}
 
-pub mod status {
-    use super::Response;
+    /// A `text/event-stream` response sending every item of `events` as it comes.
+    pub fn event_stream(events: impl Stream<Item = String> + Send + 'static) -> Self {
+        Self {
+            stream: Some(Box::pin(events)),
+            ..Self::new("200 OK", "")
+        }
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+    }
 
-    pub const OK: Response = Response {
-        status: "200 OK",
-        body: String::new(),
-    };
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
 
-    pub const BAD_REQUEST: Response = Response {
-        status: "400 Bad Request",
-        body: String::new(),
-    };
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
 
-    pub const INTERNAL_SERVER_ERROR: Response = Response {
-        status: "500 Internal Server Error",
-        body: String::new(),
-    };
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
 }
 
 pub trait IntoResponse: Send + 'static {
@@ -46,24 +78,19 @@ pub trait IntoResponse: Send + 'static {
 
 impl IntoResponse for () {
     fn into_response(self) -> Response {
-        Response {
-            status: "200 OK".into(),
-            body: "{}".into(),
-        }
+        Response::new("200 OK", "{}")
     }
 }
 
-impl<T: Serialize + Send + 'static, E: ToString + 'static + Send> IntoResponse for Result<T, E> {
+impl<T, E> IntoResponse for Result<T, E>
+where
+    T: Serialize + Send + 'static,
+    E: Into<AppError> + Send + 'static,
+{
     fn into_response(self) -> Response {
         match self {
-            Ok(v) => Response {
-                status: "200 OK".into(),
-                body: serde_json::to_string(&v).unwrap(),
-            },
-            Err(err) => Response {
-                status: "500 Internal Server Error".into(),
-                body: err.to_string(),
-            },
+            Ok(v) => Response::new("200 OK", serde_json::to_string(&v).unwrap()),
+            Err(err) => err.into().into_response(),
         }
     }
 }
@@ -75,61 +102,368 @@ pub struct Request {
     pub data: serde_json::Value,
 }
 
-fn parse_request(data: &str) -> anyhow::Result<Request> {
-    serde_json::from_str(data).context("Invalid Request")
+fn parse_request(data: &[u8]) -> Result<Request, AppError> {
+    serde_json::from_slice(data)
+        .map_err(|err| AppError::BadRequest(format!("invalid request: {err}")))
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An async function of a request and a borrow of the state `S`, e.g.
+///
+/// ```ignore
+/// async fn health(req: HttpRequest, state: &AppState) -> AppResult<Health>
+/// ```
+///
+/// Blanket implemented for every such function. It only exists because a plain `Fn`
+/// bound can't say that the returned future borrows from the state.
+pub trait Handler<'a, Req, S: 'a>: Fn(Req, &'a S) -> Self::Future {
+    type Future: Future<Output = Self::Response> + Send + 'a;
+    type Response: IntoResponse;
 }
 
-pub async fn run_request<Ft, R>(
-    handler: impl Fn(Request, &'static Pool<Sqlite>) -> Ft,
-    request: Request,
-    db: &'static Pool<Sqlite>,
-) -> R
+impl<'a, Req, S, F, Ft> Handler<'a, Req, S> for F
 where
-    Ft: Future<Output = R> + Send + 'static,
-    R: IntoResponse,
+    S: 'a,
+    F: Fn(Req, &'a S) -> Ft,
+    Ft: Future + Send + 'a,
+    Ft::Output: IntoResponse,
 {
-    handler(request, db).await
+    type Future = Ft;
+    type Response = Ft::Output;
 }
 
-pub async fn server_loop<H, Ft, R>(handler_fn: H) -> anyhow::Result<()>
+/// A type erased route handler.
+pub(crate) trait Endpoint<S>: Send + Sync {
+    fn call<'a>(&'a self, req: HttpRequest, state: &'a S) -> BoxFuture<'a, Response>;
+}
+
+/// Calls `H` with the HTTP request as is.
+struct Plain<H>(H);
+
+impl<S, H> Endpoint<S> for Plain<H>
 where
-    H: Send + Clone + 'static + Fn(Request, &'static Pool<Sqlite>) -> Ft,
-    Ft: Future<Output = R> + Send + 'static,
-    R: IntoResponse,
+    H: for<'a> Handler<'a, HttpRequest, S> + Send + Sync,
 {
-    let pool = SqlitePool::connect("sqlite://./coffee.db").await?;
+    fn call<'a>(&'a self, req: HttpRequest, state: &'a S) -> BoxFuture<'a, Response> {
+        let fut = (self.0)(req, state);
+        Box::pin(async move { fut.await.into_response() })
+    }
+}
+
+/// Calls `H` with the body decoded as a JSON `{ "method", "data" }` request.
+struct Rpc<H>(H);
+
+impl<S, H> Endpoint<S> for Rpc<H>
+where
+    H: for<'a> Handler<'a, Request, S> + Send + Sync,
+{
+    fn call<'a>(&'a self, req: HttpRequest, state: &'a S) -> BoxFuture<'a, Response> {
+        match parse_request(&req.body) {
+            Ok(req) => {
+                let fut = (self.0)(req, state);
+                Box::pin(async move { fut.await.into_response() })
+            }
+            Err(err) => {
+                tracing::error!("{err}");
+                Box::pin(async { err.into_response() })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route<S: 'static> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Box<dyn Endpoint<S>>,
+}
+
+impl<S: 'static> Route<S> {
+    /// Matches the route's path against `path`, returning the captured parameters.
+    fn match_path(&self, path: &[&str]) -> Option<Vec<(String, String)>> {
+        if self.segments.len() != path.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (segment, part) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.push((name.clone(), percent_decode(part)?)),
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Dispatches requests on their verb and path.
+///
+/// Paths are matched segment by segment, `{name}` segments capture into
+/// [`HttpRequest::params`]. Literal segments win over captures when both match, so
+/// `/coffees/random` can sit next to `/coffees/{id}`.
+///
+/// Every handler receives a borrow of the server state `S` alongside the request.
+pub struct Router<S: 'static> {
+    routes: Vec<Route<S>>,
+    websockets: Vec<(Vec<String>, Box<dyn MessageHandler<S>>)>,
+}
+
+impl<S: 'static> Default for Router<S> {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            websockets: Vec::new(),
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<H>(self, method: Method, path: &str, handler: H) -> Self
+    where
+        H: for<'a> Handler<'a, HttpRequest, S> + Send + Sync + 'static,
+    {
+        self.endpoint(method, path, Plain(handler))
+    }
+
+    /// Serves an RPC handler on `POST path`, taking the `{ "method", "data" }`
+    /// request decoded from the body.
+    pub fn rpc<H>(self, path: &str, handler: H) -> Self
+    where
+        H: for<'a> Handler<'a, Request, S> + Send + Sync + 'static,
+    {
+        self.endpoint(Method::Post, path, Rpc(handler))
+    }
+
+    /// Serves a JSON-RPC 2.0 handler on `POST path`, see [`crate::jsonrpc`].
+    pub fn json_rpc<H>(self, path: &str, handler: H) -> Self
+    where
+        H: for<'a> jsonrpc::Handler<'a, S> + Send + Sync + 'static,
+    {
+        self.endpoint(Method::Post, path, JsonRpc(handler))
+    }
+
+    /// Accepts WebSocket upgrades on `GET path` and serves RPC messages over them,
+    /// see [`crate::websocket`].
+    pub fn websocket<H>(mut self, path: &str, handler: H) -> Self
+    where
+        H: for<'a> Handler<'a, Request, S> + Send + Sync + 'static,
     {
-        let mut conn = pool.acquire().await?;
-        db::initialize_db(conn.deref_mut()).await?;
+        let path = split_path(path).into_iter().map(String::from).collect();
+        self.websockets.push((path, Box::new(handler)));
+        self
+    }
+
+    fn websocket_handler(&self, path: &str) -> Option<&dyn MessageHandler<S>> {
+        let path = split_path(path);
+        self.websockets
+            .iter()
+            .find(|(segments, _)| segments.iter().eq(path.iter()))
+            .map(|(_, handler)| &**handler)
+    }
+
+    fn endpoint(mut self, method: Method, path: &str, handler: impl Endpoint<S> + 'static) -> Self {
+        let segments = split_path(path)
+            .into_iter()
+            .map(|segment| match segment.strip_prefix('{') {
+                Some(name) => Segment::Param(name.trim_end_matches('}').to_string()),
+                None => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
     }
-    let db: &Pool<_> = Box::leak(Box::new(pool.clone()));
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
+
+    pub async fn dispatch(&self, mut req: HttpRequest, state: &S) -> Response {
+        let path = split_path(&req.path);
+        let mut matched = self
+            .routes
+            .iter()
+            .filter_map(|route| route.match_path(&path).map(|params| (route, params)))
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return AppError::NotFound(format!("no route for {}", req.path)).into_response();
+        }
+        // Prefer the most specific route, i.e. the one with the fewest captures.
+        matched.sort_by_key(|(_, params)| params.len());
+        let fewest = matched[0].1.len();
+        matched.retain(|(_, params)| params.len() == fewest);
+
+        // HEAD is served by the GET handler, the body is dropped on the way out.
+        let found = matched
+            .iter()
+            .find(|(route, _)| route.method == req.method)
+            .or_else(|| {
+                (req.method == Method::Head)
+                    .then(|| {
+                        matched
+                            .iter()
+                            .find(|(route, _)| route.method == Method::Get)
+                    })
+                    .flatten()
+            });
+        let Some((route, params)) = found else {
+            let mut allow = matched
+                .iter()
+                .map(|(route, _)| route.method.to_string())
+                .collect::<Vec<_>>();
+            if allow.iter().any(|method| method == "GET") {
+                allow.push(Method::Head.to_string());
+            }
+            allow.dedup();
+            let message = format!("{} is not allowed on {}", req.method, req.path);
+            return ErrorBody::new(ErrorCode::MethodNotAllowed, message)
+                .into_response()
+                .with_header("Allow", allow.join(", "));
+        };
+        req.params = params.clone();
+        route.handler.call(req, state).await
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+/// Serves `router` until SIGINT or SIGTERM, see [`server_loop_until`].
+pub async fn server_loop<S>(router: Router<S>, state: S, config: ServerConfig) -> anyhow::Result<()>
+where
+    S: Send + Sync + 'static,
+{
+    server_loop_until(router, state, config, shutdown_signal()).await
+}
+
+/// Serves `router` until `shutdown` resolves.
+///
+/// On shutdown the listener is closed first, then open connections get
+/// [`ServerConfig::shutdown_timeout`] to answer the request they are working on.
+/// Idle keep-alive connections are closed right away. Whatever is still running
+/// after the deadline is aborted.
+pub async fn server_loop_until<S>(
+    router: Router<S>,
+    state: S,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()>
+where
+    S: Send + Sync + 'static,
+{
+    let state = Arc::new(state);
+    let router = Arc::new(router);
+    let config = Arc::new(config);
+    let listener = TcpListener::bind(&config.listen).await?;
     tracing::info!("🔥 Listening on {}", listener.local_addr()?);
+
+    let (stop, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
     loop {
-        let (socket, _) = listener.accept().await?;
-        let handler = handler_fn.clone();
-        tokio::spawn(async move {
-            let result = handle_connection(handler, socket, db).await;
-            if let Err(err) = result {
-                tracing::error!("Error: {err}");
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+                let router = router.clone();
+                let state = state.clone();
+                let config = config.clone();
+                let stopping = stopping.clone();
+                connections.spawn(async move {
+                    let result =
+                        handle_connection(&router, socket, peer, &state, &config, stopping).await;
+                    if let Err(err) = result {
+                        tracing::error!("Error: {err}");
+                    }
+                });
             }
-        });
+            // Reap finished connections so the set doesn't grow with every accept.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            () = &mut shutdown => break,
+        }
     }
+
+    drop(listener);
+    tracing::info!(
+        "🌙 Shutting down, draining {} connections",
+        connections.len()
+    );
+    stop.send_replace(true);
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(config.shutdown_timeout(), drain)
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Shutdown deadline passed, aborting {} connections",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+    tracing::info!("🌙 Server stopped");
+    Ok(())
 }
 
-async fn handle_connection<H, Ft, R>(
-    handler_fn: H,
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM.
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for SIGINT: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = interrupt => tracing::info!("🌙 Received SIGINT"),
+        () = terminate => tracing::info!("🌙 Received SIGTERM"),
+    }
+}
+
+async fn handle_connection<S>(
+    router: &Router<S>,
     mut socket: TcpStream,
-    pool: &'static SqlitePool,
+    peer: SocketAddr,
+    state: &S,
+    config: &ServerConfig,
+    mut stopping: watch::Receiver<bool>,
 ) -> anyhow::Result<()>
 where
-    H: Send + Clone + 'static + Fn(Request, &'static Pool<Sqlite>) -> Ft,
-    Ft: Future<Output = R> + Send + 'static,
-    R: IntoResponse,
+    S: Send + Sync + 'static,
 {
-    let mut data = [0u8; 1024];
-    async fn send_response(socket: &mut TcpStream, res: Response) -> anyhow::Result<()> {
-        let res = response(res)?;
+    let mut data = [0u8; 4096];
+    let mut parser = RequestParser::new(config.max_body_size);
+    async fn send_response(
+        socket: &mut TcpStream,
+        res: Response,
+        keep_alive: bool,
+        head: bool,
+    ) -> anyhow::Result<()> {
+        let res = response(res, keep_alive, head);
         socket.write_all(res.as_bytes()).await?;
         socket.flush().await?;
         tracing::info!("🌸 Sent response");
@@ -137,37 +471,357 @@ where
     }
 
     loop {
-        let len = socket.read(&mut data).await?;
+        // Drain every request already buffered before reading again, so pipelined
+        // requests are answered in order.
+        match parser.next_request() {
+            Ok(Some(req)) => {
+                tracing::info!("🌸 {} {}", req.method, req.target());
+                let keep_alive = req.keep_alive();
+                let context = RequestContext::new(peer, &req);
+                let head = req.method == Method::Head;
+                if let Some(handler) = router.websocket_handler(&req.path) {
+                    match websocket::handshake(&req) {
+                        Ok(res) => {
+                            let mut head = format!("HTTP/1.1 {}\r\n", res.status);
+                            for (name, value) in &res.headers {
+                                head.push_str(&format!("{name}: {value}\r\n"));
+                            }
+                            head.push_str("\r\n");
+                            socket.write_all(head.as_bytes()).await?;
+                            tracing::info!("🌸 Upgraded to WebSocket");
+                            let buf = parser.into_buffered();
+                            let serve =
+                                websocket::serve(socket, buf, handler, state, config, stopping);
+                            return context.scope(serve).await;
+                        }
+                        Err(res) => {
+                            send_response(&mut socket, res, keep_alive, head).await?;
+                            if !keep_alive {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                let dispatch = context.scope(router.dispatch(req, state));
+                let res = match tokio::time::timeout(config.request_timeout(), dispatch).await {
+                    Ok(res) => res,
+                    Err(_) => AppError::Timeout.into_response(),
+                };
+                if res.stream.is_some() {
+                    return stream_response(socket, res, head, stopping).await;
+                }
+                // Once shutdown starts, finish this response and then hang up.
+                let keep_alive = keep_alive && !*stopping.borrow();
+                send_response(&mut socket, res, keep_alive, head).await?;
+                if !keep_alive {
+                    break;
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::error!("Invalid request: {err}");
+                let code = match err {
+                    ParseError::BodyTooLarge => ErrorCode::PayloadTooLarge,
+                    _ => ErrorCode::BadRequest,
+                };
+                let res = ErrorBody::new(code, err.to_string()).with_status(err.status());
+                send_response(&mut socket, res, false, false).await?;
+                break;
+            }
+        }
+        if parser.take_expect_continue() {
+            socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        // An idle connection is closed on shutdown; one halfway through sending a
+        // request keeps reading until the drain deadline.
+        let len = tokio::select! {
+            len = socket.read(&mut data) => len?,
+            _ = stopping.wait_for(|&stop| stop), if !parser.is_mid_request() => break,
+        };
         if len == 0 {
+            if parser.is_mid_request() {
+                tracing::error!("Connection closed mid-request");
+            }
             break;
         }
-        let req = data[..len].to_vec();
-        let Ok(req) = String::from_utf8(req) else {
-            tracing::error!("Invalid UTF-8 request");
-            send_response(&mut socket, status::BAD_REQUEST).await?;
-            continue;
-        };
-        let Some(body) = req.split("\r\n\r\n").nth(1) else {
-            send_response(&mut socket, status::BAD_REQUEST).await?;
-            tracing::error!("Invalid request");
-            continue;
+        parser.feed(&data[..len]);
+    }
+    Ok(())
+}
+
+/// Writes a streamed response, without a length, until the stream ends, the client
+/// goes away or the server shuts down, and closes the connection.
+async fn stream_response(
+    mut socket: TcpStream,
+    mut res: Response,
+    head: bool,
+    mut stopping: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let Some(mut stream) = res.stream.take() else {
+        return Ok(());
+    };
+    let mut http_response = format!("HTTP/1.1 {}\r\n", res.status);
+    for (name, value) in &res.headers {
+        http_response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    http_response.push_str("Connection: close\r\n\r\n");
+    if !head {
+        http_response.push_str(&res.body);
+    }
+    socket.write_all(http_response.as_bytes()).await?;
+    socket.flush().await?;
+    if head {
+        return Ok(());
+    }
+    tracing::info!("🌸 Streaming response");
+
+    let mut keep_alive = tokio::time::interval(STREAM_KEEP_ALIVE);
+    keep_alive.tick().await;
+    let mut data = [0u8; 1024];
+    loop {
+        let chunk = tokio::select! {
+            chunk = stream.next() => match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            },
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+            // Anything the client sends is ignored, we only watch for it hanging up.
+            len = socket.read(&mut data) => match len {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+            () = async { _ = stopping.wait_for(|&stop| stop).await } => break,
         };
-        let res = run_request(handler_fn.clone(), parse_request(body)?, pool).await;
-        send_response(&mut socket, res.into_response()).await?;
+        socket.write_all(chunk.as_bytes()).await?;
+        socket.flush().await?;
     }
+    tracing::info!("🌸 Stream closed");
     Ok(())
 }
 
-fn response(res: Response) -> anyhow::Result<String> {
+fn response(res: Response, keep_alive: bool, head: bool) -> String {
     let content_length = res.body.len();
     let status = res.status;
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let content_type = res.header("content-type").unwrap_or("application/json");
 
-    let http_response = [
-        &format!("HTTP/1.1 {status}\r\n"),
-        "Content-Type: application/json\r\n",
-        &format!("Content-Length: {content_length}\r\n\r\n"),
-        &res.body,
-    ];
-    let http_response = http_response.join("");
-    Ok(http_response)
+    let mut http_response = format!("HTTP/1.1 {status}\r\n");
+    http_response.push_str(&format!("Content-Type: {content_type}\r\n"));
+    for (name, value) in &res.headers {
+        if !name.eq_ignore_ascii_case("content-type") {
+            http_response.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    http_response.push_str(&format!("Connection: {connection}\r\n"));
+    http_response.push_str(&format!("Content-Length: {content_length}\r\n\r\n"));
+    if !head {
+        http_response.push_str(&res.body);
+    }
+    http_response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str, body: &str) -> HttpRequest {
+        let mut parser = RequestParser::default();
+        parser
+            .feed(format!("{head} HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+        parser.feed(body.as_bytes());
+        parser.next_request().unwrap().unwrap()
+    }
+
+    /// Answers with its name and the captured parameters.
+    async fn show(name: &'static str, req: HttpRequest) -> Response {
+        let params = req
+            .params
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+        Response::new("200 OK", format!("{name} {}", params.join(",")))
+    }
+
+    async fn grab(req: HttpRequest, _: &()) -> Response {
+        show("grab", req).await
+    }
+
+    async fn random(req: HttpRequest, _: &()) -> Response {
+        show("random", req).await
+    }
+
+    async fn edit(req: HttpRequest, _: &()) -> Response {
+        show("edit", req).await
+    }
+
+    async fn rpc(req: Request, _: &()) -> Response {
+        Response::new("200 OK", req.method)
+    }
+
+    fn router() -> Router<()> {
+        Router::new()
+            .rpc("/rpc", rpc)
+            .route(Method::Get, "/coffees/{id}", grab)
+            .route(Method::Patch, "/coffees/{id}", edit)
+            .route(Method::Get, "/coffees/random", random)
+            .route(Method::Get, "/coffees/{id}/history/{version}", grab)
+    }
+
+    async fn dispatch(head: &str, body: &str) -> Response {
+        router().dispatch(request(head, body), &()).await
+    }
+
+    #[tokio::test]
+    async fn routes_on_method_and_path() {
+        assert_eq!(dispatch("GET /coffees/abc", "").await.body, "grab id=abc");
+        assert_eq!(dispatch("PATCH /coffees/abc", "").await.body, "edit id=abc");
+        let res = dispatch("GET /coffees/a%20b/history/3", "").await;
+        assert_eq!(res.body, "grab id=a b,version=3");
+        // Trailing and doubled slashes don't matter.
+        assert_eq!(dispatch("GET //coffees/abc/", "").await.body, "grab id=abc");
+    }
+
+    #[tokio::test]
+    async fn literal_segments_win_over_captures() {
+        assert_eq!(dispatch("GET /coffees/random", "").await.body, "random ");
+        // The literal claims the path for every method.
+        let res = dispatch("PATCH /coffees/random", "").await;
+        assert_eq!(res.status, "405 Method Not Allowed");
+        assert_eq!(res.header("allow"), Some("GET, HEAD"));
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_methods() {
+        let res = dispatch("GET /teas/abc", "").await;
+        assert_eq!(res.status, "404 Not Found");
+        let res = dispatch("DELETE /coffees/abc", "").await;
+        assert_eq!(res.status, "405 Method Not Allowed");
+        assert_eq!(res.header("allow"), Some("GET, PATCH, HEAD"));
+        let res = dispatch("GET /rpc", "").await;
+        assert_eq!(res.header("allow"), Some("POST"));
+    }
+
+    #[tokio::test]
+    async fn head_is_served_by_get() {
+        let res = dispatch("HEAD /coffees/abc", "").await;
+        assert_eq!(res.status, "200 OK");
+        let text = response(res, true, true);
+        assert!(text.ends_with("Content-Length: 11\r\n\r\n"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn rpc_routes_decode_the_body() {
+        let res = dispatch("POST /rpc", r#"{"method":"GetRandomCoffee"}"#).await;
+        assert_eq!(res.body, "GetRandomCoffee");
+        let res = dispatch("POST /rpc", "{").await;
+        assert_eq!(res.status, "400 Bad Request");
+    }
+
+    #[test]
+    fn responses_are_framed_with_their_headers() {
+        let res = Response::new("201 Created", "{}").with_header("Location", "/coffees/a");
+        let text = response(res, false, false);
+        assert_eq!(
+            text,
+            "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nLocation: /coffees/a\r\n\
+             Connection: close\r\nContent-Length: 2\r\n\r\n{}"
+        );
+        let res = Response::new("200 OK", "hi").with_header("content-type", "text/plain");
+        let text = response(res, true, false);
+        assert!(text.contains("Content-Type: text/plain\r\n"), "{text}");
+        assert!(!text.contains("content-type"), "{text}");
+        assert!(text.contains("Connection: keep-alive\r\n"), "{text}");
+    }
+
+    async fn slow(req: HttpRequest, _: &()) -> Response {
+        let millis = req.param("millis").unwrap().parse().unwrap();
+        tokio::time::sleep(Duration::from_millis(millis)).await;
+        Response::new("200 OK", "done")
+    }
+
+    /// Serves `router()` plus a slow route on a free port until the sender fires.
+    async fn serve(
+        shutdown_timeout_secs: u64,
+    ) -> (
+        SocketAddr,
+        tokio::sync::oneshot::Sender<()>,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = ServerConfig {
+            listen: addr.to_string(),
+            shutdown_timeout_secs,
+            ..ServerConfig::default()
+        };
+        let router = router().route(Method::Get, "/slow/{millis}", slow);
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        let server = tokio::spawn(server_loop_until(router, (), config, async {
+            _ = stopped.await;
+        }));
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        (addr, stop, server)
+    }
+
+    async fn read_to_string(socket: &mut TcpStream) -> String {
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).await.unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_open_requests() {
+        let (addr, stop, server) = serve(10).await;
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        idle.write_all(b"GET /coffees/a HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut data = [0; 1024];
+        let len = idle.read(&mut data).await.unwrap();
+        assert!(data[..len].ends_with(b"grab id=a"));
+
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        busy.write_all(b"GET /slow/300 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+
+        // The idle keep-alive connection is closed right away, the busy one gets
+        // its answer and is closed after it.
+        assert_eq!(read_to_string(&mut idle).await, "");
+        let text = read_to_string(&mut busy).await;
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"), "{text}");
+        assert!(text.contains("Connection: close\r\n"), "{text}");
+        assert!(text.ends_with("done"), "{text}");
+
+        server.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_requests_past_the_deadline() {
+        let (addr, stop, server) = serve(0).await;
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        busy.write_all(b"GET /slow/10000 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_to_string(&mut busy).await, "");
+    }
 }
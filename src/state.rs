@@ -0,0 +1,71 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sqlx::SqlitePool;
+
+use crate::{config::ServerConfig, events::EventBus, ratelimit::RateLimiter};
+
+/// Shared application state handed to every handler.
+///
+/// Cloning is cheap, everything inside is reference counted, so handlers that need
+/// the state past their own future (background tasks, streams) just clone it.
+#[derive(Clone)]
+pub struct AppState {
+    pub db: SqlitePool,
+    pub config: Arc<ServerConfig>,
+    pub clock: Arc<dyn Clock>,
+    pub events: EventBus,
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
+impl AppState {
+    pub fn new(db: SqlitePool, config: ServerConfig) -> Self {
+        Self {
+            db,
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            config: Arc::new(config),
+            clock: Arc::new(SystemClock),
+            events: EventBus::default(),
+        }
+    }
+
+    /// Replaces the clock, e.g. with a [`FixedClock`] in tests.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Source of the current time, injectable so time dependent behaviour can be tested.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Seconds since the Unix epoch, as stored in the database.
+    fn unix_now(&self) -> i64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64)
+    }
+}
+
+/// The wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock stuck at a given number of seconds since the Unix epoch.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.0.max(0) as u64)
+    }
+}
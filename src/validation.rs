@@ -0,0 +1,356 @@
+//! Input validation, declared on the params types with `#[derive(Validate)]`:
+//!
+//! ```ignore
+//! #[derive(Validate)]
+//! pub struct AddCoffeeParams {
+//!     #[validate(text(100))]
+//!     pub roastery: String,
+//!     #[validate(currency, non_negative)]
+//!     pub price: Money,
+//! }
+//! ```
+//!
+//! Every rule names a function of this module, called with a reference to the
+//! field followed by the rule's arguments. `Option` fields are only checked when
+//! set. Service methods marked `#[validate]` check their arguments before they
+//! run, failing with a `validation_failed` error listing every invalid field.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::Methods,
+    auth::ALL_ROASTERIES,
+    error::AppError,
+    money::{Currency, Money},
+};
+
+pub use rpissc::Validate;
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// Longest accepted icon URL.
+pub const MAX_URL_LENGTH: usize = 2048;
+
+/// Countries coffee is grown in.
+pub const KNOWN_COUNTRIES: &[&str] = &[
+    "Bolivia",
+    "Brazil",
+    "Burundi",
+    "Cameroon",
+    "China",
+    "Colombia",
+    "Costa Rica",
+    "Cuba",
+    "DR Congo",
+    "Dominican Republic",
+    "Ecuador",
+    "El Salvador",
+    "Ethiopia",
+    "Guatemala",
+    "Haiti",
+    "Honduras",
+    "India",
+    "Indonesia",
+    "Jamaica",
+    "Kenya",
+    "Laos",
+    "Malawi",
+    "Mexico",
+    "Myanmar",
+    "Nepal",
+    "Nicaragua",
+    "Panama",
+    "Papua New Guinea",
+    "Peru",
+    "Philippines",
+    "Rwanda",
+    "Tanzania",
+    "Thailand",
+    "Timor-Leste",
+    "Uganda",
+    "United States",
+    "Venezuela",
+    "Vietnam",
+    "Yemen",
+    "Zambia",
+    "Zimbabwe",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Every invalid field of a value, in declaration order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidationErrors {
+    pub fields: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.fields.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.fields.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        let fields = errors
+            .fields
+            .iter()
+            .map(|error| format!("{} {}", error.field, error.message))
+            .collect::<Vec<_>>();
+        AppError::Unprocessable(format!("invalid input: {}", fields.join(", ")))
+            .with_details(errors)
+    }
+}
+
+/// A string that isn't blank, has no surrounding whitespace and is at most `max`
+/// characters long.
+pub fn text(value: &str, max: usize) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("must not be blank".into());
+    }
+    if value.trim() != value {
+        return Err("must not start or end with whitespace".into());
+    }
+    if value.chars().count() > max {
+        return Err(format!("must be at most {max} characters"));
+    }
+    Ok(())
+}
+
+/// A currency, or an amount in one, of [`CURRENCIES`](crate::money::CURRENCIES).
+pub fn currency(value: &impl AsRef<Currency>) -> Result<(), String> {
+    let currency = value.as_ref();
+    if !currency.is_known() {
+        return Err(format!("unknown currency {currency}"));
+    }
+    Ok(())
+}
+
+pub fn non_negative(value: &Money) -> Result<(), String> {
+    if value.amount_minor < 0 {
+        return Err("must not be negative".into());
+    }
+    Ok(())
+}
+
+pub fn positive(value: &f64) -> Result<(), String> {
+    if !value.is_finite() || *value <= 0.0 {
+        return Err("must be a positive number".into());
+    }
+    Ok(())
+}
+
+/// A single emoji or an http(s) URL.
+pub fn icon(value: &str) -> Result<(), String> {
+    if is_emoji(value) || url(value).is_ok() {
+        return Ok(());
+    }
+    Err("must be a single emoji or an http(s) URL".into())
+}
+
+/// An absolute http(s) URL of at most [`MAX_URL_LENGTH`] bytes.
+pub fn url(value: &str) -> Result<(), String> {
+    if value.len() <= MAX_URL_LENGTH {
+        if let Ok(url) = url::Url::parse(value) {
+            if matches!(url.scheme(), "http" | "https") && url.has_host() {
+                return Ok(());
+            }
+        }
+    }
+    Err("must be an http(s) URL".into())
+}
+
+/// One of [`KNOWN_COUNTRIES`].
+pub fn known_country(value: &str) -> Result<(), String> {
+    if KNOWN_COUNTRIES
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(value))
+    {
+        return Ok(());
+    }
+    Err(format!(
+        "must be a coffee growing country, {value:?} isn't one"
+    ))
+}
+
+/// Permissions some method needs, see [`Methods::PERMISSIONS`], or
+/// [`ALL_ROASTERIES`].
+pub fn permissions(value: &[String]) -> Result<(), String> {
+    let known = |permission: &str| {
+        permission == ALL_ROASTERIES || Methods::PERMISSIONS.contains(&permission)
+    };
+    let unknown = value
+        .iter()
+        .map(String::as_str)
+        .filter(|permission| !known(permission))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "unknown permissions {}, expected some of {}, {ALL_ROASTERIES}",
+            unknown.join(", "),
+            Methods::PERMISSIONS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `value` is exactly one emoji, ZWJ sequences, skin tones, flags and
+/// keycaps included.
+fn is_emoji(value: &str) -> bool {
+    const ZWJ: char = '\u{200D}';
+    const VARIATION_SELECTOR: char = '\u{FE0F}';
+    const KEYCAP: char = '\u{20E3}';
+    let is_regional_indicator = |c: char| ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+    let is_skin_tone = |c: char| ('\u{1F3FB}'..='\u{1F3FF}').contains(&c);
+    let is_tag = |c: char| ('\u{E0020}'..='\u{E007F}').contains(&c);
+
+    let chars = value.chars().collect::<Vec<_>>();
+    match chars.as_slice() {
+        [] => return false,
+        [a, b] if is_regional_indicator(*a) && is_regional_indicator(*b) => return true,
+        [c, KEYCAP] | [c, VARIATION_SELECTOR, KEYCAP] if matches!(c, '0'..='9' | '#' | '*') => {
+            return true
+        }
+        _ => {}
+    }
+    // One or more pictographs joined by ZWJ, each optionally followed by a
+    // variation selector, a skin tone or a tag sequence (subdivision flags).
+    let mut chars = chars.into_iter().peekable();
+    loop {
+        match chars.next() {
+            Some(c) if is_pictographic(c) => {}
+            _ => return false,
+        }
+        chars.next_if_eq(&VARIATION_SELECTOR);
+        chars.next_if(|&c| is_skin_tone(c));
+        while chars.next_if(|&c| is_tag(c)).is_some() {}
+        match chars.next() {
+            None => return true,
+            Some(ZWJ) => continue,
+            Some(_) => return false,
+        }
+    }
+}
+
+/// Roughly Unicode's `Extended_Pictographic`.
+fn is_pictographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00A9}'
+            | '\u{00AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{21AA}'
+            | '\u{231A}'..='\u{23FF}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25FE}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2B05}'..='\u{2B55}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1F000}'..='\u{1F1E5}'
+            | '\u{1F200}'..='\u{1F3FA}'
+            | '\u{1F400}'..='\u{1FAFF}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::{AddCoffeeParams, ListCoffees};
+
+    fn invalid_fields(errors: ValidationErrors) -> Vec<(String, String)> {
+        errors
+            .fields
+            .into_iter()
+            .map(|error| (error.field, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn every_invalid_field_is_reported() {
+        let params: AddCoffeeParams = serde_json::from_value(json!({
+            "roastery_id": "r",
+            "icon": "",
+            "farmer_id": "f",
+            "price": { "amount_minor": -5, "currency": "xxx" },
+            "origin_id": "o",
+        }))
+        .unwrap();
+        let errors = params.validate().unwrap_err();
+        assert_eq!(
+            invalid_fields(errors),
+            [
+                (
+                    "icon".to_string(),
+                    "must be a single emoji or an http(s) URL".to_string()
+                ),
+                ("price".to_string(), "unknown currency XXX".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn currencies_are_upper_cased_then_checked() {
+        let query: ListCoffees =
+            serde_json::from_value(json!({ "currency": "eur", "convert_to": "usd" })).unwrap();
+        assert!(query.validate().is_ok());
+        let query: ListCoffees = serde_json::from_value(json!({ "convert_to": "ABC" })).unwrap();
+        let errors = query.validate().unwrap_err();
+        assert_eq!(
+            invalid_fields(errors),
+            [("convert_to".to_string(), "unknown currency ABC".to_string())]
+        );
+    }
+
+    #[test]
+    fn negative_prices_are_rejected() {
+        let money: Money =
+            serde_json::from_value(json!({ "amount_minor": -1, "currency": "EUR" })).unwrap();
+        assert_eq!(currency(&money), Ok(()));
+        assert_eq!(non_negative(&money), Err("must not be negative".into()));
+    }
+
+    #[test]
+    fn icons_are_single_emoji_or_urls() {
+        for valid in ["☕", "🇪🇹", "👩🏽‍🌾", "3️⃣", "https://example.com/bean.png"]
+        {
+            assert_eq!(icon(valid), Ok(()), "{valid}");
+        }
+        for invalid in ["", "☕☕", "bean", "ftp://example.com/bean.png", "https://"] {
+            assert!(icon(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn text_is_trimmed_and_bounded() {
+        assert_eq!(text("Onyx", 10), Ok(()));
+        assert!(text("  ", 10).is_err());
+        assert!(text(" Onyx", 10).is_err());
+        assert!(text("Onyx Coffee Lab", 10).is_err());
+    }
+}
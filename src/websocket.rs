@@ -0,0 +1,568 @@
+//! WebSocket transport for the native RPC protocol
+//! ([RFC 6455](https://www.rfc-editor.org/rfc/rfc6455)).
+//!
+//! Every text or binary message is a request with an id of the client's choosing,
+//!
+//! ```json
+//! { "id": 1, "method": "GrabCoffee", "data": "..." }
+//! ```
+//!
+//! answered with either `{ "id": 1, "result": ... }` or
+//! `{ "id": 1, "error": { "code", "message", "details" } }`. Calls run concurrently,
+//! so responses arrive in completion order and are matched up by id.
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::watch,
+};
+
+use crate::{
+    config::ServerConfig,
+    error::{AppError, ErrorBody, ErrorCode, ErrorEnvelope},
+    http::{HttpRequest, Method},
+    service::{BoxFuture, Handler, IntoResponse, Request, Response},
+};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How often an idle connection is pinged. One that hasn't answered by the next
+/// ping is closed.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// A type erased message handler.
+pub(crate) trait MessageHandler<S>: Send + Sync {
+    fn call<'a>(&'a self, req: Request, state: &'a S) -> BoxFuture<'a, Response>;
+}
+
+impl<S, H> MessageHandler<S> for H
+where
+    H: for<'a> Handler<'a, Request, S> + Send + Sync,
+{
+    fn call<'a>(&'a self, req: Request, state: &'a S) -> BoxFuture<'a, Response> {
+        let fut = self(req, state);
+        Box::pin(async move { fut.await.into_response() })
+    }
+}
+
+#[derive(Deserialize)]
+struct Incoming {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Serialize)]
+struct Outgoing {
+    id: Value,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Result(Value),
+    Error(ErrorBody),
+}
+
+impl Outgoing {
+    fn new(id: Value, res: Response) -> Self {
+        let outcome = if res.status.starts_with('2') {
+            match serde_json::from_str(&res.body) {
+                Ok(result) => Outcome::Result(result),
+                Err(err) => Outcome::Error(AppError::Internal(err.into()).body()),
+            }
+        } else {
+            match serde_json::from_str::<ErrorEnvelope>(&res.body) {
+                Ok(envelope) => Outcome::Error(envelope.error),
+                Err(_) => Outcome::Error(ErrorBody::new(ErrorCode::Internal, res.status)),
+            }
+        };
+        Self { id, outcome }
+    }
+
+    fn error(id: Value, err: AppError) -> Self {
+        Self {
+            id,
+            outcome: Outcome::Error(err.body()),
+        }
+    }
+}
+
+/// Checks that `req` asks for a WebSocket, returning the `101 Switching Protocols`
+/// response that accepts it or the response rejecting it.
+pub(crate) fn handshake(req: &HttpRequest) -> Result<Response, Response> {
+    let key = req.headers.get("sec-websocket-key");
+    let upgrade = req.method == Method::Get
+        && req.headers.has_token("connection", "upgrade")
+        && req.headers.has_token("upgrade", "websocket");
+    let Some(key) = key.filter(|_| upgrade) else {
+        return Err(Response::new(
+            "426 Upgrade Required",
+            serde_json::to_string(&ErrorEnvelope {
+                error: ErrorBody::new(ErrorCode::BadRequest, "expected a WebSocket upgrade"),
+            })
+            .unwrap(),
+        )
+        .with_header("Upgrade", "websocket"));
+    };
+    if req.headers.get("sec-websocket-version") != Some("13") {
+        return Err(AppError::BadRequest("unsupported WebSocket version".into())
+            .into_response()
+            .with_header("Sec-WebSocket-Version", "13"));
+    }
+    let accept = STANDARD.encode(Sha1::digest(format!("{}{ACCEPT_GUID}", key.trim())));
+    Ok(Response::new("101 Switching Protocols", "")
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept))
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Takes one complete frame off the front of `buf`, unmasking its payload.
+fn parse_frame(buf: &mut Vec<u8>, max_size: usize) -> Result<Option<Frame>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    if buf[0] & 0x70 != 0 || buf[1] & 0x80 == 0 {
+        // Reserved bits without an extension, or an unmasked client frame.
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let (len, mut offset) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > max_size as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+    let len = len as usize;
+    if buf.len() < offset + 4 + len {
+        return Ok(None);
+    }
+    let mask: [u8; 4] = buf[offset..offset + 4].try_into().unwrap();
+    offset += 4;
+    let mut payload = buf[offset..offset + len].to_vec();
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    buf.drain(..offset + len);
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+async fn write_frame(socket: &mut TcpStream, opcode: u8, payload: &[u8]) -> anyhow::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    socket.write_all(&frame).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+async fn close(socket: &mut TcpStream, code: u16) -> anyhow::Result<()> {
+    write_frame(socket, OP_CLOSE, &code.to_be_bytes()).await
+}
+
+/// Serves RPC messages over an upgraded connection until either side closes it or
+/// the server shuts down. `buf` holds any bytes that arrived with the handshake.
+pub(crate) async fn serve<S>(
+    mut socket: TcpStream,
+    mut buf: Vec<u8>,
+    handler: &dyn MessageHandler<S>,
+    state: &S,
+    config: &ServerConfig,
+    mut stopping: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut data = [0u8; 4096];
+    let mut message: Option<Vec<u8>> = None;
+    let mut in_flight = FuturesUnordered::new();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+    let mut awaiting_pong = false;
+
+    let call = |id: Value, req: Request| async move {
+        let res =
+            match tokio::time::timeout(config.request_timeout(), handler.call(req, state)).await {
+                Ok(res) => res,
+                Err(_) => AppError::Timeout.into_response(),
+            };
+        Outgoing::new(id, res)
+    };
+
+    loop {
+        // Handle every frame already buffered before reading again.
+        loop {
+            let frame = match parse_frame(&mut buf, config.max_body_size) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(code) => {
+                    tracing::error!("Invalid WebSocket frame, closing with {code}");
+                    return close(&mut socket, code).await;
+                }
+            };
+            awaiting_pong = false;
+            if frame.opcode >= OP_CLOSE && (!frame.fin || frame.payload.len() > 125) {
+                return close(&mut socket, CLOSE_PROTOCOL_ERROR).await;
+            }
+            match frame.opcode {
+                OP_PING => write_frame(&mut socket, OP_PONG, &frame.payload).await?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    while let Some(out) = in_flight.next().await {
+                        send(&mut socket, &out).await?;
+                    }
+                    return close(&mut socket, CLOSE_NORMAL).await;
+                }
+                OP_TEXT | OP_BINARY if message.is_none() => {
+                    message = Some(frame.payload);
+                }
+                OP_CONTINUATION if message.is_some() => {
+                    let payload = message.as_mut().unwrap();
+                    payload.extend_from_slice(&frame.payload);
+                    if payload.len() > config.max_body_size {
+                        return close(&mut socket, CLOSE_TOO_BIG).await;
+                    }
+                }
+                _ => return close(&mut socket, CLOSE_PROTOCOL_ERROR).await,
+            }
+            // Control frames may arrive between the fragments of a message, only
+            // the final data frame completes it.
+            if frame.opcode < OP_CLOSE && frame.fin {
+                let payload = message.take().unwrap();
+                match serde_json::from_slice::<Incoming>(&payload) {
+                    Ok(Incoming { id, request }) => in_flight.push(call(id, request)),
+                    Err(err) => {
+                        let err = AppError::BadRequest(format!("invalid request: {err}"));
+                        send(&mut socket, &Outgoing::error(Value::Null, err)).await?;
+                    }
+                }
+            }
+        }
+
+        tokio::select! {
+            Some(out) = in_flight.next(), if !in_flight.is_empty() => {
+                send(&mut socket, &out).await?;
+            }
+            len = socket.read(&mut data) => {
+                let len = len?;
+                if len == 0 {
+                    return Ok(());
+                }
+                buf.extend_from_slice(&data[..len]);
+            }
+            _ = ping.tick() => {
+                if awaiting_pong {
+                    tracing::info!("WebSocket peer stopped answering pings");
+                    return close(&mut socket, CLOSE_GOING_AWAY).await;
+                }
+                write_frame(&mut socket, OP_PING, b"").await?;
+                awaiting_pong = true;
+            }
+            // The watch guard isn't Send, so it must not outlive the wait.
+            () = async { _ = stopping.wait_for(|&stop| stop).await } => {
+                while let Some(out) = in_flight.next().await {
+                    send(&mut socket, &out).await?;
+                }
+                return close(&mut socket, CLOSE_GOING_AWAY).await;
+            }
+        }
+    }
+}
+
+async fn send(socket: &mut TcpStream, out: &Outgoing) -> anyhow::Result<()> {
+    write_frame(socket, OP_TEXT, &serde_json::to_vec(out)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::http::RequestParser;
+
+    fn upgrade(headers: &str) -> HttpRequest {
+        let mut parser = RequestParser::default();
+        parser.feed(format!("GET /ws HTTP/1.1\r\n{headers}\r\n").as_bytes());
+        parser.next_request().unwrap().unwrap()
+    }
+
+    /// A masked client frame, the way browsers send them.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Reads one unmasked server frame.
+    async fn read_frame(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        socket.read_exact(&mut head).await.unwrap();
+        let len = match head[1] {
+            126 => socket.read_u16().await.unwrap() as usize,
+            127 => socket.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        socket.read_exact(&mut payload).await.unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    async fn read_message(socket: &mut TcpStream) -> Value {
+        let (opcode, payload) = read_frame(socket).await;
+        assert_eq!(opcode, OP_TEXT);
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    /// Echoes `data` after sleeping for `data.sleep` milliseconds, or fails for
+    /// `Fail`.
+    async fn echo(req: Request, _: &()) -> Response {
+        if req.method == "Fail" {
+            return AppError::NotFound("no such coffee".into()).into_response();
+        }
+        let millis = req.data["sleep"].as_u64().unwrap_or(0);
+        tokio::time::sleep(Duration::from_millis(millis)).await;
+        Response::new("200 OK", req.data.to_string())
+    }
+
+    /// Serves [`echo`] over a fresh connection, returning the client's end.
+    async fn connect() -> (TcpStream, watch::Sender<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (stop, stopping) = watch::channel(false);
+        tokio::spawn(async move {
+            let config = ServerConfig {
+                max_body_size: 1024,
+                ..ServerConfig::default()
+            };
+            serve(socket, Vec::new(), &echo, &(), &config, stopping)
+                .await
+                .unwrap();
+        });
+        (client, stop)
+    }
+
+    #[test]
+    fn handshake_accepts_the_key() {
+        // The example from RFC 6455, section 1.3.
+        let req = upgrade(
+            "Connection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+        );
+        let res = handshake(&req).ok().expect("upgrade accepted");
+        assert_eq!(res.status, "101 Switching Protocols");
+        assert!(res.headers.contains(&(
+            "Sec-WebSocket-Accept",
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()
+        )));
+    }
+
+    #[test]
+    fn handshake_rejects_plain_requests_and_old_versions() {
+        let res = handshake(&upgrade("")).err().expect("upgrade refused");
+        assert_eq!(res.status, "426 Upgrade Required");
+        let req = upgrade(
+            "Connection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n",
+        );
+        let res = handshake(&req).err().expect("upgrade refused");
+        assert_eq!(res.status, "400 Bad Request");
+        assert!(res
+            .headers
+            .contains(&("Sec-WebSocket-Version", "13".to_string())));
+    }
+
+    #[test]
+    fn frames_are_unmasked_once_complete() {
+        // The masked "Hello" from RFC 6455, section 5.7.
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let mut buf = frame[..6].to_vec();
+        assert!(parse_frame(&mut buf, 1024).unwrap().is_none());
+        assert_eq!(buf.len(), 6);
+        buf.extend_from_slice(&frame[6..]);
+        buf.extend_from_slice(&client_frame(true, OP_PING, b""));
+        let hello = parse_frame(&mut buf, 1024).unwrap().unwrap();
+        assert!(hello.fin);
+        assert_eq!(hello.opcode, OP_TEXT);
+        assert_eq!(hello.payload, b"Hello");
+        let ping = parse_frame(&mut buf, 1024).unwrap().unwrap();
+        assert_eq!(ping.opcode, OP_PING);
+        assert!(buf.is_empty());
+
+        let long = vec![b'x'; 300];
+        let mut buf = client_frame(false, OP_BINARY, &long);
+        let frame = parse_frame(&mut buf, 1024).unwrap().unwrap();
+        assert!(!frame.fin);
+        assert_eq!(frame.payload, long);
+    }
+
+    #[test]
+    fn invalid_frames_are_refused() {
+        // Unmasked.
+        let mut buf = vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert_eq!(
+            parse_frame(&mut buf, 1024).err(),
+            Some(CLOSE_PROTOCOL_ERROR)
+        );
+        // Reserved bits set.
+        let mut buf = client_frame(true, OP_TEXT, b"hi");
+        buf[0] |= 0x40;
+        assert_eq!(
+            parse_frame(&mut buf, 1024).err(),
+            Some(CLOSE_PROTOCOL_ERROR)
+        );
+        // Over the limit, refused from the header alone.
+        let mut buf = client_frame(true, OP_TEXT, &[b'x'; 200])[..4].to_vec();
+        assert_eq!(parse_frame(&mut buf, 100).err(), Some(CLOSE_TOO_BIG));
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered_by_id_as_they_complete() {
+        let (mut client, _stop) = connect().await;
+        let slow = br#"{"id":1,"method":"Echo","data":{"sleep":200}}"#;
+        client
+            .write_all(&client_frame(true, OP_TEXT, slow))
+            .await
+            .unwrap();
+        // A message split over two fragments with a ping in between.
+        let fast = br#"{"id":"two","method":"Echo","data":{"n":2}}"#;
+        client
+            .write_all(&client_frame(false, OP_TEXT, &fast[..10]))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_PING, b"hi"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_CONTINUATION, &fast[10..]))
+            .await
+            .unwrap();
+
+        assert_eq!(read_frame(&mut client).await, (OP_PONG, b"hi".to_vec()));
+        assert_eq!(
+            read_message(&mut client).await,
+            serde_json::json!({ "id": "two", "result": { "n": 2 } })
+        );
+        assert_eq!(
+            read_message(&mut client).await,
+            serde_json::json!({ "id": 1, "result": { "sleep": 200 } })
+        );
+
+        let fail = br#"{"id":3,"method":"Fail"}"#;
+        client
+            .write_all(&client_frame(true, OP_TEXT, fail))
+            .await
+            .unwrap();
+        let out = read_message(&mut client).await;
+        assert_eq!(out["id"], 3);
+        assert_eq!(out["error"]["code"], "not_found");
+        client
+            .write_all(&client_frame(true, OP_TEXT, b"{"))
+            .await
+            .unwrap();
+        let out = read_message(&mut client).await;
+        assert_eq!(out["id"], Value::Null);
+        assert_eq!(out["error"]["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_calls_in_flight() {
+        let (mut client, _stop) = connect().await;
+        let slow = br#"{"id":1,"method":"Echo","data":{"sleep":100}}"#;
+        client
+            .write_all(&client_frame(true, OP_TEXT, slow))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(read_message(&mut client).await["id"], 1);
+        assert_eq!(
+            read_frame(&mut client).await,
+            (OP_CLOSE, CLOSE_NORMAL.to_be_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_and_oversized_messages_close_the_connection() {
+        let (mut client, stop) = connect().await;
+        stop.send_replace(true);
+        assert_eq!(
+            read_frame(&mut client).await,
+            (OP_CLOSE, CLOSE_GOING_AWAY.to_be_bytes().to_vec())
+        );
+
+        let (mut client, _stop) = connect().await;
+        let part = vec![b' '; 600];
+        client
+            .write_all(&client_frame(false, OP_TEXT, &part))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_CONTINUATION, &part))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (OP_CLOSE, CLOSE_TOO_BIG.to_be_bytes().to_vec())
+        );
+    }
+}